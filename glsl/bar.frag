@@ -0,0 +1,9 @@
+#version 330
+
+in  vec4 outColor;
+out vec4 fragColor;
+
+void main()
+{
+    fragColor = outColor;
+}
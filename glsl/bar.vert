@@ -0,0 +1,13 @@
+#version 330
+
+in vec2 b_position;
+in vec4 b_color;
+
+out vec4 outColor;
+
+uniform mat4 projection;
+
+void main() {
+    outColor = b_color;
+    gl_Position = projection * vec4(b_position, 0.0, 1.0);
+}
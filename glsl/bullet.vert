@@ -0,0 +1,21 @@
+#version 330
+
+in vec2 corner;
+in vec2 b_position;
+in float b_angle;
+in vec2 b_size;
+in vec4 b_uv;
+
+out vec2 outTexCoord;
+
+uniform mat4 projection;
+
+void main() {
+    vec2 uv = corner + vec2(0.5, 0.5);
+    outTexCoord = mix(b_uv.xy, b_uv.zw, uv);
+    float c = cos(b_angle);
+    float s = sin(b_angle);
+    vec2 p = corner * b_size;
+    p = vec2(c * p.x - s * p.y, s * p.x + c * p.y);
+    gl_Position = projection * vec4(p + b_position, 0.0, 1.0);
+}
@@ -0,0 +1,13 @@
+#version 330
+
+in  vec2 outTexCoord;
+in  float outAlpha;
+out vec4 fragColor;
+
+uniform sampler2D texture_sampler;
+
+void main()
+{
+    vec4 color = texture(texture_sampler, outTexCoord);
+    fragColor = vec4(color.rgb, color.a * outAlpha);
+}
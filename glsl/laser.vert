@@ -0,0 +1,16 @@
+#version 330
+
+in vec2 l_position;
+in vec2 l_uv;
+in float l_alpha;
+
+out vec2 outTexCoord;
+out float outAlpha;
+
+uniform mat4 projection;
+
+void main() {
+    outTexCoord = l_uv;
+    outAlpha = l_alpha;
+    gl_Position = projection * vec4(l_position, 0.0, 1.0);
+}
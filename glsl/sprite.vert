@@ -0,0 +1,16 @@
+#version 330
+
+in vec2 s_position;
+in vec2 s_uv;
+in vec4 s_color;
+
+out vec2 outTexCoord;
+out vec4 outColor;
+
+uniform mat4 projection;
+
+void main() {
+    outTexCoord = s_uv;
+    outColor = s_color;
+    gl_Position = projection * vec4(s_position, 0.0, 1.0);
+}
@@ -0,0 +1,546 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::fs::OpenOptions;
+use glium::backend::Facade;
+use glium::texture::{Texture2d, RawImage2d};
+use serde::{Deserialize, Serialize};
+
+use super::util::Resource;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 8 bit rgba pixels, rows from the top
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+
+    pub width: u32,
+
+    pub height: u32,
+
+    pub data: Vec<u8>,
+
+}
+
+impl Image {
+
+    pub fn new(width: u32, height: u32) -> Self {
+        Image { width, height, data: vec![0; (width * height * 4) as usize] }
+    }
+
+    /// 16 bit samples are cut to 8 bit, palettes and low bit depths expanded;
+    /// rgb and grayscale files get an opaque alpha channel
+    pub fn read_png<R: Read>(ifile: R) -> io::Result<Self> {
+        let mut decoder = png::Decoder::new(ifile);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder.read_info().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let (color_type, bit_depth) = reader.output_color_type();
+        if bit_depth != png::BitDepth::Eight {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} bit samples", bit_depth)));
+        }
+        let mut buf = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let data = match color_type {
+            png::ColorType::RGBA => buf,
+            png::ColorType::RGB => buf.chunks(3).flat_map(|p| vec![p[0], p[1], p[2], 255]).collect(),
+            png::ColorType::GrayscaleAlpha => buf.chunks(2).flat_map(|p| vec![p[0], p[0], p[0], p[1]]).collect(),
+            png::ColorType::Grayscale => buf.iter().flat_map(|g| vec![*g, *g, *g, 255]).collect(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", color_type))),
+        };
+        Ok(Image { width: info.width, height: info.height, data })
+    }
+
+    pub fn write_png<W: Write>(&self, ofile: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(ofile, self.width, self.height);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer.write_image_data(&self.data).map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    pub fn load(resource: &Resource, file: &str) -> Result<Self> {
+        let ifile = resource.open_read_only(file).map_err(Box::new)?;
+        let image = Self::read_png(io::BufReader::new(ifile)).map_err(Box::new)?;
+        Ok(image)
+    }
+
+    pub fn save(&self, resource: &Resource, file: &str) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        let ofile = resource.open(file, options).map_err(Box::new)?;
+        self.write_png(io::BufWriter::new(ofile)).map_err(Box::new)?;
+        Ok(())
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = ((y * self.width + x) * 4) as usize;
+        [self.data[i], self.data[i + 1], self.data[i + 2], self.data[i + 3]]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, p: [u8; 4]) {
+        let i = ((y * self.width + x) * 4) as usize;
+        self.data[i..i + 4].copy_from_slice(&p);
+    }
+
+    /// same layout as `load_texture2d`, so v grows down the image
+    pub fn to_raw(&self) -> RawImage2d<'static, u8> {
+        RawImage2d::from_raw_rgba(self.data.clone(), (self.width, self.height))
+    }
+
+    // copy `src` to (x, y) and repeat its border `extrude` pixels outwards
+    fn blit_extruded(&mut self, src: &Image, x: u32, y: u32, extrude: u32) {
+        let w = src.width as i64;
+        let h = src.height as i64;
+        let e = extrude as i64;
+        let mut j = -e;
+        while j < h + e {
+            let mut i = -e;
+            while i < w + e {
+                let p = src.pixel(i.max(0).min(w - 1) as u32, j.max(0).min(h - 1) as u32);
+                self.set_pixel((x as i64 + i) as u32, (y as i64 + j) as u32, p);
+                i += 1;
+            }
+            j += 1;
+        }
+    }
+}
+
+/// skyline bottom-left packer for one page
+#[derive(Debug, Clone)]
+pub struct Skyline {
+
+    width: u32,
+
+    height: u32,
+
+    /// (x, y, width) of each horizontal segment, left to right
+    nodes: Vec<(u32, u32, u32)>,
+
+}
+
+impl Skyline {
+
+    pub fn new(width: u32, height: u32) -> Self {
+        Skyline { width, height, nodes: vec![(0, 0, width)] }
+    }
+
+    /// top left corner of the placed rectangle, `None` when it does not fit
+    pub fn insert(&mut self, w: u32, h: u32) -> Option<(u32, u32)> {
+        let mut best: Option<(usize, u32, u32)> = None;
+        let mut i = 0;
+        while i < self.nodes.len() {
+            if let Some(y) = self.fit(i, w, h) {
+                let better = match best {
+                    Some((b, by, _)) => y < by || (y == by && self.nodes[i].2 < self.nodes[b].2),
+                    None => true,
+                };
+                if better {
+                    best = Some((i, y, self.nodes[i].0));
+                }
+            }
+            i += 1;
+        }
+        let (index, y, x) = best?;
+        self.nodes.insert(index, (x, y + h, w));
+
+        // trim the segments now covered by the new one
+        let i = index + 1;
+        while i < self.nodes.len() {
+            let (px, _, pw) = self.nodes[i - 1];
+            let (nx, ny, nw) = self.nodes[i];
+            if nx >= px + pw {
+                break;
+            }
+            let shrink = px + pw - nx;
+            if nw <= shrink {
+                self.nodes.remove(i);
+            } else {
+                self.nodes[i] = (nx + shrink, ny, nw - shrink);
+                break;
+            }
+        }
+        // merge neighbours of equal height
+        let mut i = 0;
+        while i + 1 < self.nodes.len() {
+            if self.nodes[i].1 == self.nodes[i + 1].1 {
+                self.nodes[i].2 += self.nodes[i + 1].2;
+                self.nodes.remove(i + 1);
+            } else {
+                i += 1;
+            }
+        }
+        Some((x, y))
+    }
+
+    // lowest y at which a `w` x `h` rectangle fits starting at node `index`
+    fn fit(&self, index: usize, w: u32, h: u32) -> Option<u32> {
+        let x = self.nodes[index].0;
+        if x + w > self.width {
+            return None;
+        }
+        let mut left = w as i64;
+        let mut y = 0;
+        let mut i = index;
+        while left > 0 {
+            let (_, ny, nw) = *self.nodes.get(i)?;
+            y = std::cmp::max(y, ny);
+            if y + h > self.height {
+                return None;
+            }
+            left -= nw as i64;
+            i += 1;
+        }
+        Some(y)
+    }
+}
+
+/// named area of an atlas page
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+
+    pub page: usize,
+
+    /// [x, y, width, height] in pixels, from the top left
+    pub rect: [u32; 4],
+
+    /// [u0, v0, u1, v1], v0 at the top edge of the sprite
+    pub uv: [f32; 4],
+
+}
+
+impl Region {
+
+    /// uv of the corners in the order used with `INDICES4_RECT`: bottom left, bottom right, top left, top right
+    pub fn quad_uv(&self) -> [[f32; 2]; 4] {
+        let [u0, v0, u1, v1] = self.uv;
+        [[u0, v1], [u1, v1], [u0, v0], [u1, v0]]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PageDesc {
+
+    pub file: String,
+
+    pub width: u32,
+
+    pub height: u32,
+
+}
+
+///
+/// atlas description, e.g.
+///
+/// { "pages": [{ "file": "rsc/bullets_0.png", "width": 256, "height": 256 }],
+///   "regions": { "rice": { "page": 0, "rect": [2, 2, 16, 8], "uv": [0.0078, 0.0078, 0.0703, 0.0391] } } }
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AtlasDesc {
+
+    pub pages: Vec<PageDesc>,
+
+    pub regions: BTreeMap<String, Region>,
+
+}
+
+impl AtlasDesc {
+
+    pub fn parse(src: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(src)
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn load(resource: &Resource, file: &str) -> Result<Self> {
+        let src = resource.load_as_string(file).map_err(Box::new)?;
+        let desc = Self::parse(&src).map_err(Box::new)?;
+        Ok(desc)
+    }
+
+    pub fn save(&self, resource: &Resource, file: &str) -> Result<()> {
+        let json = self.to_json().map_err(Box::new)?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        let mut ofile = resource.open(file, options).map_err(Box::new)?;
+        ofile.write_all(json.as_bytes()).map_err(Box::new)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum AtlasError {
+    /// the image is larger than a page even on its own
+    TooLarge { name: String, width: u32, height: u32 },
+    DuplicateName(String),
+}
+
+impl fmt::Display for AtlasError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtlasError::TooLarge { name, width, height } => write!(f, "atlas: {} ({}x{}) does not fit on a page", name, width, height),
+            AtlasError::DuplicateName(name) => write!(f, "atlas: {} added twice", name),
+        }
+    }
+}
+
+impl std::error::Error for AtlasError {}
+
+///
+/// packs many small images into as few pages as possible
+///
+/// each image is surrounded by `extrude` copies of its border pixels, so linear filtering
+/// never samples a neighbour, and `padding` transparent pixels
+pub struct AtlasBuilder {
+
+    page_size: [u32; 2],
+
+    padding: u32,
+
+    extrude: u32,
+
+    images: Vec<(String, Image)>,
+
+}
+
+impl Default for AtlasBuilder {
+
+    fn default() -> Self {
+        AtlasBuilder {
+            page_size: [1024, 1024],
+            padding: 1,
+            extrude: 1,
+            images: Vec::new(),
+        }
+    }
+}
+
+impl AtlasBuilder {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_page_size(&mut self, width: u32, height: u32) -> &mut Self {
+        self.page_size = [width, height];
+        self
+    }
+
+    pub fn set_padding(&mut self, padding: u32) -> &mut Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn set_extrude(&mut self, extrude: u32) -> &mut Self {
+        self.extrude = extrude;
+        self
+    }
+
+    pub fn add(&mut self, name: &str, image: Image) -> std::result::Result<&mut Self, AtlasError> {
+        if self.images.iter().any(|(n, _)| n == name) {
+            return Err(AtlasError::DuplicateName(name.to_string()));
+        }
+        self.images.push((name.to_string(), image));
+        Ok(self)
+    }
+
+    /// named after the file without directory and extension
+    pub fn add_file(&mut self, resource: &Resource, file: &str) -> Result<&mut Self> {
+        let image = Image::load(resource, file)?;
+        let name = std::path::Path::new(file).file_stem().and_then(|s| s.to_str()).unwrap_or(file).to_string();
+        self.add(&name, image).map_err(Box::new)?;
+        Ok(self)
+    }
+
+    /// page files are named `<prefix>_<n>.png`
+    pub fn build(&self, prefix: &str) -> std::result::Result<(AtlasDesc, Vec<Image>), AtlasError> {
+        let border = self.extrude + self.padding;
+        // tallest first packs a skyline much tighter
+        let mut order: Vec<usize> = (0..self.images.len()).collect();
+        order.sort_by_key(|&i| (std::cmp::Reverse(self.images[i].1.height), std::cmp::Reverse(self.images[i].1.width)));
+
+        let mut desc = AtlasDesc::default();
+        let mut skylines: Vec<Skyline> = Vec::new();
+        let mut pages: Vec<Image> = Vec::new();
+        for i in order {
+            let (name, image) = &self.images[i];
+            let w = image.width + border * 2;
+            let h = image.height + border * 2;
+            if w > self.page_size[0] || h > self.page_size[1] {
+                return Err(AtlasError::TooLarge { name: name.clone(), width: image.width, height: image.height });
+            }
+            let mut placed = None;
+            for (page, skyline) in skylines.iter_mut().enumerate() {
+                if let Some(p) = skyline.insert(w, h) {
+                    placed = Some((page, p));
+                    break;
+                }
+            }
+            let (page, (x, y)) = match placed {
+                Some(placed) => placed,
+                None => {
+                    let mut skyline = Skyline::new(self.page_size[0], self.page_size[1]);
+                    let p = skyline.insert(w, h).ok_or_else(|| AtlasError::TooLarge { name: name.clone(), width: image.width, height: image.height })?;
+                    skylines.push(skyline);
+                    pages.push(Image::new(self.page_size[0], self.page_size[1]));
+                    desc.pages.push(PageDesc { file: format!("{}_{}.png", prefix, pages.len() - 1), width: self.page_size[0], height: self.page_size[1] });
+                    (pages.len() - 1, p)
+                }
+            };
+            let x = x + border;
+            let y = y + border;
+            pages[page].blit_extruded(image, x, y, self.extrude);
+            let pw = self.page_size[0] as f32;
+            let ph = self.page_size[1] as f32;
+            desc.regions.insert(name.clone(), Region {
+                page,
+                rect: [x, y, image.width, image.height],
+                uv: [x as f32 / pw, y as f32 / ph, (x + image.width) as f32 / pw, (y + image.height) as f32 / ph],
+            });
+        }
+        Ok((desc, pages))
+    }
+
+    /// offline step: write the pages and `<prefix>.json` through `resource`
+    pub fn save(&self, resource: &Resource, prefix: &str) -> Result<AtlasDesc> {
+        let (desc, pages) = self.build(prefix).map_err(Box::new)?;
+        for (page, image) in desc.pages.iter().zip(pages.iter()) {
+            image.save(resource, &page.file)?;
+        }
+        desc.save(resource, &format!("{}.json", prefix))?;
+        Ok(desc)
+    }
+}
+
+/// atlas pages on the gpu with their named regions
+pub struct Atlas {
+
+    desc: AtlasDesc,
+
+    pages: Vec<Texture2d>,
+
+}
+
+impl Atlas {
+
+    /// upload pages packed at runtime
+    pub fn new(facade: &dyn Facade, desc: AtlasDesc, pages: &[Image]) -> Result<Self> {
+        let mut textures = Vec::with_capacity(pages.len());
+        for image in pages.iter() {
+            textures.push(Texture2d::new(facade, image.to_raw()).map_err(Box::new)?);
+        }
+        Ok(Atlas { desc, pages: textures })
+    }
+
+    /// read a description written by `AtlasBuilder::save` and its pages
+    pub fn load(facade: &dyn Facade, resource: &Resource, file: &str) -> Result<Self> {
+        let desc = AtlasDesc::load(resource, file)?;
+        let mut pages = Vec::with_capacity(desc.pages.len());
+        for page in desc.pages.iter() {
+            pages.push(Image::load(resource, &page.file)?);
+        }
+        Self::new(facade, desc, &pages)
+    }
+
+    pub fn desc(&self) -> &AtlasDesc {
+        &self.desc
+    }
+
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.desc.regions.get(name)
+    }
+
+    pub fn uv(&self, name: &str) -> Option<[f32; 4]> {
+        self.region(name).map(|r| r.uv)
+    }
+
+    pub fn page(&self, page: usize) -> Option<&Texture2d> {
+        self.pages.get(page)
+    }
+
+    pub fn pages(&self) -> &[Texture2d] {
+        &self.pages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn solid(w: u32, h: u32, c: u8) -> Image {
+        let mut image = Image::new(w, h);
+        for p in image.data.chunks_mut(4) {
+            p.copy_from_slice(&[c, c, c, 255]);
+        }
+        image
+    }
+
+    #[test]
+    fn skyline_never_overlaps() {
+        let mut sky = Skyline::new(64, 64);
+        let mut rects = Vec::new();
+        let sizes = [(20, 10), (10, 30), (30, 5), (16, 16), (40, 8), (8, 8), (8, 8)];
+        for (w, h) in sizes.iter() {
+            let (x, y) = sky.insert(*w, *h).unwrap();
+            assert!(x + w <= 64 && y + h <= 64);
+            for &(rx, ry, rw, rh) in rects.iter() {
+                assert!(x >= rx + rw || rx >= x + w || y >= ry + rh || ry >= y + h);
+            }
+            rects.push((x, y, *w, *h));
+        }
+        assert!(sky.insert(65, 1).is_none());
+    }
+
+    #[test]
+    fn build_extrudes_and_spills_to_new_pages() {
+        let mut builder = AtlasBuilder::new();
+        builder.set_page_size(32, 32).set_padding(1).set_extrude(1);
+        builder.add("a", solid(20, 20, 10)).unwrap();
+        builder.add("b", solid(20, 20, 20)).unwrap();
+        assert!(builder.add("a", solid(1, 1, 0)).is_err());
+        let (desc, pages) = builder.build("test").unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(desc.pages[1].file, "test_1.png");
+
+        let a = desc.regions["a"];
+        assert_eq!(a.rect, [2, 2, 20, 20]);
+        let page = &pages[a.page];
+        // extruded border, then transparent padding
+        assert_eq!(page.pixel(1, 1), [10, 10, 10, 255]);
+        assert_eq!(page.pixel(0, 0), [0, 0, 0, 0]);
+        assert_eq!(a.uv, [2.0 / 32.0, 2.0 / 32.0, 22.0 / 32.0, 22.0 / 32.0]);
+
+        let json = desc.to_json().unwrap();
+        assert_eq!(AtlasDesc::parse(&json).unwrap(), desc);
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let image = solid(3, 2, 77);
+        let mut buf = Vec::new();
+        image.write_png(&mut buf).unwrap();
+        assert_eq!(Image::read_png(buf.as_slice()).unwrap(), image);
+    }
+
+    fn encode(color: png::ColorType, depth: png::BitDepth, width: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut encoder = png::Encoder::new(&mut buf, width, 1);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        encoder.write_header().unwrap().write_image_data(data).unwrap();
+        buf
+    }
+
+    #[test]
+    fn png_16_bit_and_grayscale_become_rgba8() {
+        let rgb16 = encode(png::ColorType::RGB, png::BitDepth::Sixteen, 1, &[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        let image = Image::read_png(rgb16.as_slice()).unwrap();
+        assert_eq!(image.data, vec![0x12, 0x56, 0x9a, 255]);
+        let gray = encode(png::ColorType::Grayscale, png::BitDepth::Eight, 2, &[10, 200]);
+        assert_eq!(Image::read_png(gray.as_slice()).unwrap().data, vec![10, 10, 10, 255, 200, 200, 200, 255]);
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::fmt;
+use serde::Deserialize;
+
+use super::atlas::{AtlasBuilder, AtlasDesc, Image};
+use super::mesh::{Mesh, MeshIndex, INDICES4_RECT};
+use super::primitive::{BuildVertex, Face, WHITE};
+use super::util::Resource;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// parents deeper than this are taken for a loop
+const MAX_PARENTS: usize = 32;
+
+/// largest stitched texture tried before giving up
+const MAX_PAGE: u32 = 4096;
+
+#[derive(Deserialize)]
+struct RawModel {
+
+    parent: Option<String>,
+
+    #[serde(default)]
+    textures: HashMap<String, String>,
+
+    elements: Option<Vec<Element>>,
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ElementRotation {
+
+    /// pivot, in model units (0 to 16)
+    pub origin: [f32; 3],
+
+    pub axis: Axis,
+
+    /// degrees, -45 to 45 in steps of 22.5
+    pub angle: f32,
+
+    /// stretch the rotated faces back to the full block width
+    #[serde(default)]
+    pub rescale: bool,
+
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ElementFace {
+
+    /// [u0, v0, u1, v1] in texture pixels of a 16x16 texture; derived from the element when missing
+    pub uv: Option<[f32; 4]>,
+
+    /// usually a `#variable`
+    pub texture: String,
+
+    /// clockwise, 0, 90, 180 or 270
+    #[serde(default)]
+    pub rotation: u32,
+
+    pub cullface: Option<String>,
+
+}
+
+/// box of a block model, in model units (0 to 16)
+#[derive(Debug, Clone, Deserialize)]
+pub struct Element {
+
+    pub from: [f32; 3],
+
+    pub to: [f32; 3],
+
+    pub rotation: Option<ElementRotation>,
+
+    pub faces: HashMap<String, ElementFace>,
+
+}
+
+#[derive(Debug)]
+pub enum BlockError {
+    Json { file: String, error: serde_json::Error },
+    /// the parent chain of the model comes back to itself
+    ParentLoop(String),
+    /// no element in the model or any of its parents
+    NoElements(String),
+    /// a face names a texture variable nothing defines
+    MissingTexture(String),
+    UnknownFace(String),
+    /// the textures do not fit in one `MAX_PAGE` square
+    TooManyTextures,
+}
+
+impl fmt::Display for BlockError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::Json { file, error } => write!(f, "block model {}: {}", file, error),
+            BlockError::ParentLoop(name) => write!(f, "block model {}: parent loop", name),
+            BlockError::NoElements(name) => write!(f, "block model {} has no elements", name),
+            BlockError::MissingTexture(var) => write!(f, "block texture {} is not defined", var),
+            BlockError::UnknownFace(face) => write!(f, "unknown block face {}", face),
+            BlockError::TooManyTextures => write!(f, "block textures do not fit in {}x{}", MAX_PAGE, MAX_PAGE),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// mesh of a block model with the texture its uvs refer to
+pub struct BlockMesh<V: BuildVertex, I: MeshIndex> {
+
+    pub mesh: Mesh<V, I>,
+
+    /// every texture of the model stitched together
+    pub texture: Image,
+
+    /// where each texture ended up in `texture`, keyed by texture name
+    pub atlas: AtlasDesc,
+
+}
+
+///
+/// block model in the json format used by minecraft and blockbench, with `parent` resolved
+///
+/// model `block/cube` is read from `<root>/block/cube.json`; texture `block/oak_planks` from
+/// `<root>/block/oak_planks.png`, or `<root>/oak_planks.png` when the textures sit flat in `root`
+pub struct BlockModel {
+
+    name: String,
+
+    textures: HashMap<String, String>,
+
+    elements: Vec<Element>,
+
+}
+
+impl BlockModel {
+
+    pub fn load(resource: &Resource, root: &str, name: &str) -> Result<Self> {
+        let mut textures = HashMap::new();
+        let mut elements = None;
+        let mut current = Some(strip_namespace(name).to_string());
+        let mut depth = 0;
+        // walk from the model up to its root parent; values of the child win
+        while let Some(model) = current {
+            if depth > MAX_PARENTS {
+                return Err(Box::new(BlockError::ParentLoop(name.to_string())));
+            }
+            let file = format!("{}/{}.json", root, model);
+            let src = resource.load_as_string(&file).map_err(Box::new)?;
+            let raw: RawModel = serde_json::from_str(&src).map_err(|error| Box::new(BlockError::Json { file, error }))?;
+            for (k, v) in raw.textures {
+                textures.entry(k).or_insert(v);
+            }
+            if elements.is_none() {
+                elements = raw.elements;
+            }
+            current = raw.parent.map(|p| strip_namespace(&p).to_string());
+            depth += 1;
+        }
+        let elements = elements.ok_or_else(|| Box::new(BlockError::NoElements(name.to_string())))?;
+        Ok(BlockModel { name: name.to_string(), textures, elements })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn elements(&self) -> &[Element] {
+        &self.elements
+    }
+
+    /// follow `#variable` references down to a texture name
+    pub fn texture<'a>(&'a self, reference: &'a str) -> Option<&'a str> {
+        let mut r = reference;
+        let mut depth = 0;
+        while let Some(var) = r.strip_prefix('#') {
+            if depth > MAX_PARENTS {
+                return None;
+            }
+            r = self.textures.get(var)?;
+            depth += 1;
+        }
+        Some(strip_namespace(r))
+    }
+
+    /// texture shown by break particles
+    pub fn particle(&self) -> Option<&str> {
+        self.texture("#particle")
+    }
+
+    /// one quad per face, stitching the textures through `root` as in `load`;
+    /// positions are centered on the origin with the block spanning -0.5 to 0.5
+    pub fn build<V: BuildVertex, I: MeshIndex>(&self, resource: &Resource, root: &str) -> Result<BlockMesh<V, I>> {
+        let mut names: Vec<&str> = Vec::new();
+        for element in self.elements.iter() {
+            for (side, face) in element.faces.iter() {
+                if Face::from_name(side).is_none() {
+                    return Err(Box::new(BlockError::UnknownFace(side.clone())));
+                }
+                let texture = self.texture(&face.texture).ok_or_else(|| Box::new(BlockError::MissingTexture(face.texture.clone())))?;
+                if !names.contains(&texture) {
+                    names.push(texture);
+                }
+            }
+        }
+        let mut images = Vec::with_capacity(names.len());
+        for texture in names.iter() {
+            images.push(load_texture(resource, root, texture)?);
+        }
+        let (atlas, texture) = stitch(&names, images).map_err(|_| Box::new(BlockError::TooManyTextures))?;
+
+        let mut mesh = Mesh::new();
+        for element in self.elements.iter() {
+            // fixed face order so the mesh does not depend on hash map iteration
+            for face in Face::ALL.iter() {
+                let f = match element.faces.get(face.name()) {
+                    Some(f) => f,
+                    None => continue,
+                };
+                let name = self.texture(&f.texture).ok_or_else(|| Box::new(BlockError::MissingTexture(f.texture.clone())))?;
+                let region = atlas.regions[name].uv;
+                let uv = f.uv.unwrap_or_else(|| default_uv(element, *face));
+                // texture pixels to atlas coordinates
+                let to_atlas = |u: f32, v: f32| [region[0] + (region[2] - region[0]) * u / 16.0, region[1] + (region[3] - region[1]) * v / 16.0];
+                // clockwise from the top left, as the face is seen from outside
+                let mut uvs = [to_atlas(uv[0], uv[1]), to_atlas(uv[2], uv[1]), to_atlas(uv[2], uv[3]), to_atlas(uv[0], uv[3])];
+                uvs.rotate_right(((f.rotation / 90) % 4) as usize);
+
+                let corners = face_quad(element.from, element.to, *face);
+                let normal = rotate(element, face.normal(), false);
+                let mut quad = Vec::with_capacity(4);
+                // bottom left, bottom right, top left, top right for `INDICES4_RECT`
+                for &k in [3, 2, 0, 1].iter() {
+                    let p = rotate(element, corners[k], true);
+                    let mut v = V::default();
+                    v.set_position([p[0] / 16.0 - 0.5, p[1] / 16.0 - 0.5, p[2] / 16.0 - 0.5]);
+                    v.set_uv(uvs[k]);
+                    v.set_normal(normal);
+                    v.set_color(WHITE);
+                    quad.push(v);
+                }
+                mesh.push(&quad, &INDICES4_RECT).map_err(Box::new)?;
+            }
+        }
+        Ok(BlockMesh { mesh, texture, atlas })
+    }
+}
+
+fn strip_namespace(name: &str) -> &str {
+    name.strip_prefix("minecraft:").unwrap_or(name)
+}
+
+fn load_texture(resource: &Resource, root: &str, texture: &str) -> Result<Image> {
+    let nested = format!("{}/{}.png", root, texture);
+    if resource.join(&nested).exists() {
+        return Image::load(resource, &nested);
+    }
+    let flat = texture.rsplit('/').next().unwrap_or(texture);
+    Image::load(resource, &format!("{}/{}.png", root, flat))
+}
+
+// smallest square page holding every texture
+fn stitch(names: &[&str], images: Vec<Image>) -> std::result::Result<(AtlasDesc, Image), ()> {
+    let mut builder = AtlasBuilder::new();
+    for (name, image) in names.iter().zip(images) {
+        builder.add(name, image).map_err(|_| ())?;
+    }
+    let mut size = 16;
+    while size <= MAX_PAGE {
+        builder.set_page_size(size, size);
+        if let Ok((desc, mut pages)) = builder.build("block") {
+            if pages.len() == 1 {
+                return Ok((desc, pages.remove(0)));
+            }
+        }
+        size *= 2;
+    }
+    Err(())
+}
+
+// uv a face gets when the model does not give one: the element projected onto that side
+fn default_uv(element: &Element, face: Face) -> [f32; 4] {
+    let [x1, y1, z1] = element.from;
+    let [x2, y2, z2] = element.to;
+    match face {
+        Face::Down => [x1, 16.0 - z2, x2, 16.0 - z1],
+        Face::Up => [x1, z1, x2, z2],
+        Face::North => [16.0 - x2, 16.0 - y2, 16.0 - x1, 16.0 - y1],
+        Face::South => [x1, 16.0 - y2, x2, 16.0 - y1],
+        Face::West => [z1, 16.0 - y2, z2, 16.0 - y1],
+        Face::East => [16.0 - z2, 16.0 - y2, 16.0 - z1, 16.0 - y1],
+    }
+}
+
+// corners clockwise from the top left as seen from outside the block: top left, top right, bottom right, bottom left
+fn face_quad(from: [f32; 3], to: [f32; 3], face: Face) -> [[f32; 3]; 4] {
+    let [x1, y1, z1] = from;
+    let [x2, y2, z2] = to;
+    match face {
+        Face::Down => [[x1, y1, z2], [x2, y1, z2], [x2, y1, z1], [x1, y1, z1]],
+        Face::Up => [[x1, y2, z1], [x2, y2, z1], [x2, y2, z2], [x1, y2, z2]],
+        Face::North => [[x2, y2, z1], [x1, y2, z1], [x1, y1, z1], [x2, y1, z1]],
+        Face::South => [[x1, y2, z2], [x2, y2, z2], [x2, y1, z2], [x1, y1, z2]],
+        Face::West => [[x1, y2, z1], [x1, y2, z2], [x1, y1, z2], [x1, y1, z1]],
+        Face::East => [[x2, y2, z2], [x2, y2, z1], [x2, y1, z1], [x2, y1, z2]],
+    }
+}
+
+// apply the element rotation to a point (around the origin, with rescale) or to a direction
+fn rotate(element: &Element, p: [f32; 3], point: bool) -> [f32; 3] {
+    let r = match &element.rotation {
+        Some(r) if r.angle != 0.0 => r,
+        _ => return p,
+    };
+    let (s, c) = r.angle.to_radians().sin_cos();
+    let o = if point { r.origin } else { [0.0; 3] };
+    let d = [p[0] - o[0], p[1] - o[1], p[2] - o[2]];
+    let mut q = match r.axis {
+        Axis::X => [d[0], d[1] * c - d[2] * s, d[1] * s + d[2] * c],
+        Axis::Y => [d[0] * c + d[2] * s, d[1], -d[0] * s + d[2] * c],
+        Axis::Z => [d[0] * c - d[1] * s, d[0] * s + d[1] * c, d[2]],
+    };
+    if point && r.rescale {
+        let k = 1.0 / c;
+        match r.axis {
+            Axis::X => { q[1] *= k; q[2] *= k; }
+            Axis::Y => { q[0] *= k; q[2] *= k; }
+            Axis::Z => { q[0] *= k; q[1] *= k; }
+        }
+    }
+    [q[0] + o[0], q[1] + o[1], q[2] + o[2]]
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[derive(Copy, Clone, Default)]
+    struct V {
+        position: [f32; 3],
+        uv: [f32; 2],
+    }
+
+    implement_vertex!(V, position, uv);
+
+    impl BuildVertex for V {
+
+        fn set_position(&mut self, position: [f32; 3]) {
+            self.position = position;
+        }
+
+        fn set_uv(&mut self, uv: [f32; 2]) {
+            self.uv = uv;
+        }
+    }
+
+    fn element(rotation: Option<ElementRotation>) -> Element {
+        Element { from: [0.0, 0.0, 0.0], to: [16.0, 16.0, 16.0], rotation, faces: HashMap::new() }
+    }
+
+    #[test]
+    fn crafting_table_resolves_parents_and_textures() {
+        let resource = Resource::default();
+        let model = BlockModel::load(&resource, "rsc", "crafting_table").unwrap();
+        assert_eq!(model.texture("#north"), Some("block/crafting_table_front"));
+        assert_eq!(model.particle(), Some("block/crafting_table_front"));
+        assert_eq!(model.elements().len(), 1);
+
+        let block: BlockMesh<V, u16> = model.build(&resource, "rsc").unwrap();
+        assert_eq!(block.mesh.vertices().len(), 24);
+        assert_eq!(block.atlas.regions.len(), 4);
+        assert_eq!((block.texture.width, block.texture.height), (64, 64));
+        assert!(block.mesh.vertices().iter().all(|v| v.position.iter().all(|p| p.abs() == 0.5)));
+
+        // down face comes first: bottom left, bottom right, top left, top right of its quad
+        let region = block.atlas.regions["block/oak_planks"].uv;
+        let corners = [[-0.5, -0.5, -0.5], [0.5, -0.5, -0.5], [-0.5, -0.5, 0.5], [0.5, -0.5, 0.5]];
+        let uvs = [[region[0], region[3]], [region[2], region[3]], [region[0], region[1]], [region[2], region[1]]];
+        for (i, v) in block.mesh.vertices()[..4].iter().enumerate() {
+            assert_eq!(v.position, corners[i]);
+            assert_eq!(v.uv, uvs[i]);
+        }
+    }
+
+    #[test]
+    fn default_uv_projects_the_element() {
+        let e = Element { from: [2.0, 0.0, 4.0], to: [14.0, 8.0, 12.0], rotation: None, faces: HashMap::new() };
+        assert_eq!(default_uv(&e, Face::Up), [2.0, 4.0, 14.0, 12.0]);
+        assert_eq!(default_uv(&e, Face::North), [2.0, 8.0, 14.0, 16.0]);
+        // top left of the north face is its east edge seen from outside
+        assert_eq!(face_quad(e.from, e.to, Face::North)[0], [14.0, 8.0, 4.0]);
+    }
+
+    #[test]
+    fn rotation_turns_around_the_origin() {
+        let r = ElementRotation { origin: [8.0, 8.0, 8.0], axis: Axis::Y, angle: 45.0, rescale: true };
+        let e = element(Some(r));
+        let p = rotate(&e, [16.0, 8.0, 8.0], true);
+        // rescale keeps the corner at the block edge distance from the pivot on the rotated axis
+        assert!((p[0] - 16.0).abs() < 1e-4 && (p[2] - 0.0).abs() < 1e-4);
+        let n = rotate(&e, [0.0, 1.0, 0.0], false);
+        assert_eq!(n, [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn bad_faces_are_rejected() {
+        let mut model = BlockModel { name: "test".to_string(), textures: HashMap::new(), elements: vec![element(None)] };
+        let face = ElementFace { uv: None, texture: "#all".to_string(), rotation: 0, cullface: None };
+        model.elements[0].faces.insert("sideways".to_string(), face.clone());
+        assert!(model.build::<V, u16>(&Resource::default(), "rsc").is_err());
+        model.elements[0].faces.clear();
+        model.elements[0].faces.insert("up".to_string(), face);
+        let err = model.build::<V, u16>(&Resource::default(), "rsc").err().unwrap();
+        assert_eq!(err.to_string(), "block texture #all is not defined");
+    }
+}
@@ -0,0 +1,621 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use glium::{Surface, Program, DrawParameters};
+use glium::backend::Facade;
+use glium::index::PrimitiveType;
+use serde::Deserialize;
+
+use super::danmaku::{Pattern, Emitter, EmitterEnv};
+use super::path::{Path, PathDesc, PathFollower, FollowSpeed, FollowEnd};
+use super::spline::CubeSplineError;
+use super::mesh::Mesh;
+use super::util::Resource;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const NANOS_PER_SEC: f32 = 1_000_000_000.0;
+
+///
+/// boss definition, e.g.
+///
+/// { "name": "rumia", "phases": [
+///   { "name": "nonspell 1", "hp": 800, "time": 30, "pattern": "rsc/pattern/spiral.json",
+///     "path": { "points": [[0, 180], [60, 160], [0, 140], [-60, 160], [0, 180]] }, "speed": 40, "loop": true },
+///   { "name": "Night Sign \"Night Bird\"", "spell": true, "hp": 1200, "time": 40, "bonus": 3000000,
+///     "position": [0, 160], "transition": { "time": 2, "clear_bullets": true } } ] }
+///
+/// times are in seconds, `transition` describes how the boss enters the phase
+#[derive(Deserialize)]
+struct RawBoss {
+
+    name: String,
+
+    phases: Vec<RawPhase>,
+
+}
+
+#[derive(Deserialize)]
+struct RawPhase {
+
+    name: String,
+
+    #[serde(default)]
+    spell: bool,
+
+    /// invulnerable for the whole phase, surviving the time limit captures the card
+    #[serde(default)]
+    survival: bool,
+
+    #[serde(default)]
+    hp: f32,
+
+    time: f32,
+
+    pattern: Option<String>,
+
+    #[serde(default)]
+    params: Vec<f32>,
+
+    path: Option<PathDesc>,
+
+    #[serde(default)]
+    speed: f32,
+
+    #[serde(default, rename = "loop")]
+    looped: bool,
+
+    /// where to stand when there is no path
+    position: Option<[f32; 2]>,
+
+    #[serde(default)]
+    bonus: u64,
+
+    #[serde(default)]
+    transition: Transition,
+
+}
+
+/// entry into a phase: the boss is invulnerable and glides to the start of the phase
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Transition {
+
+    /// seconds
+    #[serde(default = "default_transition_time")]
+    pub time: f32,
+
+    /// bullets on screen turn into nothing when the previous phase ends
+    #[serde(default = "default_clear_bullets")]
+    pub clear_bullets: bool,
+
+}
+
+fn default_transition_time() -> f32 {
+    1.0
+}
+
+fn default_clear_bullets() -> bool {
+    true
+}
+
+impl Default for Transition {
+
+    fn default() -> Self {
+        Transition {
+            time: default_transition_time(),
+            clear_bullets: default_clear_bullets(),
+        }
+    }
+}
+
+/// one phase, either a nonspell or a spell card
+pub struct PhaseDef {
+
+    pub name: String,
+
+    pub spell: bool,
+
+    pub survival: bool,
+
+    pub hp: f32,
+
+    /// seconds
+    pub time_limit: f32,
+
+    pub pattern: Option<Rc<Pattern>>,
+
+    pub params: Vec<f32>,
+
+    pub path: Option<Arc<Path<f32>>>,
+
+    /// world units per second along `path`
+    pub speed: f32,
+
+    pub looped: bool,
+
+    pub position: Option<[f32; 2]>,
+
+    /// paid only for captured spell cards
+    pub bonus: u64,
+
+    pub transition: Transition,
+
+}
+
+impl PhaseDef {
+
+    // where the boss stands once the transition is over
+    fn home(&self) -> Option<[f32; 2]> {
+        match &self.path {
+            Some(path) => {
+                let mut p = [0.0; 2];
+                let x = path.curve().domain().ok()?.0;
+                path.curve().get(x, &mut p).ok()?;
+                Some(p)
+            }
+            None => self.position,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BossError {
+    Json(serde_json::Error),
+    NoPhases,
+    /// path of phase `phase` does not compile
+    Path { phase: usize, error: CubeSplineError },
+    /// pattern file of phase `phase` failed to load
+    Pattern { phase: usize, file: String, error: String },
+    /// phase `phase` can only end by its time limit or by being shot down, but has no hp
+    NoHp { phase: usize },
+}
+
+impl fmt::Display for BossError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BossError::Json(e) => write!(f, "boss: {}", e),
+            BossError::NoPhases => write!(f, "boss has no phases"),
+            BossError::Path { phase, error } => write!(f, "boss phase {}: bad path: {}", phase, error),
+            BossError::Pattern { phase, file, error } => write!(f, "boss phase {}: pattern {}: {}", phase, file, error),
+            BossError::NoHp { phase } => write!(f, "boss phase {}: needs hp above 0 unless it is a survival phase", phase),
+        }
+    }
+}
+
+impl std::error::Error for BossError {}
+
+/// sequence of phases, loaded from data so a fight can be rebalanced without rebuilding
+pub struct BossDef {
+
+    pub name: String,
+
+    pub phases: Vec<PhaseDef>,
+
+}
+
+impl BossDef {
+
+    /// pattern files are read through `resource`, each one once
+    pub fn parse(src: &str, resource: &Resource) -> std::result::Result<Self, BossError> {
+        let raw: RawBoss = serde_json::from_str(src).map_err(BossError::Json)?;
+        if raw.phases.is_empty() {
+            return Err(BossError::NoPhases);
+        }
+        let mut patterns: HashMap<String, Rc<Pattern>> = HashMap::new();
+        let mut phases = Vec::with_capacity(raw.phases.len());
+        for (phase, p) in raw.phases.into_iter().enumerate() {
+            // `hp` defaults to 0, which would clear the phase on its first tick
+            if !p.survival && p.hp <= 0.0 {
+                return Err(BossError::NoHp { phase });
+            }
+            let pattern = match &p.pattern {
+                Some(file) => {
+                    if !patterns.contains_key(file) {
+                        let pat = Pattern::load(resource, file).map_err(|error| BossError::Pattern { phase, file: file.clone(), error: error.to_string() })?;
+                        patterns.insert(file.clone(), Rc::new(pat));
+                    }
+                    patterns.get(file).cloned()
+                }
+                None => None,
+            };
+            let path = match &p.path {
+                Some(path) => Some(Arc::new(path.compile([0.0, 0.0]).map_err(|error| BossError::Path { phase, error })?)),
+                None => None,
+            };
+            phases.push(PhaseDef {
+                name: p.name,
+                spell: p.spell,
+                survival: p.survival,
+                hp: p.hp,
+                time_limit: p.time,
+                pattern,
+                params: p.params,
+                path,
+                speed: p.speed,
+                looped: p.looped,
+                position: p.position,
+                bonus: p.bonus,
+                transition: p.transition,
+            });
+        }
+        Ok(BossDef { name: raw.name, phases })
+    }
+
+    pub fn load(resource: &Resource, file: &str) -> Result<Self> {
+        let src = resource.load_as_string(file).map_err(Box::new)?;
+        let def = Self::parse(&src, resource).map_err(Box::new)?;
+        Ok(def)
+    }
+}
+
+/// what the ui listens to, drained with `Boss::poll_event`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BossEvent {
+    /// the transition into `phase` begins; show the spell card name here
+    PhaseStart { phase: usize },
+    /// `bonus` is 0 unless a spell card was captured
+    PhaseEnd { phase: usize, captured: bool, bonus: u64 },
+    /// the time limit ran out, followed by the `PhaseEnd`
+    Timeout { phase: usize },
+    Defeated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BossState {
+    /// seconds into the transition
+    Transition(f32),
+    Active,
+    Defeated,
+}
+
+/// runs a `BossDef`: moves the boss, fires its patterns and counts down hp and time of each phase
+pub struct Boss {
+
+    def: Rc<BossDef>,
+
+    phase: usize,
+
+    state: BossState,
+
+    hp: f32,
+
+    time_left: f32,
+
+    /// still eligible for the capture bonus
+    capturing: bool,
+
+    position: [f32; 2],
+
+    from: [f32; 2],
+
+    follower: Option<PathFollower<f32>>,
+
+    emitter: Option<Emitter>,
+
+    events: VecDeque<BossEvent>,
+
+}
+
+impl Boss {
+
+    /// `NoPhases` for a hand built definition without phases
+    pub fn new(def: Rc<BossDef>, position: [f32; 2]) -> std::result::Result<Self, BossError> {
+        if def.phases.is_empty() {
+            return Err(BossError::NoPhases);
+        }
+        let mut boss = Boss {
+            def,
+            phase: 0,
+            state: BossState::Defeated,
+            hp: 0.0,
+            time_left: 0.0,
+            capturing: false,
+            position,
+            from: position,
+            follower: None,
+            emitter: None,
+            events: VecDeque::new(),
+        };
+        boss.begin_phase(0);
+        Ok(boss)
+    }
+
+    pub fn def(&self) -> &Rc<BossDef> {
+        &self.def
+    }
+
+    pub fn phase(&self) -> usize {
+        self.phase
+    }
+
+    /// `None` once defeated
+    pub fn phase_def(&self) -> Option<&PhaseDef> {
+        if self.state == BossState::Defeated {
+            return None;
+        }
+        self.def.phases.get(self.phase)
+    }
+
+    /// phases after the current one, for the star markers next to the bar
+    pub fn remaining_phases(&self) -> usize {
+        self.def.phases.len().saturating_sub(self.phase + 1)
+    }
+
+    pub fn state(&self) -> BossState {
+        self.state
+    }
+
+    pub fn position(&self) -> [f32; 2] {
+        self.position
+    }
+
+    pub fn hp(&self) -> f32 {
+        self.hp
+    }
+
+    /// fill of the health bar; refills during transitions
+    pub fn hp_fraction(&self) -> f32 {
+        let def = match self.phase_def() {
+            Some(def) => def,
+            None => return 0.0,
+        };
+        match self.state {
+            BossState::Transition(t) if def.transition.time > 0.0 => (t / def.transition.time).min(1.0),
+            _ if def.hp > 0.0 => (self.hp / def.hp).max(0.0),
+            _ => 1.0,
+        }
+    }
+
+    /// seconds left on the phase timer
+    pub fn time_left(&self) -> f32 {
+        self.time_left.max(0.0)
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capturing
+    }
+
+    pub fn is_invulnerable(&self) -> bool {
+        match self.state {
+            BossState::Active => !matches!(self.phase_def(), Some(def) if !def.survival),
+            _ => true,
+        }
+    }
+
+    pub fn is_defeated(&self) -> bool {
+        self.state == BossState::Defeated
+    }
+
+    /// returns false when the hit is ignored
+    pub fn damage(&mut self, amount: f32) -> bool {
+        if self.is_invulnerable() {
+            return false;
+        }
+        self.hp -= amount;
+        true
+    }
+
+    /// the player died or bombed, the current card can no longer be captured
+    pub fn fail_capture(&mut self) {
+        self.capturing = false;
+    }
+
+    pub fn poll_event(&mut self) -> Option<BossEvent> {
+        self.events.pop_front()
+    }
+
+    /// advance by one update tick lasting `dt` nanoseconds
+    pub fn update(&mut self, dt: u64, env: &mut EmitterEnv) -> std::result::Result<(), CubeSplineError> {
+        let t = dt as f32 / NANOS_PER_SEC;
+        match self.state {
+            BossState::Defeated => {}
+            BossState::Transition(elapsed) => {
+                let def = &self.def.phases[self.phase];
+                let elapsed = elapsed + t;
+                let to = def.home().unwrap_or(self.from);
+                let s = if def.transition.time > 0.0 { (elapsed / def.transition.time).min(1.0) } else { 1.0 };
+                // smoothstep so the boss eases in and out
+                let s = s * s * (3.0 - 2.0 * s);
+                self.position = [self.from[0] + (to[0] - self.from[0]) * s, self.from[1] + (to[1] - self.from[1]) * s];
+                if elapsed >= def.transition.time {
+                    self.start_active()?;
+                } else {
+                    self.state = BossState::Transition(elapsed);
+                }
+            }
+            BossState::Active => {
+                self.time_left -= t;
+                if let Some(follower) = &mut self.follower {
+                    follower.update(dt)?;
+                    let p = follower.position();
+                    self.position = [p[0], p[1]];
+                }
+                if let Some(emitter) = &mut self.emitter {
+                    emitter.position = self.position;
+                    emitter.step(dt, env);
+                }
+                let def = &self.def.phases[self.phase];
+                if !def.survival && self.hp <= 0.0 {
+                    self.end_phase(true, env);
+                } else if self.time_left <= 0.0 {
+                    self.events.push_back(BossEvent::Timeout { phase: self.phase });
+                    let survived = def.survival;
+                    self.end_phase(survived, env);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn begin_phase(&mut self, phase: usize) {
+        let def = &self.def.phases[phase];
+        self.phase = phase;
+        self.state = BossState::Transition(0.0);
+        self.hp = def.hp;
+        self.time_left = def.time_limit;
+        self.capturing = true;
+        self.from = self.position;
+        self.events.push_back(BossEvent::PhaseStart { phase });
+    }
+
+    fn start_active(&mut self) -> std::result::Result<(), CubeSplineError> {
+        let def = &self.def.phases[self.phase];
+        self.follower = match &def.path {
+            Some(path) => {
+                let mut follower = PathFollower::new(path.clone(), FollowSpeed::Constant(def.speed))?;
+                follower.set_end(if def.looped { FollowEnd::Loop } else { FollowEnd::Stop });
+                let p = follower.position();
+                self.position = [p[0], p[1]];
+                Some(follower)
+            }
+            None => None,
+        };
+        self.emitter = def.pattern.as_ref().map(|pattern| Emitter::new(pattern.clone(), self.position).with_params(def.params.clone()));
+        self.state = BossState::Active;
+        Ok(())
+    }
+
+    fn end_phase(&mut self, cleared: bool, env: &mut EmitterEnv) {
+        let def = &self.def.phases[self.phase];
+        let captured = cleared && def.spell && self.capturing;
+        let bonus = if captured { def.bonus } else { 0 };
+        self.events.push_back(BossEvent::PhaseEnd { phase: self.phase, captured, bonus });
+        self.follower = None;
+        self.emitter = None;
+
+        let next = self.phase + 1;
+        if next >= self.def.phases.len() {
+            env.pool.clear();
+            self.state = BossState::Defeated;
+            self.events.push_back(BossEvent::Defeated);
+            return;
+        }
+        if self.def.phases[next].transition.clear_bullets {
+            env.pool.clear();
+        }
+        self.begin_phase(next);
+    }
+
+    /// bar from `min` to `max` in world units: a dim background with the hp fill on top
+    pub fn health_bar(&self, min: [f32; 2], max: [f32; 2]) -> Mesh<BarVertex> {
+        let spell = self.phase_def().is_some_and(|def| def.spell);
+        let fill = if spell { [1.0, 0.35, 0.35, 1.0] } else { [1.0, 1.0, 1.0, 1.0] };
+        let x = min[0] + (max[0] - min[0]) * self.hp_fraction();
+        let mut vertices = Vec::with_capacity(12);
+        quad(&mut vertices, min, max, [0.0, 0.0, 0.0, 0.5]);
+        quad(&mut vertices, min, [x, max[1]], fill);
+        Mesh::wrap_noind(vertices, PrimitiveType::TrianglesList)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct BarVertex {
+    b_position: [f32; 2],
+    b_color: [f32; 4],
+}
+
+implement_vertex!(BarVertex, b_position, b_color);
+
+fn quad(out: &mut Vec<BarVertex>, min: [f32; 2], max: [f32; 2], color: [f32; 4]) {
+    let corners = [[min[0], min[1]], [max[0], min[1]], [max[0], max[1]], [min[0], min[1]], [max[0], max[1]], [min[0], max[1]]];
+    for c in corners.iter() {
+        out.push(BarVertex { b_position: *c, b_color: color });
+    }
+}
+
+pub fn load_program(facade: &dyn Facade, resource: &Resource) -> Result<Program> {
+    let vert = resource.load_as_string("glsl/bar.vert").map_err(Box::new)?;
+    let frag = resource.load_as_string("glsl/bar.frag").map_err(Box::new)?;
+    let prog = Program::from_source(facade, &vert, &frag, None).map_err(Box::new)?;
+    Ok(prog)
+}
+
+/// draw a health bar; `projection` maps world units to clip space
+pub fn draw<S: Surface>(mesh: &Mesh<BarVertex>, facade: &dyn Facade, target: &mut S, program: &Program, projection: [[f32; 4]; 4], draw_parameters: &DrawParameters) -> Result<()> {
+    let uniforms = uniform!{ projection: projection };
+    mesh.draw(facade, target, program, &uniforms, draw_parameters)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use super::super::bullet::BulletPool;
+    use super::super::rng::Pcg32;
+
+    const TICK: u64 = 1_000_000_000 / 60;
+
+    const SRC: &str = r#"{ "name": "test", "phases": [
+        { "name": "nonspell", "hp": 10, "time": 5, "position": [0, 100] },
+        { "name": "card", "spell": true, "hp": 10, "time": 5, "bonus": 1000,
+          "path": { "points": [[0, 100], [50, 100], [0, 100]] }, "speed": 30, "loop": true,
+          "transition": { "time": 0.5, "clear_bullets": false } },
+        { "name": "survival", "spell": true, "survival": true, "time": 1, "bonus": 500, "transition": { "time": 0 } }
+    ] }"#;
+
+    fn run(boss: &mut Boss, ticks: u32) -> Vec<BossEvent> {
+        let mut pool = BulletPool::with_capacity(16);
+        let mut rng = Pcg32::new(1, 1);
+        let mut env = EmitterEnv { pool: &mut pool, target: [0.0, 0.0], rank: 0.0, rng: &mut rng };
+        let mut i = 0;
+        while i < ticks {
+            boss.update(TICK, &mut env).unwrap();
+            i += 1;
+        }
+        let mut events = Vec::new();
+        while let Some(e) = boss.poll_event() {
+            events.push(e);
+        }
+        events
+    }
+
+    #[test]
+    fn phases_advance_on_damage() {
+        let def = Rc::new(BossDef::parse(SRC, &Resource::default()).unwrap());
+        let mut boss = Boss::new(def, [0.0, 300.0]).unwrap();
+        assert!(!boss.damage(100.0));
+        assert_eq!(run(&mut boss, 61), vec![BossEvent::PhaseStart { phase: 0 }]);
+        assert_eq!(boss.position(), [0.0, 100.0]);
+        assert!(boss.damage(100.0));
+        assert_eq!(run(&mut boss, 1), vec![BossEvent::PhaseEnd { phase: 0, captured: false, bonus: 0 }, BossEvent::PhaseStart { phase: 1 }]);
+        assert!(boss.hp_fraction() < 1.0);
+        run(&mut boss, 40);
+        assert_eq!(boss.hp_fraction(), 1.0);
+        boss.damage(100.0);
+        assert_eq!(run(&mut boss, 1)[0], BossEvent::PhaseEnd { phase: 1, captured: true, bonus: 1000 });
+    }
+
+    #[test]
+    fn timeout_and_survival() {
+        let def = Rc::new(BossDef::parse(SRC, &Resource::default()).unwrap());
+        let mut boss = Boss::new(def, [0.0, 100.0]).unwrap();
+        let events = run(&mut boss, 60 * 7);
+        assert!(events.contains(&BossEvent::Timeout { phase: 0 }));
+        boss.fail_capture();
+        let mut events = run(&mut boss, 60 * 6);
+        assert!(events.contains(&BossEvent::PhaseEnd { phase: 1, captured: false, bonus: 0 }));
+        assert!(boss.is_invulnerable());
+        events.extend(run(&mut boss, 70));
+        assert!(events.contains(&BossEvent::PhaseEnd { phase: 2, captured: true, bonus: 500 }));
+        assert_eq!(events.last(), Some(&BossEvent::Defeated));
+        assert!(boss.is_defeated());
+    }
+
+    #[test]
+    fn bad_definitions_are_rejected() {
+        let src = r#"{ "name": "test", "phases": [ { "name": "card", "spell": true, "time": 5 } ] }"#;
+        assert!(matches!(BossDef::parse(src, &Resource::default()), Err(BossError::NoHp { phase: 0 })));
+        let empty = BossDef { name: "empty".to_string(), phases: Vec::new() };
+        assert!(matches!(Boss::new(Rc::new(empty), [0.0, 0.0]), Err(BossError::NoPhases)));
+    }
+
+    #[test]
+    fn sample_boss_loads() {
+        let def = BossDef::load(&Resource::default(), "rsc/boss/stage1_boss.json").unwrap();
+        assert_eq!(def.name, "stage1_boss");
+        assert!(def.phases.iter().any(|p| p.spell && p.pattern.is_some()));
+    }
+}
@@ -0,0 +1,458 @@
+use std::ops::Range;
+use glium::{Surface, Program, DrawParameters, VertexBuffer};
+use glium::backend::Facade;
+use glium::texture::Texture2d;
+use glium::uniforms::MagnifySamplerFilter;
+
+use super::mesh::{Mesh, INDICES4_RECT};
+use super::util::Resource;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const NANOS_PER_SEC: f32 = 1_000_000_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BulletId {
+
+    index: u32,
+
+    generation: u32,
+
+}
+
+/// positions in world units, velocity / acceleration per second, lifetime in seconds (0 = until culled)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bullet {
+
+    pub position: [f32; 2],
+
+    pub velocity: [f32; 2],
+
+    pub acceleration: [f32; 2],
+
+    pub angle: f32,
+
+    pub age: f32,
+
+    pub lifetime: f32,
+
+    pub sheet: u16,
+
+    pub sprite: u16,
+
+    pub radius: f32,
+
+}
+
+impl Bullet {
+
+    /// bullet moving at `speed` towards `angle` (radians), facing its direction of travel
+    pub fn polar(position: [f32; 2], angle: f32, speed: f32) -> Self {
+        Bullet {
+            position,
+            velocity: [speed * angle.cos(), speed * angle.sin()],
+            angle,
+            .. Default::default()
+        }
+    }
+}
+
+struct Slot {
+
+    bullet: Bullet,
+
+    generation: u32,
+
+    alive: bool,
+
+}
+
+/// fixed-capacity pool; ids of killed bullets are invalidated by bumping the slot generation
+pub struct BulletPool {
+
+    slots: Vec<Slot>,
+
+    free: Vec<u32>,
+
+    live: usize,
+
+    bounds: Option<([f32; 2], [f32; 2])>,
+
+}
+
+impl BulletPool {
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut slots = Vec::with_capacity(capacity);
+        let mut free = Vec::with_capacity(capacity);
+        let mut i = 0;
+        while i < capacity {
+            slots.push(Slot { bullet: Bullet::default(), generation: 0, alive: false });
+            free.push((capacity - 1 - i) as u32);
+            i += 1;
+        }
+        BulletPool {
+            slots,
+            free,
+            live: 0,
+            bounds: None,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    /// bullets leaving this box are killed during `update`
+    pub fn set_bounds(&mut self, min: [f32; 2], max: [f32; 2]) -> &mut Self {
+        self.bounds = Some((min, max));
+        self
+    }
+
+    /// returns `None` when the pool is full
+    pub fn spawn(&mut self, bullet: Bullet) -> Option<BulletId> {
+        let index = self.free.pop()?;
+        let slot = &mut self.slots[index as usize];
+        slot.bullet = bullet;
+        slot.alive = true;
+        self.live += 1;
+        Some(BulletId { index, generation: slot.generation })
+    }
+
+    pub fn kill(&mut self, id: BulletId) -> bool {
+        if self.is_alive(id) {
+            self.kill_index(id.index as usize);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_alive(&self, id: BulletId) -> bool {
+        match self.slots.get(id.index as usize) {
+            Some(slot) => slot.alive && slot.generation == id.generation,
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: BulletId) -> Option<&Bullet> {
+        if self.is_alive(id) {
+            Some(&self.slots[id.index as usize].bullet)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, id: BulletId) -> Option<&mut Bullet> {
+        if self.is_alive(id) {
+            Some(&mut self.slots[id.index as usize].bullet)
+        } else {
+            None
+        }
+    }
+
+    pub fn clear(&mut self) {
+        let mut i = 0;
+        while i < self.slots.len() {
+            if self.slots[i].alive {
+                self.kill_index(i);
+            }
+            i += 1;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (BulletId, &Bullet)> {
+        self.slots.iter().enumerate().filter(|(_, slot)| slot.alive).map(|(i, slot)| {
+            (BulletId { index: i as u32, generation: slot.generation }, &slot.bullet)
+        })
+    }
+
+    /// keep only the bullets for which `f` returns true
+    pub fn retain<F: FnMut(BulletId, &mut Bullet) -> bool>(&mut self, mut f: F) {
+        let mut i = 0;
+        while i < self.slots.len() {
+            let slot = &mut self.slots[i];
+            if slot.alive && !f(BulletId { index: i as u32, generation: slot.generation }, &mut slot.bullet) {
+                self.kill_index(i);
+            }
+            i += 1;
+        }
+    }
+
+    /// integrate every live bullet by `dt` nanoseconds, killing expired and out-of-bounds ones
+    pub fn update(&mut self, dt: u64) {
+        let t = dt as f32 / NANOS_PER_SEC;
+        let bounds = self.bounds;
+        self.retain(|_, b| {
+            b.velocity[0] += b.acceleration[0] * t;
+            b.velocity[1] += b.acceleration[1] * t;
+            b.position[0] += b.velocity[0] * t;
+            b.position[1] += b.velocity[1] * t;
+            b.age += t;
+            if b.lifetime > 0.0 && b.age >= b.lifetime {
+                return false;
+            }
+            if let Some((min, max)) = bounds {
+                let r = b.radius;
+                if b.position[0] + r < min[0] || b.position[0] - r > max[0] || b.position[1] + r < min[1] || b.position[1] - r > max[1] {
+                    return false;
+                }
+            }
+            true
+        });
+    }
+
+    fn kill_index(&mut self, index: usize) {
+        let slot = &mut self.slots[index];
+        slot.alive = false;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(index as u32);
+        self.live -= 1;
+    }
+}
+
+
+
+#[derive(Copy, Clone)]
+pub struct BulletCorner {
+    corner: [f32; 2],
+}
+
+implement_vertex!(BulletCorner, corner);
+
+#[derive(Copy, Clone)]
+pub struct BulletInstance {
+    b_position: [f32; 2],
+    b_angle: f32,
+    b_size: [f32; 2],
+    b_uv: [f32; 4],
+}
+
+implement_vertex!(BulletInstance, b_position, b_angle, b_size, b_uv);
+
+/// texture cut into a grid of equally sized sprites, numbered row by row from the top left
+pub struct SpriteSheet {
+
+    texture: Texture2d,
+
+    columns: u16,
+
+    rows: u16,
+
+    size: [f32; 2],
+
+}
+
+impl SpriteSheet {
+
+    /// `size` is the on-screen size of one sprite in world units
+    pub fn new(texture: Texture2d, columns: u16, rows: u16, size: [f32; 2]) -> Self {
+        SpriteSheet {
+            texture,
+            columns: std::cmp::max(columns, 1),
+            rows: std::cmp::max(rows, 1),
+            size,
+        }
+    }
+
+    /// [u0, v0, u1, v1] of a sprite
+    pub fn uv(&self, sprite: u16) -> [f32; 4] {
+        let c = (sprite % self.columns) as f32;
+        let r = ((sprite / self.columns) % self.rows) as f32;
+        let w = 1.0 / self.columns as f32;
+        let h = 1.0 / self.rows as f32;
+        [c * w, 1.0 - (r + 1.0) * h, (c + 1.0) * w, 1.0 - r * h]
+    }
+
+    pub fn texture(&self) -> &Texture2d {
+        &self.texture
+    }
+}
+
+/// uploads live bullets into one dynamic instance buffer and draws one instanced quad per sheet
+pub struct BulletRenderer {
+
+    quad: Mesh<BulletCorner>,
+
+    instances: VertexBuffer<BulletInstance>,
+
+    staging: Vec<BulletInstance>,
+
+    ranges: Vec<Range<usize>>,
+
+    sheets: Vec<SpriteSheet>,
+
+}
+
+impl BulletRenderer {
+
+    pub fn new(facade: &dyn Facade, capacity: usize) -> Result<Self> {
+        let corners = vec![
+            BulletCorner { corner: [-0.5, -0.5] },
+            BulletCorner { corner: [ 0.5, -0.5] },
+            BulletCorner { corner: [-0.5,  0.5] },
+            BulletCorner { corner: [ 0.5,  0.5] },
+        ];
+        let quad = Mesh::wrap(corners, INDICES4_RECT.to_vec(), glium::index::PrimitiveType::TrianglesList);
+        let instances = VertexBuffer::empty_dynamic(facade, capacity).map_err(Box::new)?;
+        Ok(BulletRenderer {
+            quad,
+            instances,
+            staging: Vec::with_capacity(capacity),
+            ranges: Vec::new(),
+            sheets: Vec::new(),
+        })
+    }
+
+    /// program built from `glsl/bullet.vert` and `glsl/bullet.frag`
+    pub fn load_program(facade: &dyn Facade, resource: &Resource) -> Result<Program> {
+        let vert = resource.load_as_string("glsl/bullet.vert").map_err(Box::new)?;
+        let frag = resource.load_as_string("glsl/bullet.frag").map_err(Box::new)?;
+        let prog = Program::from_source(facade, &vert, &frag, None).map_err(Box::new)?;
+        Ok(prog)
+    }
+
+    /// returns the sheet id to store in `Bullet::sheet`
+    pub fn add_sheet(&mut self, sheet: SpriteSheet) -> u16 {
+        self.sheets.push(sheet);
+        (self.sheets.len() - 1) as u16
+    }
+
+    pub fn get_sheet(&self, sheet: u16) -> Option<&SpriteSheet> {
+        self.sheets.get(sheet as usize)
+    }
+
+    /// copy every live bullet into the instance buffer, grouped by sheet
+    pub fn upload(&mut self, pool: &BulletPool) {
+        let sheets = self.sheets.len();
+        let capacity = self.instances.len();
+        self.ranges.clear();
+        self.ranges.resize(sheets, 0..0);
+        if sheets == 0 {
+            return;
+        }
+
+        let mut counts = vec![0usize; sheets];
+        for (_, b) in pool.iter() {
+            if (b.sheet as usize) < sheets {
+                counts[b.sheet as usize] += 1;
+            }
+        }
+        let mut start = 0;
+        let mut s = 0;
+        while s < sheets {
+            let end = std::cmp::min(start + counts[s], capacity);
+            self.ranges[s] = start..end;
+            start = end;
+            s += 1;
+        }
+
+        let empty = BulletInstance { b_position: [0.0, 0.0], b_angle: 0.0, b_size: [0.0, 0.0], b_uv: [0.0; 4] };
+        self.staging.clear();
+        self.staging.resize(start, empty);
+        let mut cursor: Vec<usize> = self.ranges.iter().map(|r| r.start).collect();
+        for (_, b) in pool.iter() {
+            let s = b.sheet as usize;
+            if s < sheets && cursor[s] < self.ranges[s].end {
+                let sheet = &self.sheets[s];
+                self.staging[cursor[s]] = BulletInstance {
+                    b_position: b.position,
+                    b_angle: b.angle,
+                    b_size: sheet.size,
+                    b_uv: sheet.uv(b.sprite),
+                };
+                cursor[s] += 1;
+            }
+        }
+        if start > 0 {
+            if let Some(slice) = self.instances.slice_mut(0..start) {
+                slice.write(&self.staging);
+            }
+        }
+    }
+
+    /// one instanced draw per non-empty sheet; `projection` maps world units to clip space
+    pub fn draw<S: Surface>(&self, facade: &dyn Facade, target: &mut S, program: &Program, projection: [[f32; 4]; 4], draw_parameters: &DrawParameters) -> Result<()> {
+        for (sheet, range) in self.sheets.iter().zip(self.ranges.iter()) {
+            if range.start == range.end {
+                continue;
+            }
+            let slice = match self.instances.slice(range.clone()) {
+                Some(slice) => slice,
+                None => continue,
+            };
+            let texture = sheet.texture.sampled().magnify_filter(MagnifySamplerFilter::Nearest);
+            let uniforms = uniform!{ texture_sampler: texture, projection: projection };
+            let per_instance = slice.per_instance().map_err(|e| format!("{:?}", e))?;
+            self.quad.draw_instances(facade, target, per_instance, program, &uniforms, draw_parameters)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn stale_handle_is_rejected_after_kill() {
+        let mut pool = BulletPool::with_capacity(4);
+        let id = pool.spawn(Bullet::default()).unwrap();
+        assert!(pool.kill(id));
+        assert!(!pool.is_alive(id));
+        assert!(pool.get(id).is_none());
+        assert!(!pool.kill(id));
+
+        // the slot comes back with a new generation, the old handle must not reach it
+        let again = pool.spawn(Bullet::default()).unwrap();
+        assert_eq!(again.index, id.index);
+        assert_ne!(again.generation, id.generation);
+        assert!(pool.get_mut(id).is_none());
+        assert!(pool.is_alive(again));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let mut pool = BulletPool::with_capacity(3);
+        let ids: Vec<BulletId> = (0..3).map(|_| pool.spawn(Bullet::default()).unwrap()).collect();
+        assert_eq!(ids.iter().map(|id| id.index).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(pool.spawn(Bullet::default()).is_none());
+
+        pool.kill(ids[1]);
+        let id = pool.spawn(Bullet::default()).unwrap();
+        assert_eq!(id.index, 1);
+        assert_eq!(pool.len(), 3);
+        assert!(pool.spawn(Bullet::default()).is_none());
+    }
+
+    #[test]
+    fn update_drops_expired_and_out_of_bounds_bullets() {
+        let mut pool = BulletPool::with_capacity(8);
+        pool.set_bounds([-10.0, -10.0], [10.0, 10.0]);
+        let stays = pool.spawn(Bullet { velocity: [1.0, 0.0], ..Default::default() }).unwrap();
+        let expires = pool.spawn(Bullet { lifetime: 0.5, ..Default::default() }).unwrap();
+        let leaves = pool.spawn(Bullet::polar([9.0, 0.0], 0.0, 4.0)).unwrap();
+        // the radius keeps it alive while it still overlaps the box
+        let grazes = pool.spawn(Bullet { position: [10.5, 0.0], radius: 1.0, ..Default::default() }).unwrap();
+
+        pool.update(250_000_000);
+        assert_eq!(pool.len(), 4);
+        pool.update(250_000_000);
+        assert!(!pool.is_alive(expires));
+        assert!(!pool.is_alive(leaves));
+        assert!(pool.is_alive(grazes));
+        let b = pool.get(stays).unwrap();
+        assert!((b.position[0] - 0.5).abs() < 1e-6 && (b.age - 0.5).abs() < 1e-6);
+        assert_eq!(pool.len(), 2);
+    }
+}
@@ -0,0 +1,524 @@
+use super::bullet::{BulletPool, BulletId};
+
+/// collision shapes in world units; angles in radians
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Circle { center: [f32; 2], radius: f32 },
+    /// segment from `a` to `b` swept by `radius`, used for lasers
+    Capsule { a: [f32; 2], b: [f32; 2], radius: f32 },
+    Aabb { min: [f32; 2], max: [f32; 2] },
+    /// rectangle of half extents `half` rotated by `angle` around `center`
+    Obb { center: [f32; 2], half: [f32; 2], angle: f32 },
+}
+
+/// result of testing a shape against another with a graze margin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Proximity {
+    Hit,
+    /// not touching, but within the graze radius
+    Graze,
+    Miss,
+}
+
+// every shape is a point, segment or rectangle grown by a radius
+enum Core {
+    Point([f32; 2]),
+    Segment([f32; 2], [f32; 2]),
+    Rect { center: [f32; 2], half: [f32; 2], cos: f32, sin: f32 },
+}
+
+impl Shape {
+
+    /// [min, max] box containing the shape
+    pub fn bounds(&self) -> ([f32; 2], [f32; 2]) {
+        match *self {
+            Shape::Circle { center, radius } => {
+                ([center[0] - radius, center[1] - radius], [center[0] + radius, center[1] + radius])
+            }
+            Shape::Capsule { a, b, radius } => {
+                ([a[0].min(b[0]) - radius, a[1].min(b[1]) - radius], [a[0].max(b[0]) + radius, a[1].max(b[1]) + radius])
+            }
+            Shape::Aabb { min, max } => (min, max),
+            Shape::Obb { center, half, angle } => {
+                let (sin, cos) = angle.sin_cos();
+                let ex = half[0] * cos.abs() + half[1] * sin.abs();
+                let ey = half[0] * sin.abs() + half[1] * cos.abs();
+                ([center[0] - ex, center[1] - ey], [center[0] + ex, center[1] + ey])
+            }
+        }
+    }
+
+    /// gap between the two shapes, 0 when they touch or overlap
+    pub fn distance(&self, other: &Shape) -> f32 {
+        let (a, ra) = self.core();
+        let (b, rb) = other.core();
+        (core_distance(&a, &b) - ra - rb).max(0.0)
+    }
+
+    pub fn intersects(&self, other: &Shape) -> bool {
+        self.distance(other) <= 0.0
+    }
+
+    /// `Graze` when the gap is positive but no larger than `graze`
+    pub fn proximity(&self, other: &Shape, graze: f32) -> Proximity {
+        let d = self.distance(other);
+        if d <= 0.0 {
+            Proximity::Hit
+        } else if d <= graze {
+            Proximity::Graze
+        } else {
+            Proximity::Miss
+        }
+    }
+
+    fn core(&self) -> (Core, f32) {
+        match *self {
+            Shape::Circle { center, radius } => (Core::Point(center), radius),
+            Shape::Capsule { a, b, radius } => (Core::Segment(a, b), radius),
+            Shape::Aabb { min, max } => {
+                let center = [(min[0] + max[0]) * 0.5, (min[1] + max[1]) * 0.5];
+                let half = [(max[0] - min[0]) * 0.5, (max[1] - min[1]) * 0.5];
+                (Core::Rect { center, half, cos: 1.0, sin: 0.0 }, 0.0)
+            }
+            Shape::Obb { center, half, angle } => {
+                let (sin, cos) = angle.sin_cos();
+                (Core::Rect { center, half, cos, sin }, 0.0)
+            }
+        }
+    }
+}
+
+fn sub(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    [a[0] - b[0], a[1] - b[1]]
+}
+
+fn dot(a: [f32; 2], b: [f32; 2]) -> f32 {
+    a[0] * b[0] + a[1] * b[1]
+}
+
+fn cross(a: [f32; 2], b: [f32; 2]) -> f32 {
+    a[0] * b[1] - a[1] * b[0]
+}
+
+fn length(a: [f32; 2]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+fn core_distance(a: &Core, b: &Core) -> f32 {
+    match (a, b) {
+        (Core::Point(p), Core::Point(q)) => length(sub(*p, *q)),
+        (Core::Point(p), Core::Segment(s0, s1)) | (Core::Segment(s0, s1), Core::Point(p)) => point_segment(*p, *s0, *s1),
+        (Core::Segment(a0, a1), Core::Segment(b0, b1)) => segment_segment(*a0, *a1, *b0, *b1),
+        (Core::Point(p), r @ Core::Rect { .. }) | (r @ Core::Rect { .. }, Core::Point(p)) => point_rect(*p, r),
+        (Core::Segment(s0, s1), r @ Core::Rect { .. }) | (r @ Core::Rect { .. }, Core::Segment(s0, s1)) => segment_rect(*s0, *s1, r),
+        (Core::Rect { .. }, Core::Rect { .. }) => rect_rect(a, b),
+    }
+}
+
+fn point_segment(p: [f32; 2], a: [f32; 2], b: [f32; 2]) -> f32 {
+    let ab = sub(b, a);
+    let len2 = dot(ab, ab);
+    let t = if len2 > 0.0 { (dot(sub(p, a), ab) / len2).clamp(0.0, 1.0) } else { 0.0 };
+    length(sub(p, [a[0] + ab[0] * t, a[1] + ab[1] * t]))
+}
+
+fn segment_segment(a0: [f32; 2], a1: [f32; 2], b0: [f32; 2], b1: [f32; 2]) -> f32 {
+    let da = sub(a1, a0);
+    let db = sub(b1, b0);
+    let denom = cross(da, db);
+    if denom != 0.0 {
+        let t = cross(sub(b0, a0), db) / denom;
+        let u = cross(sub(b0, a0), da) / denom;
+        if (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u) {
+            return 0.0;
+        }
+    }
+    point_segment(a0, b0, b1)
+        .min(point_segment(a1, b0, b1))
+        .min(point_segment(b0, a0, a1))
+        .min(point_segment(b1, a0, a1))
+}
+
+// point in the rectangle's own frame, centered on it
+fn to_local(p: [f32; 2], rect: &Core) -> [f32; 2] {
+    match *rect {
+        Core::Rect { center, cos, sin, .. } => {
+            let d = sub(p, center);
+            [d[0] * cos + d[1] * sin, -d[0] * sin + d[1] * cos]
+        }
+        _ => p,
+    }
+}
+
+fn corners(rect: &Core) -> [[f32; 2]; 4] {
+    match *rect {
+        Core::Rect { center, half, cos, sin } => {
+            let x = [half[0] * cos, half[0] * sin];
+            let y = [-half[1] * sin, half[1] * cos];
+            [
+                [center[0] - x[0] - y[0], center[1] - x[1] - y[1]],
+                [center[0] + x[0] - y[0], center[1] + x[1] - y[1]],
+                [center[0] + x[0] + y[0], center[1] + x[1] + y[1]],
+                [center[0] - x[0] + y[0], center[1] - x[1] + y[1]],
+            ]
+        }
+        Core::Point(p) => [p; 4],
+        Core::Segment(a, b) => [a, b, b, a],
+    }
+}
+
+fn half_of(rect: &Core) -> [f32; 2] {
+    match *rect {
+        Core::Rect { half, .. } => half,
+        _ => [0.0, 0.0],
+    }
+}
+
+fn point_rect(p: [f32; 2], rect: &Core) -> f32 {
+    let l = to_local(p, rect);
+    let half = half_of(rect);
+    let dx = (l[0].abs() - half[0]).max(0.0);
+    let dy = (l[1].abs() - half[1]).max(0.0);
+    (dx * dx + dy * dy).sqrt()
+}
+
+fn segment_rect(a: [f32; 2], b: [f32; 2], rect: &Core) -> f32 {
+    let la = to_local(a, rect);
+    let lb = to_local(b, rect);
+    let half = half_of(rect);
+
+    // clip the segment against the box slab by slab
+    let d = sub(lb, la);
+    let mut t0: f32 = 0.0;
+    let mut t1: f32 = 1.0;
+    let mut inside = true;
+    let mut k = 0;
+    while k < 2 && inside {
+        if d[k] == 0.0 {
+            inside = la[k].abs() <= half[k];
+        } else {
+            let mut ta = (-half[k] - la[k]) / d[k];
+            let mut tb = (half[k] - la[k]) / d[k];
+            if ta > tb {
+                std::mem::swap(&mut ta, &mut tb);
+            }
+            t0 = t0.max(ta);
+            t1 = t1.min(tb);
+            inside = t0 <= t1;
+        }
+        k += 1;
+    }
+    if inside {
+        return 0.0;
+    }
+
+    let mut best = point_rect(a, rect).min(point_rect(b, rect));
+    for c in corners(rect).iter() {
+        best = best.min(point_segment(*c, a, b));
+    }
+    best
+}
+
+fn rect_rect(a: &Core, b: &Core) -> f32 {
+    let ca = corners(a);
+    let cb = corners(b);
+    if !separated(&ca, &cb) {
+        return 0.0;
+    }
+    // closest features of two disjoint convex polygons always include a corner
+    let mut best = f32::MAX;
+    for c in ca.iter() {
+        best = best.min(point_rect(*c, b));
+    }
+    for c in cb.iter() {
+        best = best.min(point_rect(*c, a));
+    }
+    best
+}
+
+// separating axis test over the edge normals of both rectangles
+fn separated(ca: &[[f32; 2]; 4], cb: &[[f32; 2]; 4]) -> bool {
+    let axes = [sub(ca[1], ca[0]), sub(ca[3], ca[0]), sub(cb[1], cb[0]), sub(cb[3], cb[0])];
+    for axis in axes.iter() {
+        let (mut amin, mut amax) = (f32::MAX, f32::MIN);
+        let (mut bmin, mut bmax) = (f32::MAX, f32::MIN);
+        for c in ca.iter() {
+            let p = dot(*c, *axis);
+            amin = amin.min(p);
+            amax = amax.max(p);
+        }
+        for c in cb.iter() {
+            let p = dot(*c, *axis);
+            bmin = bmin.min(p);
+            bmax = bmax.max(p);
+        }
+        if amax < bmin || bmax < amin {
+            return true;
+        }
+    }
+    false
+}
+
+
+
+pub const LAYER_PLAYER: u32 = 1;
+pub const LAYER_PLAYER_BULLET: u32 = 1 << 1;
+pub const LAYER_ENEMY: u32 = 1 << 2;
+pub const LAYER_ENEMY_BULLET: u32 = 1 << 3;
+pub const LAYER_ITEM: u32 = 1 << 4;
+
+/// `layer` is what the collider is, `mask` what it wants to touch
+#[derive(Debug, Clone, Copy)]
+pub struct Collider<K> {
+
+    pub shape: Shape,
+
+    pub layer: u32,
+
+    pub mask: u32,
+
+    pub key: K,
+
+}
+
+impl<K> Collider<K> {
+
+    /// both sides have to accept each other, as with player bullets vs enemies
+    pub fn accepts(&self, other: &Collider<K>) -> bool {
+        self.mask & other.layer != 0 && other.mask & self.layer != 0
+    }
+}
+
+/// colliders inserted during a tick, bucketed into a uniform grid over the playfield by `update`
+pub struct CollisionWorld<K> {
+
+    origin: [f32; 2],
+
+    cell: f32,
+
+    columns: usize,
+
+    rows: usize,
+
+    colliders: Vec<Collider<K>>,
+
+    // inclusive cell range [x0, y0, x1, y1] of each collider
+    spans: Vec<[usize; 4]>,
+
+    cells: Vec<Vec<u32>>,
+
+}
+
+impl<K: Copy> CollisionWorld<K> {
+
+    /// grid of `cell` sized squares covering `min`..`max`; shapes outside fall into the border cells
+    pub fn new(min: [f32; 2], max: [f32; 2], cell: f32) -> Self {
+        let cell = cell.max(f32::EPSILON);
+        let columns = std::cmp::max(((max[0] - min[0]) / cell).ceil() as usize, 1);
+        let rows = std::cmp::max(((max[1] - min[1]) / cell).ceil() as usize, 1);
+        CollisionWorld {
+            origin: min,
+            cell,
+            columns,
+            rows,
+            colliders: Vec::new(),
+            spans: Vec::new(),
+            cells: (0..columns * rows).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.colliders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colliders.is_empty()
+    }
+
+    /// drop every collider, keeping allocations for the next tick
+    pub fn clear(&mut self) {
+        self.colliders.clear();
+        self.spans.clear();
+        for c in self.cells.iter_mut() {
+            c.clear();
+        }
+    }
+
+    pub fn insert(&mut self, shape: Shape, layer: u32, mask: u32, key: K) {
+        self.colliders.push(Collider { shape, layer, mask, key });
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Collider<K>> {
+        self.colliders.get(index)
+    }
+
+    /// rebuild the grid from the colliders inserted since the last `clear`
+    pub fn update(&mut self) {
+        for c in self.cells.iter_mut() {
+            c.clear();
+        }
+        self.spans.clear();
+        let mut i = 0;
+        while i < self.colliders.len() {
+            let span = self.span(&self.colliders[i].shape, 0.0);
+            let mut y = span[1];
+            while y <= span[3] {
+                let mut x = span[0];
+                while x <= span[2] {
+                    self.cells[y * self.columns + x].push(i as u32);
+                    x += 1;
+                }
+                y += 1;
+            }
+            self.spans.push(span);
+            i += 1;
+        }
+    }
+
+    /// colliders on a layer in `mask` that `shape` hits or grazes; each is reported once
+    pub fn probe<F: FnMut(&Collider<K>, Proximity)>(&self, shape: &Shape, mask: u32, graze: f32, mut f: F) {
+        let graze = graze.max(0.0);
+        let span = self.span(shape, graze);
+        let mut y = span[1];
+        while y <= span[3] {
+            let mut x = span[0];
+            while x <= span[2] {
+                for &i in self.cells[y * self.columns + x].iter() {
+                    let other = &self.colliders[i as usize];
+                    if other.layer & mask == 0 || !first_shared_cell(&span, &self.spans[i as usize], x, y) {
+                        continue;
+                    }
+                    let p = shape.proximity(&other.shape, graze);
+                    if p != Proximity::Miss {
+                        f(other, p);
+                    }
+                }
+                x += 1;
+            }
+            y += 1;
+        }
+    }
+
+    /// colliders on a layer in `mask` that overlap `shape`
+    pub fn query<F: FnMut(&Collider<K>)>(&self, shape: &Shape, mask: u32, mut f: F) {
+        self.probe(shape, mask, 0.0, |c, p| {
+            if p == Proximity::Hit {
+                f(c);
+            }
+        });
+    }
+
+    /// colliders on a layer in `mask` within `graze` of `shape` without touching it
+    pub fn graze<F: FnMut(&Collider<K>)>(&self, shape: &Shape, mask: u32, graze: f32, mut f: F) {
+        self.probe(shape, mask, graze, |c, p| {
+            if p == Proximity::Graze {
+                f(c);
+            }
+        });
+    }
+
+    /// every overlapping pair whose layers and masks accept each other, each pair once
+    pub fn pairs<F: FnMut(&Collider<K>, &Collider<K>)>(&self, mut f: F) {
+        let mut y = 0;
+        while y < self.rows {
+            let mut x = 0;
+            while x < self.columns {
+                let cell = &self.cells[y * self.columns + x];
+                let mut i = 0;
+                while i < cell.len() {
+                    let a = cell[i] as usize;
+                    let mut j = i + 1;
+                    while j < cell.len() {
+                        let b = cell[j] as usize;
+                        let (ca, cb) = (&self.colliders[a], &self.colliders[b]);
+                        if ca.accepts(cb) && first_shared_cell(&self.spans[a], &self.spans[b], x, y) && ca.shape.intersects(&cb.shape) {
+                            f(ca, cb);
+                        }
+                        j += 1;
+                    }
+                    i += 1;
+                }
+                x += 1;
+            }
+            y += 1;
+        }
+    }
+
+    fn span(&self, shape: &Shape, margin: f32) -> [usize; 4] {
+        let (min, max) = shape.bounds();
+        let cx = |v: f32| ((v - self.origin[0]) / self.cell).floor().max(0.0).min((self.columns - 1) as f32) as usize;
+        let cy = |v: f32| ((v - self.origin[1]) / self.cell).floor().max(0.0).min((self.rows - 1) as f32) as usize;
+        [cx(min[0] - margin), cy(min[1] - margin), cx(max[0] + margin), cy(max[1] + margin)]
+    }
+}
+
+impl CollisionWorld<BulletId> {
+
+    /// one circle per live bullet, keyed by its id
+    pub fn insert_bullets(&mut self, pool: &BulletPool, layer: u32, mask: u32) {
+        for (id, b) in pool.iter() {
+            self.insert(Shape::Circle { center: b.position, radius: b.radius }, layer, mask, id);
+        }
+    }
+}
+
+// whether (x, y) is the lowest cell both spans cover, so a pair sharing several cells is visited once
+fn first_shared_cell(a: &[usize; 4], b: &[usize; 4], x: usize, y: usize) -> bool {
+    x == std::cmp::max(a[0], b[0]) && y == std::cmp::max(a[1], b[1])
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn circle(x: f32, y: f32, r: f32) -> Shape {
+        Shape::Circle { center: [x, y], radius: r }
+    }
+
+    #[test]
+    fn shape_distances() {
+        let c = circle(0.0, 0.0, 1.0);
+        assert!((c.distance(&circle(3.0, 0.0, 1.0)) - 1.0).abs() < 1e-5);
+        let laser = Shape::Capsule { a: [-5.0, 2.0], b: [5.0, 2.0], radius: 0.5 };
+        assert!((c.distance(&laser) - 0.5).abs() < 1e-5);
+        let crossing = Shape::Capsule { a: [0.0, -5.0], b: [0.0, 5.0], radius: 0.1 };
+        assert!(laser.intersects(&crossing));
+        let b = Shape::Aabb { min: [2.0, -1.0], max: [4.0, 1.0] };
+        assert!((c.distance(&b) - 1.0).abs() < 1e-5);
+        let o = Shape::Obb { center: [0.0, 3.0], half: [1.0, 1.0], angle: std::f32::consts::FRAC_PI_4 };
+        assert!((c.distance(&o) - (2.0 - std::f32::consts::SQRT_2)).abs() < 1e-5);
+        assert!(o.intersects(&Shape::Aabb { min: [-0.5, 1.5], max: [0.5, 1.7] }));
+        assert!(!o.intersects(&Shape::Aabb { min: [0.9, 1.5], max: [1.5, 1.9] }));
+    }
+
+    #[test]
+    fn proximity_reports_graze() {
+        let player = circle(0.0, 0.0, 0.2);
+        assert_eq!(player.proximity(&circle(0.25, 0.0, 0.1), 1.0), Proximity::Hit);
+        assert_eq!(player.proximity(&circle(1.0, 0.0, 0.1), 1.0), Proximity::Graze);
+        assert_eq!(player.proximity(&circle(2.0, 0.0, 0.1), 1.0), Proximity::Miss);
+    }
+
+    #[test]
+    fn world_filters_and_dedups() {
+        let mut world = CollisionWorld::new([0.0, 0.0], [10.0, 10.0], 1.0);
+        world.insert(circle(5.0, 5.0, 3.0), LAYER_ENEMY, LAYER_PLAYER_BULLET, 0);
+        world.insert(circle(5.0, 5.0, 0.1), LAYER_PLAYER_BULLET, LAYER_ENEMY, 1);
+        world.insert(circle(5.0, 5.0, 0.1), LAYER_ENEMY_BULLET, LAYER_PLAYER, 2);
+        world.insert(circle(5.0, 6.5, 0.1), LAYER_ENEMY_BULLET, LAYER_PLAYER, 3);
+        world.update();
+
+        let mut pairs = Vec::new();
+        world.pairs(|a, b| pairs.push((a.key, b.key)));
+        assert_eq!(pairs, vec![(0, 1)]);
+
+        let player = circle(5.0, 5.0, 0.2);
+        let mut hits = Vec::new();
+        world.query(&player, LAYER_ENEMY_BULLET, |c| hits.push(c.key));
+        assert_eq!(hits, vec![2]);
+        let mut grazes = Vec::new();
+        world.graze(&player, LAYER_ENEMY_BULLET, 2.0, |c| grazes.push(c.key));
+        assert_eq!(grazes, vec![3]);
+    }
+}
@@ -0,0 +1,743 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+use serde::Deserialize;
+
+use super::bullet::{Bullet, BulletPool};
+use super::util::Resource;
+use super::rng::Pcg32;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const MAX_DEPTH: usize = 64;
+
+const MAX_OPS_PER_TICK: usize = 4096;
+
+///
+/// Pattern file (json):
+///
+/// { "actions": { "top": [ { "repeat": { "times": "8 + $rank * 8", "actions": [
+///     { "fire": { "direction": { "sequence": 45 }, "speed": { "absolute": 120 }, "sprite": 2 } },
+///     { "wait": 6 } ] } } ] } }
+///
+/// angles are degrees counter-clockwise from +x, speeds are world units per second, waits are ticks.
+/// numbers may be given as expressions over `$rand`, `$rank`, `$loop` and call parameters `$1`, `$2`, ...
+#[derive(Debug)]
+pub struct Pattern {
+
+    blocks: Vec<Vec<Op>>,
+
+    entry: usize,
+
+}
+
+#[derive(Debug)]
+pub enum PatternError {
+    Json(serde_json::Error),
+    Expr(String, String),
+    UnknownAction(String),
+    MissingEntry(String),
+}
+
+impl fmt::Display for PatternError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatternError::Json(e) => write!(f, "pattern: {}", e),
+            PatternError::Expr(src, msg) => write!(f, "pattern: bad expression `{}`: {}", src, msg),
+            PatternError::UnknownAction(name) => write!(f, "pattern: unknown action `{}`", name),
+            PatternError::MissingEntry(name) => write!(f, "pattern: entry action `{}` not found", name),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Num(f32),
+    Rand,
+    Rank,
+    Loop,
+    Param(usize),
+    Neg(Box<Expr>),
+    Bin(char, Box<Expr>, Box<Expr>),
+}
+
+pub struct ExprEnv<'a> {
+
+    pub rank: f32,
+
+    pub index: f32,
+
+    pub params: &'a [f32],
+
+    pub rng: &'a mut Pcg32,
+
+}
+
+impl Expr {
+
+    pub fn parse(src: &str) -> std::result::Result<Expr, PatternError> {
+        let mut parser = ExprParser { src: src.as_bytes(), pos: 0 };
+        let e = parser.expr().map_err(|msg| PatternError::Expr(src.to_string(), msg))?;
+        parser.skip_ws();
+        if parser.pos < parser.src.len() {
+            return Err(PatternError::Expr(src.to_string(), format!("unexpected input at {}", parser.pos)));
+        }
+        Ok(e)
+    }
+
+    pub fn eval(&self, env: &mut ExprEnv) -> f32 {
+        match self {
+            Expr::Num(v) => *v,
+            Expr::Rand => env.rng.next_f32(),
+            Expr::Rank => env.rank,
+            Expr::Loop => env.index,
+            Expr::Param(i) => env.params.get(*i).cloned().unwrap_or(0.0),
+            Expr::Neg(e) => -e.eval(env),
+            Expr::Bin(op, a, b) => {
+                let a = a.eval(env);
+                let b = b.eval(env);
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    _ => a % b,
+                }
+            }
+        }
+    }
+}
+
+struct ExprParser<'s> {
+    src: &'s [u8],
+    pos: usize,
+}
+
+impl<'s> ExprParser<'s> {
+
+    fn skip_ws(&mut self) {
+        while self.pos < self.src.len() && self.src[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ws();
+        self.src.get(self.pos).cloned()
+    }
+
+    fn expr(&mut self) -> std::result::Result<Expr, String> {
+        let mut lhs = self.term()?;
+        while let Some(c) = self.peek() {
+            if c != b'+' && c != b'-' {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.term()?;
+            lhs = Expr::Bin(c as char, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> std::result::Result<Expr, String> {
+        let mut lhs = self.factor()?;
+        while let Some(c) = self.peek() {
+            if c != b'*' && c != b'/' && c != b'%' {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.factor()?;
+            lhs = Expr::Bin(c as char, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn factor(&mut self) -> std::result::Result<Expr, String> {
+        match self.peek() {
+            Some(b'-') => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.factor()?)))
+            }
+            Some(b'(') => {
+                self.pos += 1;
+                let e = self.expr()?;
+                if self.peek() != Some(b')') {
+                    return Err(format!("expected `)` at {}", self.pos));
+                }
+                self.pos += 1;
+                Ok(e)
+            }
+            Some(b'$') => {
+                self.pos += 1;
+                let start = self.pos;
+                while self.pos < self.src.len() && self.src[self.pos].is_ascii_alphanumeric() {
+                    self.pos += 1;
+                }
+                let name = std::str::from_utf8(&self.src[start..self.pos]).unwrap_or("");
+                match name {
+                    "rand" => Ok(Expr::Rand),
+                    "rank" => Ok(Expr::Rank),
+                    "loop" => Ok(Expr::Loop),
+                    _ => match name.parse::<usize>() {
+                        Ok(i) if i > 0 => Ok(Expr::Param(i - 1)),
+                        _ => Err(format!("unknown variable `${}`", name)),
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == b'.' => {
+                let start = self.pos;
+                while self.pos < self.src.len() && (self.src[self.pos].is_ascii_digit() || self.src[self.pos] == b'.') {
+                    self.pos += 1;
+                }
+                let text = std::str::from_utf8(&self.src[start..self.pos]).unwrap_or("");
+                text.parse::<f32>().map(Expr::Num).map_err(|e| format!("{}", e))
+            }
+            Some(c) => Err(format!("unexpected `{}` at {}", c as char, self.pos)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExprSource {
+    Num(f32),
+    Text(String),
+}
+
+impl TryFrom<ExprSource> for Expr {
+    type Error = PatternError;
+
+    fn try_from(src: ExprSource) -> std::result::Result<Self, Self::Error> {
+        match src {
+            ExprSource::Num(v) => Ok(Expr::Num(v)),
+            ExprSource::Text(s) => Expr::parse(&s),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let src = ExprSource::deserialize(deserializer)?;
+        Expr::try_from(src).map_err(serde::de::Error::custom)
+    }
+}
+
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Aim(Expr),
+    Absolute(Expr),
+    Relative(Expr),
+    Sequence(Expr),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Speed {
+    Absolute(Expr),
+    Relative(Expr),
+    Sequence(Expr),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fire {
+
+    #[serde(default)]
+    pub direction: Option<Direction>,
+
+    #[serde(default)]
+    pub speed: Option<Speed>,
+
+    #[serde(default)]
+    pub sheet: u16,
+
+    #[serde(default)]
+    pub sprite: u16,
+
+    #[serde(default)]
+    pub radius: f32,
+
+    #[serde(default)]
+    pub lifetime: f32,
+
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum RawAction {
+    Fire(Fire),
+    Repeat { times: Expr, actions: Vec<RawAction> },
+    Wait(Expr),
+    ChangeDirection { direction: Direction, term: Expr },
+    ChangeSpeed { speed: Speed, term: Expr },
+    Call { action: String, #[serde(default)] params: Vec<Expr> },
+    Vanish,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPattern {
+
+    actions: HashMap<String, Vec<RawAction>>,
+
+    #[serde(default = "default_entry")]
+    entry: String,
+
+}
+
+fn default_entry() -> String {
+    "top".to_string()
+}
+
+#[derive(Debug)]
+enum Op {
+    Fire(Fire),
+    Repeat(Expr, usize),
+    Wait(Expr),
+    ChangeDirection(Direction, Expr),
+    ChangeSpeed(Speed, Expr),
+    Call(usize, Vec<Expr>),
+    Vanish,
+}
+
+impl Pattern {
+
+    pub fn parse(src: &str) -> std::result::Result<Pattern, PatternError> {
+        let raw: RawPattern = serde_json::from_str(src).map_err(PatternError::Json)?;
+        let mut names: Vec<&String> = raw.actions.keys().collect();
+        names.sort();
+        let ids: HashMap<&str, usize> = names.iter().enumerate().map(|(i, n)| (n.as_str(), i)).collect();
+        let entry = *ids.get(raw.entry.as_str()).ok_or_else(|| PatternError::MissingEntry(raw.entry.clone()))?;
+        let mut blocks: Vec<Vec<Op>> = names.iter().map(|_| Vec::new()).collect();
+        for name in names.iter() {
+            let block = Self::compile_block(&raw.actions[*name], &ids, &mut blocks)?;
+            blocks[ids[name.as_str()]] = block;
+        }
+        Ok(Pattern { blocks, entry })
+    }
+
+    pub fn load(resource: &Resource, file: &str) -> Result<Pattern> {
+        let src = resource.load_as_string(file).map_err(Box::new)?;
+        let pattern = Self::parse(&src).map_err(Box::new)?;
+        Ok(pattern)
+    }
+
+    fn compile_block(actions: &[RawAction], ids: &HashMap<&str, usize>, blocks: &mut Vec<Vec<Op>>) -> std::result::Result<Vec<Op>, PatternError> {
+        let mut ops = Vec::with_capacity(actions.len());
+        for action in actions {
+            let op = match action {
+                RawAction::Fire(fire) => Op::Fire(fire.clone()),
+                RawAction::Repeat { times, actions } => {
+                    let block = Self::compile_block(actions, ids, blocks)?;
+                    blocks.push(block);
+                    Op::Repeat(times.clone(), blocks.len() - 1)
+                }
+                RawAction::Wait(ticks) => Op::Wait(ticks.clone()),
+                RawAction::ChangeDirection { direction, term } => Op::ChangeDirection(direction.clone(), term.clone()),
+                RawAction::ChangeSpeed { speed, term } => Op::ChangeSpeed(speed.clone(), term.clone()),
+                RawAction::Call { action, params } => {
+                    let id = *ids.get(action.as_str()).ok_or_else(|| PatternError::UnknownAction(action.clone()))?;
+                    Op::Call(id, params.clone())
+                }
+                RawAction::Vanish => Op::Vanish,
+            };
+            ops.push(op);
+        }
+        Ok(ops)
+    }
+}
+
+
+
+/// what an emitter can see and touch while it runs
+pub struct EmitterEnv<'p> {
+
+    pub pool: &'p mut BulletPool,
+
+    pub target: [f32; 2],
+
+    pub rank: f32,
+
+    /// source of `$rand`; pass the gameplay stream so patterns replay identically
+    pub rng: &'p mut Pcg32,
+
+}
+
+struct Frame {
+
+    block: usize,
+
+    pc: usize,
+
+    remaining: u32,
+
+    index: u32,
+
+    params: Rc<[f32]>,
+
+}
+
+struct Change {
+
+    delta: f32,
+
+    ticks: u32,
+
+}
+
+/// runs one `Pattern` from its entry action, stepped once per update tick
+pub struct Emitter {
+
+    pattern: Rc<Pattern>,
+
+    stack: Vec<Frame>,
+
+    wait: u32,
+
+    pub position: [f32; 2],
+
+    /// heading in degrees
+    pub direction: f32,
+
+    /// world units per second; the emitter itself moves along `direction`
+    pub speed: f32,
+
+    last_direction: f32,
+
+    last_speed: f32,
+
+    change_direction: Option<Change>,
+
+    change_speed: Option<Change>,
+
+}
+
+impl Emitter {
+
+    pub fn new(pattern: Rc<Pattern>, position: [f32; 2]) -> Self {
+        let entry = pattern.entry;
+        Emitter {
+            pattern,
+            stack: vec![Frame { block: entry, pc: 0, remaining: 1, index: 0, params: Rc::from(Vec::new()) }],
+            wait: 0,
+            position,
+            direction: 0.0,
+            speed: 0.0,
+            last_direction: 0.0,
+            last_speed: 1.0,
+            change_direction: None,
+            change_speed: None,
+        }
+    }
+
+    pub fn with_params(mut self, params: Vec<f32>) -> Self {
+        if let Some(frame) = self.stack.first_mut() {
+            frame.params = Rc::from(params);
+        }
+        self
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    /// advance by one update tick lasting `dt` nanoseconds
+    pub fn step(&mut self, dt: u64, env: &mut EmitterEnv) {
+        if let Some(change) = &mut self.change_direction {
+            self.direction += change.delta;
+            change.ticks -= 1;
+            if change.ticks == 0 {
+                self.change_direction = None;
+            }
+        }
+        if let Some(change) = &mut self.change_speed {
+            self.speed += change.delta;
+            change.ticks -= 1;
+            if change.ticks == 0 {
+                self.change_speed = None;
+            }
+        }
+        if self.speed != 0.0 {
+            let t = dt as f32 / 1_000_000_000.0;
+            let rad = self.direction.to_radians();
+            self.position[0] += self.speed * rad.cos() * t;
+            self.position[1] += self.speed * rad.sin() * t;
+        }
+
+        if self.wait > 0 {
+            self.wait -= 1;
+            if self.wait > 0 {
+                return;
+            }
+        }
+        let pattern = self.pattern.clone();
+        let mut budget = MAX_OPS_PER_TICK;
+        while budget > 0 {
+            budget -= 1;
+            let frame = match self.stack.last_mut() {
+                Some(frame) => frame,
+                None => return,
+            };
+            let block = &pattern.blocks[frame.block];
+            if frame.pc >= block.len() {
+                frame.remaining = frame.remaining.saturating_sub(1);
+                if frame.remaining > 0 {
+                    frame.pc = 0;
+                    frame.index += 1;
+                } else {
+                    self.stack.pop();
+                }
+                continue;
+            }
+            let op = &block[frame.pc];
+            frame.pc += 1;
+            let params = frame.params.clone();
+            let index = self.loop_index();
+            let expr_env = &mut ExprEnv { rank: env.rank, index, params: &params, rng: &mut *env.rng };
+            match op {
+                Op::Fire(fire) => self.fire(fire, expr_env, env.pool, env.target),
+                Op::Repeat(times, block) => {
+                    let times = times.eval(expr_env).round();
+                    if times >= 1.0 && self.stack.len() < MAX_DEPTH {
+                        self.stack.push(Frame { block: *block, pc: 0, remaining: times as u32, index: 0, params });
+                    }
+                }
+                Op::Wait(ticks) => {
+                    let ticks = ticks.eval(expr_env).round();
+                    if ticks >= 1.0 {
+                        self.wait = ticks as u32;
+                        return;
+                    }
+                }
+                Op::ChangeDirection(direction, term) => {
+                    let term = term.eval(expr_env).round().max(1.0);
+                    let delta = match direction {
+                        Direction::Sequence(v) => v.eval(expr_env),
+                        _ => {
+                            let target = self.resolve_direction(direction, expr_env, env.target);
+                            normalize_degrees(target - self.direction) / term
+                        }
+                    };
+                    self.change_direction = Some(Change { delta, ticks: term as u32 });
+                }
+                Op::ChangeSpeed(speed, term) => {
+                    let term = term.eval(expr_env).round().max(1.0);
+                    let delta = match speed {
+                        Speed::Sequence(v) => v.eval(expr_env),
+                        Speed::Absolute(v) => (v.eval(expr_env) - self.speed) / term,
+                        Speed::Relative(v) => v.eval(expr_env) / term,
+                    };
+                    self.change_speed = Some(Change { delta, ticks: term as u32 });
+                }
+                Op::Call(block, args) => {
+                    if self.stack.len() < MAX_DEPTH {
+                        let args: Vec<f32> = args.iter().map(|a| a.eval(expr_env)).collect();
+                        self.stack.push(Frame { block: *block, pc: 0, remaining: 1, index: 0, params: Rc::from(args) });
+                    }
+                }
+                Op::Vanish => {
+                    self.stack.clear();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn loop_index(&self) -> f32 {
+        match self.stack.last() {
+            Some(frame) => frame.index as f32,
+            None => 0.0,
+        }
+    }
+
+    fn resolve_direction(&self, direction: &Direction, expr_env: &mut ExprEnv, target: [f32; 2]) -> f32 {
+        match direction {
+            Direction::Aim(v) => {
+                let dx = target[0] - self.position[0];
+                let dy = target[1] - self.position[1];
+                dy.atan2(dx).to_degrees() + v.eval(expr_env)
+            }
+            Direction::Absolute(v) => v.eval(expr_env),
+            Direction::Relative(v) => self.direction + v.eval(expr_env),
+            Direction::Sequence(v) => self.last_direction + v.eval(expr_env),
+        }
+    }
+
+    fn fire(&mut self, fire: &Fire, expr_env: &mut ExprEnv, pool: &mut BulletPool, target: [f32; 2]) {
+        let direction = match &fire.direction {
+            Some(direction) => self.resolve_direction(direction, expr_env, target),
+            None => self.resolve_direction(&Direction::Aim(Expr::Num(0.0)), expr_env, target),
+        };
+        let speed = match &fire.speed {
+            Some(Speed::Absolute(v)) => v.eval(expr_env),
+            Some(Speed::Relative(v)) => self.speed + v.eval(expr_env),
+            Some(Speed::Sequence(v)) => self.last_speed + v.eval(expr_env),
+            None => self.last_speed,
+        };
+        self.last_direction = direction;
+        self.last_speed = speed;
+        let mut bullet = Bullet::polar(self.position, direction.to_radians(), speed);
+        bullet.sheet = fire.sheet;
+        bullet.sprite = fire.sprite;
+        bullet.radius = fire.radius;
+        bullet.lifetime = fire.lifetime;
+        pool.spawn(bullet);
+    }
+}
+
+fn normalize_degrees(mut a: f32) -> f32 {
+    a %= 360.0;
+    if a > 180.0 {
+        a -= 360.0;
+    } else if a < -180.0 {
+        a += 360.0;
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn eval(src: &str) -> f32 {
+        let mut rng = Pcg32::new(1, 1);
+        Expr::parse(src).unwrap().eval(&mut ExprEnv { rank: 0.0, index: 0.0, params: &[], rng: &mut rng })
+    }
+
+    fn parse_error(src: &str) -> String {
+        match Expr::parse(src) {
+            Err(PatternError::Expr(_, msg)) => msg,
+            other => panic!("expected an expression error, got {:?}", other),
+        }
+    }
+
+    // run `src` for `ticks` ticks and return how many bullets were alive after each one
+    fn run(src: &str, target: [f32; 2], ticks: usize) -> (Emitter, BulletPool, Vec<usize>) {
+        let mut emitter = Emitter::new(Rc::new(Pattern::parse(src).unwrap()), [0.0, 0.0]);
+        let mut pool = BulletPool::with_capacity(10_000);
+        let mut rng = Pcg32::new(7, 3);
+        let mut counts = Vec::new();
+        while counts.len() < ticks {
+            emitter.step(0, &mut EmitterEnv { pool: &mut pool, target, rank: 0.5, rng: &mut rng });
+            counts.push(pool.len());
+        }
+        (emitter, pool, counts)
+    }
+
+    fn directions(pool: &BulletPool) -> Vec<f32> {
+        let mut dirs: Vec<f32> = pool.iter().map(|(_, b)| b.angle.to_degrees().round()).collect();
+        dirs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        dirs
+    }
+
+    #[test]
+    fn expressions_follow_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("8 - 2 - 1"), 5.0);
+        assert_eq!(eval("-2 * 3 + 10 % 4"), -4.0);
+        assert_eq!(eval("--1.5"), 1.5);
+        assert_eq!(eval("-(1 + 1) / 4"), -0.5);
+    }
+
+    #[test]
+    fn expression_errors_report_positions() {
+        assert_eq!(parse_error("1 + * 2"), "unexpected `*` at 4");
+        assert_eq!(parse_error("(1 + 2"), "expected `)` at 6");
+        assert_eq!(parse_error("1 2"), "unexpected input at 2");
+        assert_eq!(parse_error("2 *"), "unexpected end of expression");
+        assert_eq!(parse_error("$speed"), "unknown variable `$speed`");
+        assert_eq!(parse_error("$0"), "unknown variable `$0`");
+    }
+
+    #[test]
+    fn variables_read_the_environment() {
+        let e = Expr::parse("$rank * 10 + $loop + $1 * $2 + $3").unwrap();
+        let mut rng = Pcg32::new(1, 1);
+        let mut env = ExprEnv { rank: 0.5, index: 3.0, params: &[2.0, 4.0], rng: &mut rng };
+        // missing parameters read as 0
+        assert_eq!(e.eval(&mut env), 16.0);
+
+        let rand = Expr::parse("$rand").unwrap();
+        let mut a = Pcg32::new(9, 2);
+        let mut b = Pcg32::new(9, 2);
+        let mut i = 0;
+        while i < 16 {
+            let x = rand.eval(&mut ExprEnv { rank: 0.0, index: 0.0, params: &[], rng: &mut a });
+            assert!((0.0..1.0).contains(&x));
+            assert_eq!(x, b.next_f32());
+            i += 1;
+        }
+    }
+
+    #[test]
+    fn repeat_and_wait_count_ticks() {
+        let src = r#"{ "actions": { "top": [ { "repeat": { "times": "1 + 2", "actions": [ { "fire": {} }, { "wait": 2 } ] } } ] } }"#;
+        let (emitter, _, counts) = run(src, [0.0, 10.0], 8);
+        // one bullet every second tick, finished once the last wait ran out
+        assert_eq!(counts, vec![1, 1, 2, 2, 3, 3, 3, 3]);
+        assert!(emitter.is_finished());
+    }
+
+    #[test]
+    fn call_passes_parameters() {
+        let src = r#"{ "actions": {
+            "ring": [ { "repeat": { "times": "$1", "actions": [ { "fire": { "direction": { "absolute": "$loop * $2" } } } ] } } ],
+            "top": [ { "call": { "action": "ring", "params": [4, "45 * 2"] } } ] } }"#;
+        let (emitter, pool, _) = run(src, [0.0, 10.0], 1);
+        assert_eq!(directions(&pool), vec![0.0, 90.0, 180.0, 270.0]);
+        assert!(emitter.is_finished());
+    }
+
+    #[test]
+    fn sequence_accumulates_from_the_aimed_shot() {
+        let src = r#"{ "actions": { "top": [
+            { "fire": { "direction": { "aim": 0 }, "speed": { "absolute": 100 } } },
+            { "repeat": { "times": 2, "actions": [ { "fire": { "direction": { "sequence": 15 }, "speed": { "sequence": 5 } } } ] } } ] } }"#;
+        // target straight above, so the aimed shot goes at 90 degrees
+        let (_, pool, _) = run(src, [0.0, 10.0], 1);
+        assert_eq!(directions(&pool), vec![90.0, 105.0, 120.0]);
+        let mut speeds: Vec<f32> = pool.iter().map(|(_, b)| (b.velocity[0].hypot(b.velocity[1])).round()).collect();
+        speeds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(speeds, vec![100.0, 105.0, 110.0]);
+    }
+
+    #[test]
+    fn runaway_patterns_are_bounded() {
+        // a loop without waits is cut off after the op budget and resumes next tick
+        let src = r#"{ "actions": { "top": [ { "repeat": { "times": 100000, "actions": [ { "fire": {} } ] } } ] } }"#;
+        let (emitter, _, counts) = run(src, [0.0, 10.0], 2);
+        assert!(counts[0] > 0 && counts[0] < MAX_OPS_PER_TICK);
+        assert!(counts[1] > counts[0]);
+        assert!(!emitter.is_finished());
+
+        // unbounded recursion stops at the stack limit
+        let src = r#"{ "actions": { "top": [ { "fire": {} }, { "call": { "action": "top" } } ] } }"#;
+        let (emitter, _, counts) = run(src, [0.0, 10.0], 1);
+        assert_eq!(counts, vec![MAX_DEPTH]);
+        assert!(emitter.is_finished());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let typo = r#"{ "actions": { "top": [ { "fire": { "sped": { "absolute": 100 } } } ] } }"#;
+        assert!(matches!(Pattern::parse(typo), Err(PatternError::Json(_))));
+        let typo = r#"{ "actions": { "top": [ { "repeat": { "times": 2, "action": [] } } ] } }"#;
+        assert!(matches!(Pattern::parse(typo), Err(PatternError::Json(_))));
+        let typo = r#"{ "actions": { "top": [] }, "entyr": "top" }"#;
+        assert!(matches!(Pattern::parse(typo), Err(PatternError::Json(_))));
+    }
+}
@@ -0,0 +1,508 @@
+use std::any::{Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use glium::{Display, Frame, Surface};
+use glium::backend::Facade;
+
+use super::game::{GameLogic, SchedulerSettings};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Entity {
+
+    index: u32,
+
+    generation: u32,
+
+}
+
+impl Entity {
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+/// sparse set: components packed densely, looked up through the entity index
+pub struct Storage<C> {
+
+    dense: Vec<C>,
+
+    owners: Vec<Entity>,
+
+    sparse: Vec<u32>,
+
+}
+
+impl<C> Default for Storage<C> {
+
+    fn default() -> Self {
+        Storage {
+            dense: Vec::new(),
+            owners: Vec::new(),
+            sparse: Vec::new(),
+        }
+    }
+}
+
+impl<C> Storage<C> {
+
+    pub fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+
+    /// returns the component it replaced
+    pub fn insert(&mut self, entity: Entity, component: C) -> Option<C> {
+        let i = entity.index as usize;
+        if i >= self.sparse.len() {
+            self.sparse.resize(i + 1, NONE);
+        }
+        let d = self.sparse[i];
+        if d != NONE {
+            let d = d as usize;
+            self.owners[d] = entity;
+            return Some(std::mem::replace(&mut self.dense[d], component));
+        }
+        self.sparse[i] = self.dense.len() as u32;
+        self.dense.push(component);
+        self.owners.push(entity);
+        None
+    }
+
+    pub fn remove(&mut self, entity: Entity) -> Option<C> {
+        let d = self.slot(entity)?;
+        let last = self.dense.len() - 1;
+        if d != last {
+            self.sparse[self.owners[last].index as usize] = d as u32;
+        }
+        self.sparse[entity.index as usize] = NONE;
+        self.owners.swap_remove(d);
+        Some(self.dense.swap_remove(d))
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.slot(entity).is_some()
+    }
+
+    pub fn get(&self, entity: Entity) -> Option<&C> {
+        let d = self.slot(entity)?;
+        Some(&self.dense[d])
+    }
+
+    pub fn get_mut(&mut self, entity: Entity) -> Option<&mut C> {
+        let d = self.slot(entity)?;
+        Some(&mut self.dense[d])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &C)> {
+        self.owners.iter().cloned().zip(self.dense.iter())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut C)> {
+        self.owners.iter().cloned().zip(self.dense.iter_mut())
+    }
+
+    fn slot(&self, entity: Entity) -> Option<usize> {
+        let d = *self.sparse.get(entity.index as usize)?;
+        if d == NONE || self.owners[d as usize] != entity {
+            return None;
+        }
+        Some(d as usize)
+    }
+}
+
+// type-erased storage so the world can drop every component of a despawned entity
+trait AnyStorage {
+
+    fn remove_entity(&mut self, entity: Entity);
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<C: 'static> AnyStorage for Storage<C> {
+
+    fn remove_entity(&mut self, entity: Entity) {
+        self.remove(entity);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// entities and one storage per component type; storages borrow independently like `RefCell`s
+#[derive(Default)]
+pub struct World {
+
+    generations: Vec<u32>,
+
+    alive: Vec<bool>,
+
+    free: Vec<u32>,
+
+    live: usize,
+
+    storages: HashMap<TypeId, RefCell<Box<dyn AnyStorage>>>,
+
+}
+
+impl World {
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    pub fn spawn(&mut self) -> Entity {
+        self.live += 1;
+        if let Some(index) = self.free.pop() {
+            self.alive[index as usize] = true;
+            return Entity { index, generation: self.generations[index as usize] };
+        }
+        self.generations.push(0);
+        self.alive.push(true);
+        Entity { index: (self.generations.len() - 1) as u32, generation: 0 }
+    }
+
+    /// drops every component of `entity` and invalidates the id
+    pub fn despawn(&mut self, entity: Entity) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+        for s in self.storages.values_mut() {
+            s.get_mut().remove_entity(entity);
+        }
+        let i = entity.index as usize;
+        self.alive[i] = false;
+        self.generations[i] = self.generations[i].wrapping_add(1);
+        self.free.push(entity.index);
+        self.live -= 1;
+        true
+    }
+
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        let i = entity.index as usize;
+        i < self.alive.len() && self.alive[i] && self.generations[i] == entity.generation
+    }
+
+    pub fn register<C: 'static>(&mut self) -> &mut Self {
+        self.storages.entry(TypeId::of::<C>()).or_insert_with(|| RefCell::new(Box::new(Storage::<C>::default())));
+        self
+    }
+
+    pub fn is_registered<C: 'static>(&self) -> bool {
+        self.storages.contains_key(&TypeId::of::<C>())
+    }
+
+    /// registers `C` on first use; returns false when the entity is dead
+    pub fn insert<C: 'static>(&mut self, entity: Entity, component: C) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+        self.register::<C>();
+        if let Some(s) = self.storage_mut::<C>() {
+            s.insert(entity, component);
+        }
+        true
+    }
+
+    pub fn remove<C: 'static>(&mut self, entity: Entity) -> Option<C> {
+        self.storage_mut::<C>()?.remove(entity)
+    }
+
+    /// `None` if `C` was never registered; panics if it is borrowed by `write`
+    pub fn read<C: 'static>(&self) -> Option<Ref<'_, Storage<C>>> {
+        let cell = self.storages.get(&TypeId::of::<C>())?;
+        Some(Ref::map(cell.borrow(), |s| s.as_any().downcast_ref::<Storage<C>>().unwrap()))
+    }
+
+    /// `None` if `C` was never registered; panics if it is already borrowed
+    pub fn write<C: 'static>(&self) -> Option<RefMut<'_, Storage<C>>> {
+        let cell = self.storages.get(&TypeId::of::<C>())?;
+        Some(RefMut::map(cell.borrow_mut(), |s| s.as_any_mut().downcast_mut::<Storage<C>>().unwrap()))
+    }
+
+    /// run everything queued in `commands`, in order
+    pub fn apply(&mut self, commands: &mut Commands) {
+        for command in commands.queue.drain(..) {
+            match command {
+                Command::Spawn(parts) => {
+                    let entity = self.spawn();
+                    for part in parts {
+                        part(self, entity);
+                    }
+                }
+                Command::Despawn(entity) => {
+                    self.despawn(entity);
+                }
+                Command::Edit(entity, edit) => {
+                    if self.is_alive(entity) {
+                        edit(self, entity);
+                    }
+                }
+            }
+        }
+    }
+
+    // no borrow bookkeeping needed with exclusive access
+    fn storage_mut<C: 'static>(&mut self) -> Option<&mut Storage<C>> {
+        let cell = self.storages.get_mut(&TypeId::of::<C>())?;
+        cell.get_mut().as_any_mut().downcast_mut::<Storage<C>>()
+    }
+}
+
+type Edit = Box<dyn FnOnce(&mut World, Entity)>;
+
+enum Command {
+    Spawn(Vec<Edit>),
+    Despawn(Entity),
+    Edit(Entity, Edit),
+}
+
+/// structural changes queued while storages are borrowed, applied by `World::apply`
+#[derive(Default)]
+pub struct Commands {
+
+    queue: Vec<Command>,
+
+}
+
+impl Commands {
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// new entity with the components added through the returned builder
+    pub fn spawn(&mut self) -> SpawnBuilder<'_> {
+        self.queue.push(Command::Spawn(Vec::new()));
+        match self.queue.last_mut() {
+            Some(Command::Spawn(parts)) => SpawnBuilder { parts },
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn despawn(&mut self, entity: Entity) {
+        self.queue.push(Command::Despawn(entity));
+    }
+
+    pub fn insert<C: 'static>(&mut self, entity: Entity, component: C) {
+        self.queue.push(Command::Edit(entity, Box::new(move |w, e| { w.insert(e, component); })));
+    }
+
+    pub fn remove<C: 'static>(&mut self, entity: Entity) {
+        self.queue.push(Command::Edit(entity, Box::new(|w, e| { w.remove::<C>(e); })));
+    }
+}
+
+pub struct SpawnBuilder<'a> {
+
+    parts: &'a mut Vec<Edit>,
+
+}
+
+impl SpawnBuilder<'_> {
+
+    pub fn with<C: 'static>(self, component: C) -> Self {
+        self.parts.push(Box::new(move |w, e| { w.insert(e, component); }));
+        self
+    }
+}
+
+/// `S` is the surface drawn on: the window `Frame`, or e.g. the framebuffer of an `OffscreenTarget`
+pub trait System<S: Surface = Frame> {
+
+    /// structural changes go through `commands`; they are applied before the next system runs
+    fn update(&mut self, _world: &mut World, _commands: &mut Commands, _dt: u64, _settings: &mut SchedulerSettings) -> Result<()> {
+        Ok(())
+    }
+
+    fn render(&mut self, _world: &World, _dt: u64, _facade: &dyn Facade, _target: &mut S, _settings: &mut SchedulerSettings) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// closure run as the `update` half of a system
+pub struct UpdateFn<F>(pub F);
+
+impl<S: Surface, F: FnMut(&mut World, &mut Commands, u64) -> Result<()>> System<S> for UpdateFn<F> {
+
+    fn update(&mut self, world: &mut World, commands: &mut Commands, dt: u64, _settings: &mut SchedulerSettings) -> Result<()> {
+        (self.0)(world, commands, dt)
+    }
+}
+
+/// systems in insertion order, rendering to surfaces of type `S`
+pub struct Schedule<S: Surface = Frame> {
+
+    systems: Vec<Box<dyn System<S>>>,
+
+    commands: Commands,
+
+}
+
+impl<S: Surface> Default for Schedule<S> {
+
+    fn default() -> Self {
+        Schedule {
+            systems: Vec::new(),
+            commands: Commands::default(),
+        }
+    }
+}
+
+impl<S: Surface> Schedule<S> {
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add<Y: System<S> + 'static>(&mut self, system: Y) -> &mut Self {
+        self.systems.push(Box::new(system));
+        self
+    }
+
+    pub fn add_fn<F: FnMut(&mut World, &mut Commands, u64) -> Result<()> + 'static>(&mut self, f: F) -> &mut Self {
+        self.add(UpdateFn(f))
+    }
+
+    pub fn update(&mut self, world: &mut World, dt: u64, settings: &mut SchedulerSettings) -> Result<()> {
+        for system in self.systems.iter_mut() {
+            system.update(world, &mut self.commands, dt, settings)?;
+            world.apply(&mut self.commands);
+        }
+        Ok(())
+    }
+
+    pub fn render(&mut self, world: &World, dt: u64, facade: &dyn Facade, target: &mut S, settings: &mut SchedulerSettings) -> Result<()> {
+        for system in self.systems.iter_mut() {
+            system.render(world, dt, facade, target, settings)?;
+        }
+        Ok(())
+    }
+}
+
+/// world plus schedule, usable directly as the game logic of a `Scheduler`
+#[derive(Default)]
+pub struct Ecs {
+
+    pub world: World,
+
+    pub schedule: Schedule,
+
+    pub clear_color: (f32, f32, f32, f32),
+
+}
+
+impl GameLogic for Ecs {
+
+    fn update(&mut self, dt: u64, settings: &mut SchedulerSettings) -> Result<()> {
+        self.schedule.update(&mut self.world, dt, settings)
+    }
+
+    fn render(&mut self, dt: u64, display: &Display, settings: &mut SchedulerSettings) -> Result<()> {
+        let mut target = display.draw();
+        target.clear_color_and_depth(self.clear_color, 1.0);
+        let result = self.schedule.render(&self.world, dt, display, &mut target, settings);
+        target.finish().map_err(Box::new)?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Position([f32; 2]);
+
+    struct Velocity([f32; 2]);
+
+    #[test]
+    fn despawned_ids_are_invalidated() {
+        let mut world = World::new();
+        let a = world.spawn();
+        world.insert(a, Position([1.0, 2.0]));
+        assert!(world.despawn(a));
+        let b = world.spawn();
+        assert_eq!(a.index(), b.index());
+        assert!(!world.is_alive(a));
+        assert!(!world.insert(a, Position([0.0, 0.0])));
+        assert!(world.read::<Position>().unwrap().get(b).is_none());
+    }
+
+    #[test]
+    fn storage_stays_dense() {
+        let mut world = World::new();
+        let e: Vec<Entity> = (0..4).map(|_| world.spawn()).collect();
+        for (i, e) in e.iter().enumerate() {
+            world.insert(*e, i);
+        }
+        assert!(world.read::<Velocity>().is_none());
+        assert!(world.remove::<Velocity>(e[0]).is_none());
+        assert_eq!(world.remove::<usize>(e[1]), Some(1));
+        let s = world.read::<usize>().unwrap();
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.get(e[3]), Some(&3));
+        assert_eq!(s.get(e[0]), Some(&0));
+    }
+
+    #[test]
+    fn commands_apply_after_iteration() {
+        let mut world = World::new();
+        let mut schedule: Schedule = Schedule::new();
+        let mut settings = SchedulerSettings::default();
+        let e = world.spawn();
+        world.insert(e, Position([0.0, 0.0]));
+        world.insert(e, Velocity([1.0, 0.0]));
+        schedule.add_fn(|world, commands, _| {
+            let mut pos = world.write::<Position>().unwrap();
+            let vel = world.read::<Velocity>().unwrap();
+            for (e, p) in pos.iter_mut() {
+                if let Some(v) = vel.get(e) {
+                    p.0[0] += v.0[0];
+                    p.0[1] += v.0[1];
+                }
+                if p.0[0] >= 2.0 {
+                    commands.despawn(e);
+                    commands.spawn().with(Position([-1.0, -1.0]));
+                }
+            }
+            Ok(())
+        });
+        schedule.update(&mut world, 0, &mut settings).unwrap();
+        assert!(world.is_alive(e));
+        schedule.update(&mut world, 0, &mut settings).unwrap();
+        assert!(!world.is_alive(e));
+        assert_eq!(world.len(), 1);
+        assert_eq!(world.read::<Position>().unwrap().iter().next().map(|(_, p)| p.0), Some([-1.0, -1.0]));
+    }
+}
@@ -2,6 +2,8 @@
 use std::time::{Instant, Duration};
 use glium::glutin::{EventsLoop, Event};
 
+use super::rng::Rng;
+
 const NANOS_PER_SEC: u64 = 1_000_000_000;
 
 pub struct SchedulerSettings {
@@ -18,6 +20,10 @@ pub struct SchedulerSettings {
 
     lazy: bool,
 
+    fixed_step: bool,
+
+    rng: Rng,
+
 }
 
 impl Default for SchedulerSettings {
@@ -29,7 +35,9 @@ impl Default for SchedulerSettings {
             ups: 60,
             dt_u: NANOS_PER_SEC / 60,
             ups_reset: 0,
-            lazy: false
+            lazy: false,
+            fixed_step: false,
+            rng: Rng::default(),
         }
     }
 }
@@ -62,17 +70,57 @@ impl SchedulerSettings {
         self
     }
 
+    /// feed `update` with exactly `dt_u` per tick and deliver input events on tick boundaries
+    pub fn set_fixed_step(&mut self, fixed_step: bool) -> &mut Self {
+        self.fixed_step = fixed_step;
+        self
+    }
+
+    /// restart the rng streams from `seed`, e.g. the one stored in a replay
+    pub fn set_seed(&mut self, seed: u64) -> &mut Self {
+        self.rng.reseed(seed);
+        self
+    }
+
+    /// gameplay stream only usable from `init`, `update` and fixed-step `handle_event`, see `Rng::gameplay`
+    pub fn get_rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    pub fn get_dt_u(&self) -> u64 {
+        self.dt_u
+    }
+
+    pub fn get_dt_f(&self) -> u64 {
+        self.dt_f
+    }
+
+    pub fn is_fixed_step(&self) -> bool {
+        self.fixed_step
+    }
+
 }
 
 
 
 use glium::glutin::{WindowBuilder, ContextBuilder, ContextCurrentState};
-use glium::{Display};
+use glium::glutin::dpi::PhysicalSize;
+use glium::{Display, HeadlessRenderer};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+///
+/// callbacks driven by `Scheduler` (window) or `HeadlessScheduler` (tests, tools)
+///
+/// put everything that does not need a display in `setup`, both schedulers call it first;
+/// `init`/`render` only run with a window, `init_headless`/`render_headless` only without one
 pub trait GameLogic {
 
+    // called before `init` or `init_headless`, for state that does not touch gl
+    fn setup(&mut self, _settings: &mut SchedulerSettings) -> Result<()> {
+        Ok(())
+    }
+
     fn init(&mut self, display: &Display, settings: &mut SchedulerSettings) -> Result<()> {
         Ok(())
     }
@@ -99,6 +147,51 @@ pub trait GameLogic {
     fn finalize(&mut self, err: Option<Box<dyn std::error::Error>>) -> Result<()> {
         Ok(())
     }
+
+    // called by `HeadlessScheduler` instead of `init`
+    fn init_headless(&mut self, _settings: &mut SchedulerSettings) -> Result<()> {
+        Ok(())
+    }
+
+    // called by `HeadlessScheduler` instead of `render` when an offscreen context is attached
+    fn render_headless(&mut self, _dt: u64, _renderer: &HeadlessRenderer, _settings: &mut SchedulerSettings) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<L: GameLogic + ?Sized> GameLogic for &mut L {
+
+    fn setup(&mut self, settings: &mut SchedulerSettings) -> Result<()> {
+        (**self).setup(settings)
+    }
+
+    fn init(&mut self, display: &Display, settings: &mut SchedulerSettings) -> Result<()> {
+        (**self).init(display, settings)
+    }
+
+    fn render(&mut self, dt: u64, display: &Display, settings: &mut SchedulerSettings) -> Result<()> {
+        (**self).render(dt, display, settings)
+    }
+
+    fn update(&mut self, dt: u64, settings: &mut SchedulerSettings) -> Result<()> {
+        (**self).update(dt, settings)
+    }
+
+    fn handle_event(&mut self, event: Event, settings: &mut SchedulerSettings, close: &mut bool) -> Result<()> {
+        (**self).handle_event(event, settings, close)
+    }
+
+    fn finalize(&mut self, err: Option<Box<dyn std::error::Error>>) -> Result<()> {
+        (**self).finalize(err)
+    }
+
+    fn init_headless(&mut self, settings: &mut SchedulerSettings) -> Result<()> {
+        (**self).init_headless(settings)
+    }
+
+    fn render_headless(&mut self, dt: u64, renderer: &HeadlessRenderer, settings: &mut SchedulerSettings) -> Result<()> {
+        (**self).render_headless(dt, renderer, settings)
+    }
 }
 
 pub trait GameClock {
@@ -109,6 +202,11 @@ pub trait GameClock {
     // nanoseconds since reset
     fn elapsed(&mut self) -> u64;
 
+    // wait until `nanos` nanoseconds have passed on this clock
+    fn sleep(&mut self, nanos: u64) {
+        std::thread::sleep(Duration::from_nanos(nanos));
+    }
+
 }
 
 enum State {
@@ -129,6 +227,8 @@ pub struct Scheduler<'a> {
 
     last_render: u64,
 
+    tick: u64,
+
     game_logic: Box<dyn GameLogic + 'a>,
 
     game_clock: Box<dyn GameClock + 'a>,
@@ -136,6 +236,12 @@ pub struct Scheduler<'a> {
     eventsloop: EventsLoop,
 
     display: Display,
+
+    pending: Vec<Event>,
+
+    record: Option<EventRecord>,
+
+    playback: Option<EventRecord>,
 }
 
 impl<'a> Default for Scheduler<'a> {
@@ -147,11 +253,15 @@ impl<'a> Default for Scheduler<'a> {
             settings: SchedulerSettings::default(),
             last_update: 0,
             lag_update: 0,
-            last_render: 0,          
+            last_render: 0,
+            tick: 0,
             game_logic: Box::new(EmptyGameLogic::default()),
             game_clock: Box::new(StdGameClock::default()),
             eventsloop,
-            display
+            display,
+            pending: Vec::new(),
+            record: None,
+            playback: None,
         }
     }
 }
@@ -166,10 +276,14 @@ impl<'a> Scheduler<'a> {
             last_update: 0,
             lag_update: 0,
             last_render: 0,
+            tick: 0,
             game_logic: Box::new(game_logic),
             game_clock: Box::new(game_clock),
             eventsloop,
-            display
+            display,
+            pending: Vec::new(),
+            record: None,
+            playback: None,
         }
     }
 
@@ -189,20 +303,52 @@ impl<'a> Scheduler<'a> {
         &self.eventsloop
     }
 
+    /// number of `update` calls since the last `run`
+    pub fn get_tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// record every event delivered in fixed-step mode together with its tick
+    pub fn start_recording(&mut self) -> &mut Self {
+        self.record = Some(EventRecord::new());
+        self
+    }
+
+    pub fn take_record(&mut self) -> Option<EventRecord> {
+        self.record.take()
+    }
+
+    /// replay a recorded session in fixed-step mode; live events are dropped except window close requests
+    pub fn set_playback(&mut self, mut record: EventRecord) -> &mut Self {
+        record.rewind();
+        self.playback = Some(record);
+        self.settings.set_fixed_step(true);
+        self
+    }
+
     pub fn run(&mut self) -> Result<()> {
         let settings = &mut self.settings;
         let logic = self.game_logic.as_mut();
         let clock = self.game_clock.as_mut();
         let eventsloop = &mut self.eventsloop;
+        let pending = &mut self.pending;
+        let record = &mut self.record;
+        let playback = &mut self.playback;
         clock.reset();
         let mut now = clock.elapsed();
         self.last_update = 0;
+        self.tick = 0;
         let mut next_update = now;
         self.last_render = 0;
         let mut next_render = now;
         let mut res = None;
         let mut state = State::Update;
-        if let Err(e) = logic.init(&self.display, settings) {
+        pending.clear();
+        if let Some(playback) = playback {
+            playback.rewind();
+        }
+        let display = &self.display;
+        if let Err(e) = logic.setup(settings).and_then(|_| logic.init(display, settings)) {
             res = Some(e);
         } else {
             loop {
@@ -212,7 +358,10 @@ impl<'a> Scheduler<'a> {
                     now = clock.elapsed();
                 }
                 if now >= next_render {
-                    if let Err(e) = logic.render(now - self.last_render, &self.display, settings) {
+                    settings.rng.set_gameplay_locked(true);
+                    let rendered = logic.render(now - self.last_render, &self.display, settings);
+                    settings.rng.set_gameplay_locked(false);
+                    if let Err(e) = rendered {
                         res = Some(e); 
                         break;
                     }
@@ -226,18 +375,31 @@ impl<'a> Scheduler<'a> {
                 if now < next_render && now < next_update {
                     let wait = std::cmp::min(next_render - now, next_update - now);
                     state = State::Sleep;
-                    std::thread::sleep(Duration::from_nanos(wait)); 
+                    clock.sleep(wait);
                 }
 
                 //input
                 let mut close = false;
+                let replaying = playback.is_some();
+                // recorded events only come back on tick boundaries
+                let fixed_step = settings.fixed_step || replaying;
                 eventsloop.poll_events(|evt| {
                     if let None = res {
                         if !close {
-                            if let Err(e) = logic.handle_event(evt, settings, &mut close) {
-                                res = Some(e);
+                            if replaying {
+                                close = is_close_requested(&evt);
+                            } else if fixed_step {
+                                pending.push(evt);
                             } else {
-                                state = State::HandleEvents;
+                                // events arrive at frame rate here, outside the update ticks
+                                settings.rng.set_gameplay_locked(true);
+                                let handled = logic.handle_event(evt, settings, &mut close);
+                                settings.rng.set_gameplay_locked(false);
+                                if let Err(e) = handled {
+                                    res = Some(e);
+                                } else {
+                                    state = State::HandleEvents;
+                                }
                             }
                         }
                     }
@@ -254,10 +416,23 @@ impl<'a> Scheduler<'a> {
                     now = clock.elapsed();
                 }
                 if now >= next_update {
-                    if let Err(e) = logic.update(now - self.last_update, settings) {
+                    if fixed_step {
+                        let source: &mut dyn EventSource = match playback.as_mut() {
+                            Some(playback) => playback,
+                            None => pending,
+                        };
+                        if let Err(e) = fixed_update(logic, settings, self.tick, source, record, &mut close) {
+                            res = Some(e);
+                            break;
+                        }
+                        if close {
+                            break;
+                        }
+                    } else if let Err(e) = logic.update(now - self.last_update, settings) {
                         res = Some(e); 
                         break; 
                     }
+                    self.tick += 1;
                     self.last_update = now;
                     self.lag_update = Self::update_time(&mut next_update, now, settings.dt_u, settings.ups_reset as u64);
                     state = State::Update;
@@ -278,6 +453,291 @@ impl<'a> Scheduler<'a> {
     }
 }
 
+fn is_close_requested(event: &Event) -> bool {
+    matches!(event, Event::WindowEvent{ event: glium::glutin::WindowEvent::CloseRequested, .. })
+}
+
+// deliver the events due on `tick`, then advance the logic by exactly `dt_u`
+fn fixed_update(logic: &mut dyn GameLogic, settings: &mut SchedulerSettings, tick: u64, source: &mut dyn EventSource, record: &mut Option<EventRecord>, close: &mut bool) -> Result<()> {
+    source.poll_tick(tick, &mut |evt| {
+        if let Some(record) = record.as_mut() {
+            record.push(tick, evt.clone());
+        }
+        if !*close {
+            logic.handle_event(evt, settings, close)?;
+        }
+        Ok(())
+    })?;
+    if !*close {
+        logic.update(settings.dt_u, settings)?;
+    }
+    Ok(())
+}
+
+
+
+pub trait EventSource {
+
+    // hand every event due on `tick` to `f`
+    fn poll_tick(&mut self, tick: u64, f: &mut dyn FnMut(Event) -> Result<()>) -> Result<()>;
+
+    // true when no more events will ever be produced
+    fn is_exhausted(&self) -> bool {
+        false
+    }
+}
+
+// events queued since the last tick are all due now
+impl EventSource for Vec<Event> {
+
+    fn poll_tick(&mut self, _tick: u64, f: &mut dyn FnMut(Event) -> Result<()>) -> Result<()> {
+        self.drain(..).try_for_each(f)
+    }
+}
+
+impl EventSource for EventRecord {
+
+    fn poll_tick(&mut self, tick: u64, f: &mut dyn FnMut(Event) -> Result<()>) -> Result<()> {
+        self.drain_tick(tick, f)
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.is_finished()
+    }
+}
+
+/// produces no events at all
+#[derive(Default)]
+pub struct NoEvents;
+
+impl EventSource for NoEvents {
+
+    fn poll_tick(&mut self, _tick: u64, _f: &mut dyn FnMut(Event) -> Result<()>) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_exhausted(&self) -> bool {
+        true
+    }
+}
+
+
+
+/// input events tagged with the update tick they were delivered on
+#[derive(Debug, Clone, Default)]
+pub struct EventRecord {
+
+    events: Vec<(u64, Event)>,
+
+    cursor: usize,
+
+}
+
+impl EventRecord {
+
+    pub fn new() -> Self {
+        EventRecord {
+            events: Vec::new(),
+            cursor: 0,
+        }
+    }
+
+    /// ticks must be pushed in non-decreasing order
+    pub fn push(&mut self, tick: u64, event: Event) {
+        debug_assert!(self.events.last().map(|(t, _)| *t <= tick).unwrap_or(true));
+        self.events.push((tick, event));
+    }
+
+    pub fn events(&self) -> &[(u64, Event)] {
+        self.events.as_slice()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// tick of the last recorded event
+    pub fn last_tick(&self) -> Option<u64> {
+        self.events.last().map(|(tick, _)| *tick)
+    }
+
+    pub fn rewind(&mut self) {
+        self.cursor = 0;
+    }
+
+    /// true when every recorded event has been played back
+    pub fn is_finished(&self) -> bool {
+        self.cursor >= self.events.len()
+    }
+
+    /// hand over all events recorded for `tick`, skipping anything older
+    pub fn drain_tick<F: FnMut(Event) -> Result<()>>(&mut self, tick: u64, mut f: F) -> Result<()> {
+        while self.cursor < self.events.len() {
+            let (t, evt) = &self.events[self.cursor];
+            if *t > tick {
+                break;
+            }
+            self.cursor += 1;
+            if *t == tick {
+                f(evt.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+
+/// drives `GameLogic` on a virtual clock without a window; rendering is optional
+pub struct HeadlessScheduler<'a> {
+
+    settings: SchedulerSettings,
+
+    tick: u64,
+
+    now: u64,
+
+    last_render: u64,
+
+    next_render: u64,
+
+    closed: bool,
+
+    game_logic: Box<dyn GameLogic + 'a>,
+
+    game_clock: Box<dyn GameClock + 'a>,
+
+    event_source: Box<dyn EventSource + 'a>,
+
+    // the events loop only exists where the context needs one to live
+    renderer: Option<(Option<EventsLoop>, HeadlessRenderer)>,
+
+    record: Option<EventRecord>,
+}
+
+impl<'a> HeadlessScheduler<'a> {
+
+    pub fn new<L: GameLogic + 'a, C: GameClock + 'a, E: EventSource + 'a>(settings: SchedulerSettings, game_logic: L, game_clock: C, event_source: E) -> Self {
+        HeadlessScheduler {
+            settings,
+            tick: 0,
+            now: 0,
+            last_render: 0,
+            next_render: 0,
+            closed: false,
+            game_logic: Box::new(game_logic),
+            game_clock: Box::new(game_clock),
+            event_source: Box::new(event_source),
+            renderer: None,
+            record: None,
+        }
+    }
+
+    /// attach an offscreen GL context of the given size; `render_headless` is called at `fps`
+    ///
+    /// on unix this is a software OSMesa context that needs no display server, only libOSMesa;
+    /// elsewhere glutin's regular headless context is used
+    pub fn with_offscreen<T: ContextCurrentState>(mut self, cb: ContextBuilder<'a, T>, width: u32, height: u32) -> Result<Self> {
+        let size = PhysicalSize::new(width as f64, height as f64);
+        #[cfg(any(target_os = "linux", target_os = "dragonfly", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+        let (eventsloop, context) = {
+            use glium::glutin::os::unix::HeadlessContextExt;
+            (None, cb.build_osmesa(size).map_err(Box::new)?)
+        };
+        #[cfg(not(any(target_os = "linux", target_os = "dragonfly", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd")))]
+        let (eventsloop, context) = {
+            let eventsloop = EventsLoop::new();
+            let context = cb.build_headless(&eventsloop, size).map_err(Box::new)?;
+            (Some(eventsloop), context)
+        };
+        let renderer = HeadlessRenderer::new(context).map_err(Box::new)?;
+        self.renderer = Some((eventsloop, renderer));
+        Ok(self)
+    }
+
+    pub fn get_renderer(&self) -> Option<&HeadlessRenderer> {
+        self.renderer.as_ref().map(|(_, renderer)| renderer)
+    }
+
+    pub fn get_settings(&mut self) -> &mut SchedulerSettings {
+        &mut self.settings
+    }
+
+    pub fn get_tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn start_recording(&mut self) -> &mut Self {
+        self.record = Some(EventRecord::new());
+        self
+    }
+
+    pub fn take_record(&mut self) -> Option<EventRecord> {
+        self.record.take()
+    }
+
+    pub fn init(&mut self) -> Result<()> {
+        self.game_clock.reset();
+        self.now = self.game_clock.elapsed();
+        self.tick = 0;
+        self.last_render = self.now;
+        self.next_render = self.now;
+        self.closed = false;
+        self.game_logic.setup(&mut self.settings)?;
+        self.game_logic.init_headless(&mut self.settings)
+    }
+
+    /// run exactly one update tick; returns false once the logic asked to close
+    pub fn step(&mut self) -> Result<bool> {
+        if self.closed {
+            return Ok(false);
+        }
+        let settings = &mut self.settings;
+        let logic = self.game_logic.as_mut();
+        fixed_update(logic, settings, self.tick, self.event_source.as_mut(), &mut self.record, &mut self.closed)?;
+        if self.closed {
+            return Ok(false);
+        }
+        self.tick += 1;
+        self.game_clock.sleep(settings.dt_u);
+        self.now = self.game_clock.elapsed();
+        if let Some((_, renderer)) = &self.renderer {
+            if settings.fps > 0 && self.now >= self.next_render {
+                settings.rng.set_gameplay_locked(true);
+                let rendered = logic.render_headless(self.now - self.last_render, renderer, settings);
+                settings.rng.set_gameplay_locked(false);
+                rendered?;
+                self.last_render = self.now;
+                Scheduler::update_time(&mut self.next_render, self.now, settings.dt_f, 1);
+            }
+        }
+        Ok(true)
+    }
+
+    /// step until `ticks` updates ran or the logic closed; returns the number of ticks run
+    pub fn run_ticks(&mut self, ticks: u64) -> Result<u64> {
+        let mut n = 0;
+        while n < ticks && self.step()? {
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// init, step until closed or `max_ticks` is reached, then finalize like `Scheduler::run`
+    pub fn run(&mut self, max_ticks: u64) -> Result<()> {
+        let res = self.init().and_then(|_| self.run_ticks(max_ticks)).err();
+        self.game_logic.finalize(res)
+    }
+}
+
 
 
 pub struct StdGameClock {
@@ -308,6 +768,46 @@ impl Default for StdGameClock {
 
 
 
+/// virtual clock that only moves when advanced or slept on, so a run never waits on real time
+#[derive(Default)]
+pub struct ManualClock {
+    base: u64,
+    now: u64,
+}
+
+impl ManualClock {
+
+    pub fn new() -> Self {
+        ManualClock {
+            base: 0,
+            now: 0,
+        }
+    }
+
+    pub fn advance(&mut self, nanos: u64) {
+        self.now += nanos;
+    }
+}
+
+impl GameClock for ManualClock {
+
+    fn reset(&mut self) -> u64 {
+        let old = self.base;
+        self.base = self.now;
+        self.base - old
+    }
+
+    fn elapsed(&mut self) -> u64 {
+        self.now - self.base
+    }
+
+    fn sleep(&mut self, nanos: u64) {
+        self.advance(nanos);
+    }
+}
+
+
+
 pub struct EmptyGameLogic {
     
 }
@@ -336,9 +836,176 @@ impl Default for EmptyGameLogic {
 
 
 
+#[cfg(test)]
+mod tests {
+
+    use super::*;
 
+    use glium::glutin::{WindowEvent, WindowId};
 
+    #[derive(Default)]
+    struct Counter {
 
+        setup: bool,
 
+        updates: u64,
 
+        // value of `updates` when each event arrived, i.e. the tick it was delivered on
+        events: Vec<u64>,
 
+    }
+
+    impl GameLogic for Counter {
+
+        fn setup(&mut self, _settings: &mut SchedulerSettings) -> Result<()> {
+            self.setup = true;
+            Ok(())
+        }
+
+        fn update(&mut self, _dt: u64, _settings: &mut SchedulerSettings) -> Result<()> {
+            self.updates += 1;
+            Ok(())
+        }
+
+        fn handle_event(&mut self, event: Event, _settings: &mut SchedulerSettings, close: &mut bool) -> Result<()> {
+            *close = is_close_requested(&event);
+            self.events.push(self.updates);
+            Ok(())
+        }
+    }
+
+    fn close_requested() -> Event {
+        Event::WindowEvent { window_id: unsafe { WindowId::dummy() }, event: WindowEvent::CloseRequested }
+    }
+
+    #[test]
+    fn headless_delivers_events_on_their_tick() {
+        let mut script = EventRecord::new();
+        script.push(0, Event::Awakened);
+        script.push(3, Event::Awakened);
+        script.push(3, Event::Awakened);
+        script.push(7, Event::Awakened);
+        let mut logic = Counter::default();
+        let mut scheduler = HeadlessScheduler::new(SchedulerSettings::default(), &mut logic, ManualClock::new(), script);
+        scheduler.init().unwrap();
+        assert_eq!(scheduler.run_ticks(1000).unwrap(), 1000);
+        assert_eq!(scheduler.get_tick(), 1000);
+        drop(scheduler);
+        assert!(logic.setup);
+        assert_eq!(logic.updates, 1000);
+        assert_eq!(logic.events, vec![0, 3, 3, 7]);
+    }
+
+    #[test]
+    fn headless_stops_once_closed() {
+        let mut script = EventRecord::new();
+        script.push(2, Event::Awakened);
+        script.push(5, close_requested());
+        script.push(6, Event::Awakened);
+        let mut logic = Counter::default();
+        let mut scheduler = HeadlessScheduler::new(SchedulerSettings::default(), &mut logic, ManualClock::new(), script);
+        scheduler.init().unwrap();
+        assert_eq!(scheduler.run_ticks(100).unwrap(), 5);
+        assert!(scheduler.is_closed());
+        assert!(!scheduler.step().unwrap());
+        assert_eq!(scheduler.run_ticks(10).unwrap(), 0);
+        drop(scheduler);
+        // the close request is seen but the update of tick 5 never runs
+        assert_eq!(logic.updates, 5);
+        assert_eq!(logic.events, vec![2, 5]);
+    }
+
+    // folds every dt, event and gameplay draw into its state, so any divergence shows up
+    #[derive(Default)]
+    struct Mixer {
+
+        hash: u64,
+
+        position: f64,
+
+        speed: f64,
+
+    }
+
+    impl GameLogic for Mixer {
+
+        fn update(&mut self, dt: u64, settings: &mut SchedulerSettings) -> Result<()> {
+            let draw = settings.get_rng().gameplay().next_u64();
+            self.hash = (self.hash ^ draw ^ dt).wrapping_mul(0x100_0000_01b3);
+            self.position += self.speed * dt as f64 * 1e-9 + (draw >> 40) as f64 * 1e-7;
+            Ok(())
+        }
+
+        fn handle_event(&mut self, event: Event, _settings: &mut SchedulerSettings, _close: &mut bool) -> Result<()> {
+            match event {
+                Event::Awakened => self.speed += 0.75,
+                Event::Suspended(_) => self.speed *= -0.5,
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+
+    fn run_mixer(source: EventRecord, ticks: u64) -> (Mixer, EventRecord) {
+        let mut logic = Mixer::default();
+        let mut settings = SchedulerSettings::default();
+        settings.set_ups(50).set_seed(42);
+        let mut scheduler = HeadlessScheduler::new(settings, &mut logic, ManualClock::new(), source);
+        scheduler.start_recording();
+        scheduler.init().unwrap();
+        scheduler.run_ticks(ticks).unwrap();
+        let record = scheduler.take_record().unwrap();
+        drop(scheduler);
+        (logic, record)
+    }
+
+    #[test]
+    fn recorded_input_replays_bit_for_bit() {
+        let mut script = EventRecord::new();
+        script.push(4, Event::Awakened);
+        script.push(90, Event::Suspended(true));
+        script.push(90, Event::Awakened);
+        script.push(333, Event::Suspended(false));
+        let (live, record) = run_mixer(script, 600);
+        assert_eq!(record.events().iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![4, 90, 90, 333]);
+
+        let (replayed, again) = run_mixer(record.clone(), 600);
+        assert_eq!(replayed.hash, live.hash);
+        assert_eq!(replayed.position.to_bits(), live.position.to_bits());
+        assert_eq!(again.events(), record.events());
+
+        // the events really matter: without them the run diverges
+        let (silent, _) = run_mixer(EventRecord::new(), 600);
+        assert_ne!(silent.position.to_bits(), live.position.to_bits());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn offscreen_needs_no_display_server() {
+        // without libOSMesa this is an error, never a panic
+        let scheduler = HeadlessScheduler::new(SchedulerSettings::default(), Counter::default(), ManualClock::new(), EventRecord::new());
+        let scheduler = match scheduler.with_offscreen(ContextBuilder::new(), 64, 64) {
+            Ok(scheduler) => scheduler,
+            Err(e) => {
+                eprintln!("skipped: no OSMesa context ({})", e);
+                return;
+            }
+        };
+        assert_eq!(scheduler.get_renderer().map(|r| r.get_framebuffer_dimensions()), Some((64, 64)));
+    }
+
+    #[test]
+    fn manual_clock_moves_only_when_told() {
+        let mut clock = ManualClock::new();
+        assert_eq!(clock.reset(), 0);
+        assert_eq!(clock.elapsed(), 0);
+        clock.advance(5);
+        clock.sleep(3);
+        assert_eq!(clock.elapsed(), 8);
+        assert_eq!(clock.elapsed(), 8);
+        assert_eq!(clock.reset(), 8);
+        assert_eq!(clock.elapsed(), 0);
+        clock.sleep(NANOS_PER_SEC);
+        assert_eq!(clock.elapsed(), NANOS_PER_SEC);
+    }
+}
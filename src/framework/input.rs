@@ -0,0 +1,572 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use glium::glutin::{Event, WindowEvent, ElementState, VirtualKeyCode};
+use serde::{Serialize, Deserialize};
+
+use super::util::Resource;
+use super::replay::{Replay, ReplayPlayer, ReplayError};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// held state of every action fits in one `u64`, see `Input::bits`
+pub const MAX_ACTIONS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GamepadButton {
+    South,
+    East,
+    West,
+    North,
+    LeftShoulder,
+    RightShoulder,
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+    LeftStick,
+    RightStick,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GamepadAxis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+}
+
+/// one physical input that can drive an action
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Binding {
+    Key(VirtualKeyCode),
+    Button(GamepadButton),
+    /// axis pushed past the dead zone, towards positive or negative values
+    Axis { axis: GamepadAxis, positive: bool },
+}
+
+#[derive(Debug)]
+pub enum InputError {
+    Json(serde_json::Error),
+    TooManyActions,
+    DuplicateAction(String),
+}
+
+impl fmt::Display for InputError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputError::Json(e) => write!(f, "input config: {}", e),
+            InputError::TooManyActions => write!(f, "more than {} input actions", MAX_ACTIONS),
+            InputError::DuplicateAction(name) => write!(f, "input action {} defined twice", name),
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ActionId(usize);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ActionBindings {
+
+    name: String,
+
+    #[serde(default)]
+    bindings: Vec<Binding>,
+
+}
+
+/// named actions and their bindings; action order is kept so ids stay stable across loads
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputMap {
+
+    #[serde(default = "default_dead_zone")]
+    dead_zone: f32,
+
+    actions: Vec<ActionBindings>,
+
+}
+
+fn default_dead_zone() -> f32 {
+    0.5
+}
+
+impl Default for InputMap {
+
+    /// arrows to move, z / x to shoot and bomb, shift to focus
+    fn default() -> Self {
+        let mut map = InputMap::new();
+        let defaults = [
+            ("up", VirtualKeyCode::Up, Binding::Axis { axis: GamepadAxis::LeftY, positive: true }),
+            ("down", VirtualKeyCode::Down, Binding::Axis { axis: GamepadAxis::LeftY, positive: false }),
+            ("left", VirtualKeyCode::Left, Binding::Axis { axis: GamepadAxis::LeftX, positive: false }),
+            ("right", VirtualKeyCode::Right, Binding::Axis { axis: GamepadAxis::LeftX, positive: true }),
+            ("shoot", VirtualKeyCode::Z, Binding::Button(GamepadButton::South)),
+            ("bomb", VirtualKeyCode::X, Binding::Button(GamepadButton::East)),
+            ("focus", VirtualKeyCode::LShift, Binding::Button(GamepadButton::RightShoulder)),
+            ("pause", VirtualKeyCode::Escape, Binding::Button(GamepadButton::Start)),
+        ];
+        for (name, key, pad) in defaults.iter() {
+            let id = map.add_action(name).unwrap();
+            map.bind(id, Binding::Key(*key));
+            map.bind(id, *pad);
+        }
+        map
+    }
+}
+
+impl InputMap {
+
+    pub fn new() -> Self {
+        InputMap {
+            dead_zone: default_dead_zone(),
+            actions: Vec::new(),
+        }
+    }
+
+    pub fn parse(src: &str) -> std::result::Result<Self, InputError> {
+        let map: InputMap = serde_json::from_str(src).map_err(InputError::Json)?;
+        if map.actions.len() > MAX_ACTIONS {
+            return Err(InputError::TooManyActions);
+        }
+        let mut i = 1;
+        while i < map.actions.len() {
+            let name = &map.actions[i].name;
+            if map.actions[..i].iter().any(|a| &a.name == name) {
+                return Err(InputError::DuplicateAction(name.clone()));
+            }
+            i += 1;
+        }
+        Ok(map)
+    }
+
+    pub fn load(resource: &Resource, file: &str) -> Result<Self> {
+        let src = resource.load_as_string(file).map_err(Box::new)?;
+        let map = Self::parse(&src).map_err(Box::new)?;
+        Ok(map)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn save(&self, resource: &Resource, file: &str) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        let mut ofile = resource.open(file, options).map_err(Box::new)?;
+        ofile.write_all(self.to_json().as_bytes()).map_err(Box::new)?;
+        Ok(())
+    }
+
+    /// returns the existing id if `name` is already an action
+    pub fn add_action(&mut self, name: &str) -> std::result::Result<ActionId, InputError> {
+        if let Some(id) = self.action(name) {
+            return Ok(id);
+        }
+        if self.actions.len() >= MAX_ACTIONS {
+            return Err(InputError::TooManyActions);
+        }
+        self.actions.push(ActionBindings { name: name.to_string(), bindings: Vec::new() });
+        Ok(ActionId(self.actions.len() - 1))
+    }
+
+    pub fn action(&self, name: &str) -> Option<ActionId> {
+        self.actions.iter().position(|a| a.name == name).map(ActionId)
+    }
+
+    pub fn name(&self, action: ActionId) -> &str {
+        &self.actions[action.0].name
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    pub fn set_dead_zone(&mut self, dead_zone: f32) -> &mut Self {
+        self.dead_zone = dead_zone;
+        self
+    }
+
+    pub fn bind(&mut self, action: ActionId, binding: Binding) -> &mut Self {
+        let bindings = &mut self.actions[action.0].bindings;
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+        self
+    }
+
+    /// remove `binding` from every action
+    pub fn unbind(&mut self, binding: &Binding) -> &mut Self {
+        for a in self.actions.iter_mut() {
+            a.bindings.retain(|b| b != binding);
+        }
+        self
+    }
+
+    pub fn clear(&mut self, action: ActionId) -> &mut Self {
+        self.actions[action.0].bindings.clear();
+        self
+    }
+
+    pub fn bindings(&self, action: ActionId) -> &[Binding] {
+        &self.actions[action.0].bindings
+    }
+}
+
+/// gamepad readings, filled in by a `GamepadBackend` once per tick
+#[derive(Debug, Clone, Default)]
+pub struct GamepadState {
+
+    buttons: HashSet<GamepadButton>,
+
+    axes: HashMap<GamepadAxis, f32>,
+
+}
+
+impl GamepadState {
+
+    pub fn set_button(&mut self, button: GamepadButton, down: bool) {
+        if down {
+            self.buttons.insert(button);
+        } else {
+            self.buttons.remove(&button);
+        }
+    }
+
+    /// `value` in [-1, 1]
+    pub fn set_axis(&mut self, axis: GamepadAxis, value: f32) {
+        self.axes.insert(axis, value);
+    }
+
+    pub fn button(&self, button: GamepadButton) -> bool {
+        self.buttons.contains(&button)
+    }
+
+    pub fn axis(&self, axis: GamepadAxis) -> f32 {
+        self.axes.get(&axis).cloned().unwrap_or(0.0)
+    }
+}
+
+/// source of gamepad input; glutin has none, so platforms plug their own in
+pub trait GamepadBackend {
+
+    fn poll(&mut self, state: &mut GamepadState);
+}
+
+pub struct NoGamepad;
+
+impl GamepadBackend for NoGamepad {
+
+    fn poll(&mut self, _state: &mut GamepadState) {}
+}
+
+/// action state sampled once per update tick from keyboard events and the gamepad backend
+pub struct Input {
+
+    map: InputMap,
+
+    keys: HashSet<VirtualKeyCode>,
+
+    // keys that went down since the last sample, so a tap inside one tick is not lost
+    tapped: HashSet<VirtualKeyCode>,
+
+    gamepad: Box<dyn GamepadBackend>,
+
+    pad: GamepadState,
+
+    held: u64,
+
+    prev: u64,
+
+    capture: Option<ActionId>,
+
+    recording: Option<Replay>,
+
+    playback: Option<ReplayPlayer>,
+
+}
+
+impl Input {
+
+    pub fn new(map: InputMap) -> Self {
+        Input {
+            map,
+            keys: HashSet::new(),
+            tapped: HashSet::new(),
+            gamepad: Box::new(NoGamepad),
+            pad: GamepadState::default(),
+            held: 0,
+            prev: 0,
+            capture: None,
+            recording: None,
+            playback: None,
+        }
+    }
+
+    pub fn set_gamepad<B: GamepadBackend + 'static>(&mut self, backend: B) -> &mut Self {
+        self.gamepad = Box::new(backend);
+        self
+    }
+
+    pub fn map(&self) -> &InputMap {
+        &self.map
+    }
+
+    pub fn map_mut(&mut self) -> &mut InputMap {
+        &mut self.map
+    }
+
+    pub fn action(&self, name: &str) -> Option<ActionId> {
+        self.map.action(name)
+    }
+
+    /// feed every window event through here from `GameLogic::handle_event`
+    pub fn handle_event(&mut self, event: &Event) {
+        if let Event::WindowEvent { event, .. } = event {
+            match event {
+                WindowEvent::KeyboardInput { input, .. } => {
+                    if let Some(key) = input.virtual_keycode {
+                        match input.state {
+                            ElementState::Pressed => {
+                                if self.keys.insert(key) {
+                                    self.tapped.insert(key);
+                                    self.captured(Binding::Key(key));
+                                }
+                            }
+                            ElementState::Released => {
+                                self.keys.remove(&key);
+                            }
+                        }
+                    }
+                }
+                WindowEvent::Focused(false) => {
+                    self.keys.clear();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// latch the state seen by this tick; call once at the start of `GameLogic::update`
+    pub fn sample(&mut self) {
+        if let Some(player) = &mut self.playback {
+            // live devices are ignored while a replay drives the actions
+            let bits = player.next_bits().unwrap_or(0);
+            self.tapped.clear();
+            self.set_bits(bits);
+        } else {
+            self.sample_live();
+        }
+        if let Some(replay) = &mut self.recording {
+            replay.push(self.held);
+        }
+    }
+
+    /// record the bits of every following `sample`, tagged with the run's rng seed and stage
+    pub fn start_recording(&mut self, seed: u64, stage: &str) {
+        self.recording = Some(Replay::new(seed, stage, self.map.len()));
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn take_recording(&mut self) -> Option<Replay> {
+        self.recording.take()
+    }
+
+    /// drive actions from `replay` instead of the keyboard and gamepad
+    pub fn set_playback(&mut self, replay: Replay) -> std::result::Result<(), ReplayError> {
+        self.playback = Some(ReplayPlayer::new(replay, self.map.len())?);
+        Ok(())
+    }
+
+    pub fn stop_playback(&mut self) -> Option<ReplayPlayer> {
+        self.playback.take()
+    }
+
+    pub fn is_playing_back(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    /// playing back and past the last recorded tick
+    pub fn is_playback_finished(&self) -> bool {
+        self.playback.as_ref().is_some_and(|p| p.is_finished())
+    }
+
+    fn sample_live(&mut self) {
+        let before = self.pad.clone();
+        self.gamepad.poll(&mut self.pad);
+        if self.capture.is_some() {
+            let pressed = self.pad.buttons.iter().find(|b| !before.button(**b)).cloned();
+            if let Some(button) = pressed {
+                self.captured(Binding::Button(button));
+            }
+        }
+
+        let mut held = 0;
+        for (i, a) in self.map.actions.iter().enumerate() {
+            if a.bindings.iter().any(|b| self.is_down(b)) {
+                held |= 1 << i;
+            }
+        }
+        self.tapped.clear();
+        self.set_bits(held);
+    }
+
+    /// held actions, bit `i` for action id `i`; this is all a replay needs to store per tick
+    pub fn bits(&self) -> u64 {
+        self.held
+    }
+
+    /// override the sampled state, e.g. with bits read back from a replay
+    pub fn set_bits(&mut self, bits: u64) {
+        self.prev = self.held;
+        self.held = bits;
+    }
+
+    pub fn is_held(&self, action: ActionId) -> bool {
+        self.held & (1 << action.0) != 0
+    }
+
+    /// went down this tick
+    pub fn is_pressed(&self, action: ActionId) -> bool {
+        self.is_held(action) && self.prev & (1 << action.0) == 0
+    }
+
+    /// went up this tick
+    pub fn is_released(&self, action: ActionId) -> bool {
+        !self.is_held(action) && self.prev & (1 << action.0) != 0
+    }
+
+    /// `is_held` by name; false for unknown actions
+    pub fn held(&self, name: &str) -> bool {
+        self.action(name).is_some_and(|a| self.is_held(a))
+    }
+
+    pub fn pressed(&self, name: &str) -> bool {
+        self.action(name).is_some_and(|a| self.is_pressed(a))
+    }
+
+    pub fn released(&self, name: &str) -> bool {
+        self.action(name).is_some_and(|a| self.is_released(a))
+    }
+
+    /// the next key or gamepad button pressed replaces the bindings of `action` for that device
+    pub fn capture(&mut self, action: ActionId) {
+        self.capture = Some(action);
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    fn captured(&mut self, binding: Binding) {
+        let action = match self.capture.take() {
+            Some(action) => action,
+            None => return,
+        };
+        let keyboard = matches!(binding, Binding::Key(_));
+        self.map.unbind(&binding);
+        self.map.actions[action.0].bindings.retain(|b| matches!(b, Binding::Key(_)) != keyboard);
+        self.map.bind(action, binding);
+    }
+
+    fn is_down(&self, binding: &Binding) -> bool {
+        match binding {
+            Binding::Key(key) => self.keys.contains(key) || self.tapped.contains(key),
+            Binding::Button(button) => self.pad.button(*button),
+            Binding::Axis { axis, positive } => {
+                let v = self.pad.axis(*axis);
+                if *positive { v >= self.map.dead_zone } else { v <= -self.map.dead_zone }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn key(key: VirtualKeyCode, state: ElementState) -> Event {
+        let input = glium::glutin::KeyboardInput { scancode: 0, state, virtual_keycode: Some(key), modifiers: Default::default() };
+        Event::WindowEvent {
+            window_id: unsafe { glium::glutin::WindowId::dummy() },
+            event: WindowEvent::KeyboardInput { device_id: unsafe { glium::glutin::DeviceId::dummy() }, input },
+        }
+    }
+
+    #[test]
+    fn tap_inside_one_tick_is_seen() {
+        let mut input = Input::new(InputMap::default());
+        let shoot = input.action("shoot").unwrap();
+        input.handle_event(&key(VirtualKeyCode::Z, ElementState::Pressed));
+        input.handle_event(&key(VirtualKeyCode::Z, ElementState::Released));
+        input.sample();
+        assert!(input.is_pressed(shoot));
+        input.sample();
+        assert!(input.is_released(shoot));
+        input.sample();
+        assert!(!input.is_held(shoot) && !input.is_released(shoot));
+    }
+
+    #[test]
+    fn config_round_trips() {
+        let map = InputMap::default();
+        let copy = InputMap::parse(&map.to_json()).unwrap();
+        assert_eq!(copy.len(), map.len());
+        let bomb = copy.action("bomb").unwrap();
+        assert_eq!(copy.bindings(bomb), map.bindings(map.action("bomb").unwrap()));
+        assert!(InputMap::parse(r#"{"actions":[{"name":"a"},{"name":"a"}]}"#).is_err());
+    }
+
+    #[test]
+    fn capture_rebinds_keyboard_only() {
+        let mut input = Input::new(InputMap::default());
+        let bomb = input.action("bomb").unwrap();
+        input.capture(bomb);
+        input.handle_event(&key(VirtualKeyCode::C, ElementState::Pressed));
+        assert!(!input.is_capturing());
+        assert_eq!(input.map().bindings(bomb), &[Binding::Button(GamepadButton::East), Binding::Key(VirtualKeyCode::C)]);
+    }
+
+    #[test]
+    fn recording_plays_back() {
+        let mut input = Input::new(InputMap::default());
+        let shoot = input.action("shoot").unwrap();
+        input.start_recording(7, "stage1");
+        input.handle_event(&key(VirtualKeyCode::Z, ElementState::Pressed));
+        input.sample();
+        input.sample();
+        input.handle_event(&key(VirtualKeyCode::Z, ElementState::Released));
+        input.sample();
+        let replay = input.take_recording().unwrap();
+        assert_eq!(replay.len(), 3);
+
+        let mut input = Input::new(InputMap::default());
+        input.set_playback(replay).unwrap();
+        // live keys are ignored during playback
+        input.handle_event(&key(VirtualKeyCode::X, ElementState::Pressed));
+        input.sample();
+        assert!(input.is_pressed(shoot) && !input.held("bomb"));
+        input.sample();
+        input.sample();
+        assert!(input.is_released(shoot));
+        assert!(input.is_playback_finished());
+    }
+}
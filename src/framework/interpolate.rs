@@ -0,0 +1,483 @@
+use num_traits::float::Float;
+use serde::Deserialize;
+
+use super::spline::{CubeSpline, CubeSplineError, validate};
+
+/// a curve through `dim`-dimensional points, evaluated by a scalar parameter
+pub trait Interpolator<T> {
+
+    fn dim(&self) -> usize;
+
+    /// parameter of every point the curve passes through, in increasing order
+    fn knots(&self) -> &[T];
+
+    /// first and last knot, `EvalUncompiled` while there are none
+    fn domain(&self) -> Result<(T, T), CubeSplineError>;
+
+    fn get(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError>;
+
+    fn get_derivative(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError>;
+
+    fn get_derivative2(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError>;
+
+}
+
+impl<T: Float> Interpolator<T> for CubeSpline<T> {
+
+    fn dim(&self) -> usize {
+        CubeSpline::dim(self)
+    }
+
+    fn knots(&self) -> &[T] {
+        CubeSpline::knots(self)
+    }
+
+    fn domain(&self) -> Result<(T, T), CubeSplineError> {
+        span(self.knots())
+    }
+
+    fn get(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        CubeSpline::get(self, x, y)
+    }
+
+    fn get_derivative(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        CubeSpline::get_derivative(self, x, y)
+    }
+
+    fn get_derivative2(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        CubeSpline::get_derivative2(self, x, y)
+    }
+}
+
+fn span<T: Copy>(knots: &[T]) -> Result<(T, T), CubeSplineError> {
+    match (knots.first(), knots.last()) {
+        (Some(a), Some(b)) => Ok((*a, *b)),
+        _ => Err(CubeSplineError::EvalUncompiled),
+    }
+}
+
+/// point-only curve families a path can be authored with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CurveKind {
+    #[default]
+    Natural,
+    CatmullRom,
+    Centripetal,
+    Chordal,
+    Monotone,
+}
+
+/// build the curve of the given kind through `y_list` (n x d) at knots `x_list`
+pub fn from_points<T: Float + Send + Sync + 'static>(kind: CurveKind, x_list: Vec<T>, y_list: Vec<T>) -> Result<Box<dyn Interpolator<T> + Send + Sync>, CubeSplineError> {
+    let curve: Box<dyn Interpolator<T> + Send + Sync> = match kind {
+        CurveKind::Natural => Box::new(CubeSpline::new().compile(x_list, y_list)?),
+        CurveKind::CatmullRom => Box::new(Hermite::catmull_rom(x_list, y_list, T::zero())?),
+        CurveKind::Centripetal => Box::new(Hermite::catmull_rom(x_list, y_list, T::from(0.5).unwrap())?),
+        CurveKind::Chordal => Box::new(Hermite::catmull_rom(x_list, y_list, T::one())?),
+        CurveKind::Monotone => Box::new(Hermite::monotone(x_list, y_list)?),
+    };
+    Ok(curve)
+}
+
+
+
+///
+/// piecewise cubic hermite curve; every segment keeps its own start and end tangent (dy/dx),
+/// so corners are allowed where neighbouring tangents differ
+pub struct Hermite<T> {
+
+    x_list: Vec<T>,
+
+    y_list: Vec<T>,
+
+    m_start: Vec<T>,
+
+    m_end: Vec<T>,
+
+    dim: usize,
+
+}
+
+impl<T: Float> Hermite<T> {
+
+    ///
+    /// x_list: vec n x 1   [x1, x2, ...]
+    ///
+    /// y_list, tangents: vec n * d
+    pub fn new(x_list: Vec<T>, y_list: Vec<T>, tangents: Vec<T>) -> Result<Self, CubeSplineError> {
+        let dim = validate(&x_list, &y_list, 2)?;
+        if tangents.len() != y_list.len() {
+            return Err(CubeSplineError::GenDimensionMismatch { knots: x_list.len(), values: tangents.len() });
+        }
+        if let Some(index) = tangents.iter().position(|m| !m.is_finite()) {
+            return Err(CubeSplineError::GenValueNotFinite { index });
+        }
+        let n = x_list.len() - 1;
+        let m_start = tangents[..n * dim].to_vec();
+        let m_end = tangents[dim..].to_vec();
+        Ok(Hermite { x_list, y_list, m_start, m_end, dim })
+    }
+
+    ///
+    /// chain of cubic bezier segments, one per knot interval
+    ///
+    /// control: vec (3n + 1) * d   [p0, c0a, c0b, p1, c1a, c1b, p2, ...]
+    pub fn bezier(x_list: Vec<T>, control: Vec<T>) -> Result<Self, CubeSplineError> {
+        if x_list.len() < 2 {
+            return Err(CubeSplineError::GenTooFewPoints { required: 2, found: x_list.len() });
+        }
+        let n = x_list.len() - 1;
+        let dim = control.len() / (3 * n + 1);
+        if dim == 0 || control.len() != (3 * n + 1) * dim {
+            return Err(CubeSplineError::GenDimensionMismatch { knots: 3 * n + 1, values: control.len() });
+        }
+        if let Some(index) = control.iter().position(|c| !c.is_finite()) {
+            return Err(CubeSplineError::GenValueNotFinite { index });
+        }
+        let _3 = T::one() + T::one() + T::one();
+        let mut y_list = Vec::with_capacity((n + 1) * dim);
+        let mut m_start = Vec::with_capacity(n * dim);
+        let mut m_end = Vec::with_capacity(n * dim);
+        let mut i = 0;
+        while i < n {
+            let h = x_list[i + 1] - x_list[i];
+            let p = |k: usize, j: usize| control[(3 * i + k) * dim + j];
+            let mut j = 0;
+            while j < dim {
+                y_list.push(p(0, j));
+                m_start.push(_3 * (p(1, j) - p(0, j)) / h);
+                m_end.push(_3 * (p(3, j) - p(2, j)) / h);
+                j += 1;
+            }
+            i += 1;
+        }
+        y_list.extend_from_slice(&control[3 * n * dim..]);
+        validate(&x_list, &y_list, 2)?;
+        Ok(Hermite { x_list, y_list, m_start, m_end, dim })
+    }
+
+    ///
+    /// catmull-rom through the points; `alpha` picks the knot spacing used for the tangents:
+    /// 0 uniform, 0.5 centripetal, 1 chordal
+    pub fn catmull_rom(x_list: Vec<T>, y_list: Vec<T>, alpha: T) -> Result<Self, CubeSplineError> {
+        let dim = validate(&x_list, &y_list, 2)?;
+        let n = x_list.len() - 1;
+        let _2 = T::one() + T::one();
+
+        // points with a mirrored phantom at each end
+        let mut p = Vec::with_capacity((n + 3) * dim);
+        let mut j = 0;
+        while j < dim {
+            p.push(_2 * y_list[j] - y_list[dim + j]);
+            j += 1;
+        }
+        p.extend_from_slice(&y_list);
+        j = 0;
+        while j < dim {
+            p.push(_2 * y_list[n * dim + j] - y_list[(n - 1) * dim + j]);
+            j += 1;
+        }
+        let point = |k: usize| &p[k * dim..(k + 1) * dim];
+
+        // knot spacing |p[k+1] - p[k]| ^ alpha
+        let mut dt = Vec::with_capacity(n + 2);
+        let mut k = 0;
+        while k < n + 2 {
+            let (a, b) = (point(k), point(k + 1));
+            let dist = a.iter().zip(b.iter()).fold(T::zero(), |acc, (u, v)| acc + (*v - *u) * (*v - *u)).sqrt();
+            let d = dist.powf(alpha);
+            dt.push(if d > T::epsilon() { d } else { T::one() });
+            k += 1;
+        }
+
+        // tangent d/dt at every real point
+        let mut tangent = vec![T::zero(); (n + 1) * dim];
+        let mut i = 0;
+        while i <= n {
+            let (p0, p1, p2) = (point(i), point(i + 1), point(i + 2));
+            let (d0, d1) = (dt[i], dt[i + 1]);
+            j = 0;
+            while j < dim {
+                tangent[i * dim + j] = (p1[j] - p0[j]) / d0 - (p2[j] - p0[j]) / (d0 + d1) + (p2[j] - p1[j]) / d1;
+                j += 1;
+            }
+            i += 1;
+        }
+
+        let mut m_start = Vec::with_capacity(n * dim);
+        let mut m_end = Vec::with_capacity(n * dim);
+        i = 0;
+        while i < n {
+            let scale = dt[i + 1] / (x_list[i + 1] - x_list[i]);
+            j = 0;
+            while j < dim {
+                m_start.push(tangent[i * dim + j] * scale);
+                m_end.push(tangent[(i + 1) * dim + j] * scale);
+                j += 1;
+            }
+            i += 1;
+        }
+        Ok(Hermite { x_list, y_list, m_start, m_end, dim })
+    }
+
+    /// fritsch-carlson monotone cubic: never overshoots between knots, for every dimension separately
+    pub fn monotone(x_list: Vec<T>, y_list: Vec<T>) -> Result<Self, CubeSplineError> {
+        let dim = validate(&x_list, &y_list, 2)?;
+        let n = x_list.len() - 1;
+        let _2 = T::one() + T::one();
+        let _9 = (_2 + T::one()) * (_2 + T::one());
+        let mut tangents = vec![T::zero(); (n + 1) * dim];
+        let mut delta = vec![T::zero(); n];
+        let mut j = 0;
+        while j < dim {
+            let mut i = 0;
+            while i < n {
+                delta[i] = (y_list[(i + 1) * dim + j] - y_list[i * dim + j]) / (x_list[i + 1] - x_list[i]);
+                i += 1;
+            }
+            let m = |i: usize| i * dim + j;
+            tangents[m(0)] = delta[0];
+            tangents[m(n)] = delta[n - 1];
+            i = 1;
+            while i < n {
+                tangents[m(i)] = if delta[i - 1] * delta[i] <= T::zero() {
+                    T::zero()
+                } else {
+                    (delta[i - 1] + delta[i]) / _2
+                };
+                i += 1;
+            }
+            i = 0;
+            while i < n {
+                if delta[i] == T::zero() {
+                    tangents[m(i)] = T::zero();
+                    tangents[m(i + 1)] = T::zero();
+                } else {
+                    let a = tangents[m(i)] / delta[i];
+                    let b = tangents[m(i + 1)] / delta[i];
+                    let r = a * a + b * b;
+                    if r > _9 {
+                        let tau = (_2 + T::one()) / r.sqrt();
+                        tangents[m(i)] = tau * a * delta[i];
+                        tangents[m(i + 1)] = tau * b * delta[i];
+                    }
+                }
+                i += 1;
+            }
+            j += 1;
+        }
+        Self::new(x_list, y_list, tangents)
+    }
+
+    // segment index and local (u, h) for x, clamped to the first / last segment
+    fn locate(&self, x: T) -> Result<(usize, T, T), CubeSplineError> {
+        if !x.is_finite() {
+            return Err(CubeSplineError::EvalNotFinite);
+        }
+        let i = find_segment(&self.x_list, x);
+        let h = self.x_list[i + 1] - self.x_list[i];
+        Ok((i, (x - self.x_list[i]) / h, h))
+    }
+}
+
+// index of the segment containing x, clamped to [0, n-1]
+fn find_segment<T: Float>(x_list: &[T], x: T) -> usize {
+    let n = x_list.len() - 1;
+    if x < x_list[1] {
+        return 0;
+    }
+    if x >= x_list[n - 1] {
+        return n - 1;
+    }
+    let mut low = 1;
+    let mut high = n - 1;
+    while high - low > 1 {
+        let mid = (low + high) / 2;
+        if x_list[mid] > x {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+    low
+}
+
+impl<T: Float> Interpolator<T> for Hermite<T> {
+
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn knots(&self) -> &[T] {
+        self.x_list.as_slice()
+    }
+
+    fn domain(&self) -> Result<(T, T), CubeSplineError> {
+        span(&self.x_list)
+    }
+
+    fn get(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        let (i, u, h) = self.locate(x)?;
+        let _2 = T::one() + T::one();
+        let _3 = _2 + T::one();
+        let u2 = u * u;
+        let u3 = u2 * u;
+        let h00 = _2 * u3 - _3 * u2 + T::one();
+        let h10 = u3 - _2 * u2 + u;
+        let h01 = _3 * u2 - _2 * u3;
+        let h11 = u3 - u2;
+        let dim = std::cmp::min(self.dim, y.len());
+        let mut j = 0;
+        while j < dim {
+            let y0 = self.y_list[i * self.dim + j];
+            let y1 = self.y_list[(i + 1) * self.dim + j];
+            let m0 = self.m_start[i * self.dim + j];
+            let m1 = self.m_end[i * self.dim + j];
+            y[j] = h00 * y0 + h10 * h * m0 + h01 * y1 + h11 * h * m1;
+            j += 1;
+        }
+        Ok(dim)
+    }
+
+    fn get_derivative(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        let (i, u, h) = self.locate(x)?;
+        let _2 = T::one() + T::one();
+        let _3 = _2 + T::one();
+        let _4 = _2 + _2;
+        let _6 = _3 + _3;
+        let u2 = u * u;
+        let d00 = _6 * u2 - _6 * u;
+        let d10 = _3 * u2 - _4 * u + T::one();
+        let d11 = _3 * u2 - _2 * u;
+        let dim = std::cmp::min(self.dim, y.len());
+        let mut j = 0;
+        while j < dim {
+            let y0 = self.y_list[i * self.dim + j];
+            let y1 = self.y_list[(i + 1) * self.dim + j];
+            let m0 = self.m_start[i * self.dim + j];
+            let m1 = self.m_end[i * self.dim + j];
+            y[j] = d00 * (y0 - y1) / h + d10 * m0 + d11 * m1;
+            j += 1;
+        }
+        Ok(dim)
+    }
+
+    fn get_derivative2(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        let (i, u, h) = self.locate(x)?;
+        let _2 = T::one() + T::one();
+        let _4 = _2 + _2;
+        let _6 = _4 + _2;
+        let _12 = _6 + _6;
+        let d00 = _12 * u - _6;
+        let d10 = _6 * u - _4;
+        let d11 = _6 * u - _2;
+        let dim = std::cmp::min(self.dim, y.len());
+        let mut j = 0;
+        while j < dim {
+            let y0 = self.y_list[i * self.dim + j];
+            let y1 = self.y_list[(i + 1) * self.dim + j];
+            let m0 = self.m_start[i * self.dim + j];
+            let m1 = self.m_end[i * self.dim + j];
+            y[j] = (d00 * (y0 - y1) / h + d10 * m0 + d11 * m1) / h;
+            j += 1;
+        }
+        Ok(dim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const EPS: f64 = 1e-9;
+
+    fn at(curve: &dyn Interpolator<f64>, x: f64) -> Vec<f64> {
+        let mut y = vec![0.0; curve.dim()];
+        curve.get(x, &mut y).unwrap();
+        y
+    }
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        for (u, v) in a.iter().zip(b.iter()) {
+            assert!((u - v).abs() < EPS, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn catmull_rom_passes_through_its_knots() {
+        let x_list = vec![0.0, 0.5, 2.0, 2.5, 4.0];
+        let y_list = vec![0.0, 0.0, 1.0, 3.0, 4.0, 3.0, 4.2, 3.1, 9.0, -2.0];
+        for kind in [CurveKind::CatmullRom, CurveKind::Centripetal, CurveKind::Chordal].iter() {
+            let curve = from_points(*kind, x_list.clone(), y_list.clone()).unwrap();
+            for (i, x) in x_list.iter().enumerate() {
+                assert_close(&at(curve.as_ref(), *x), &y_list[i * 2..i * 2 + 2]);
+            }
+        }
+    }
+
+    #[test]
+    fn bezier_hits_its_anchors() {
+        // two segments in 1d: anchors 0, 4, 1 with their control points
+        let control = vec![0.0, 1.0, 3.0, 4.0, 5.0, 2.0, 1.0];
+        let curve = Hermite::bezier(vec![0.0, 1.0, 3.0], control).unwrap();
+        assert_close(&at(&curve, 0.0), &[0.0]);
+        assert_close(&at(&curve, 1.0), &[4.0]);
+        assert_close(&at(&curve, 3.0), &[1.0]);
+        // de casteljau midpoint of the first segment
+        assert_close(&at(&curve, 0.5), &[(0.0 + 3.0 * 1.0 + 3.0 * 3.0 + 4.0) / 8.0]);
+        // tangent 3 (c - p) / h at both ends of the second segment
+        let mut d = [0.0];
+        curve.get_derivative(1.0, &mut d).unwrap();
+        assert_close(&d, &[3.0 * (5.0 - 4.0) / 2.0]);
+        curve.get_derivative(3.0, &mut d).unwrap();
+        assert_close(&d, &[3.0 * (1.0 - 2.0) / 2.0]);
+    }
+
+    #[test]
+    fn hermite_keeps_given_tangents() {
+        // one 2d segment from (0, 0) to (4, 2), leaving along +x and arriving along (3, -1)
+        let curve = Hermite::new(vec![0.0, 2.0], vec![0.0, 0.0, 4.0, 2.0], vec![1.0, 0.0, 3.0, -1.0]).unwrap();
+        assert_close(&at(&curve, 0.0), &[0.0, 0.0]);
+        assert_close(&at(&curve, 2.0), &[4.0, 2.0]);
+        let mut d = [0.0; 2];
+        curve.get_derivative(0.0, &mut d).unwrap();
+        assert_close(&d, &[1.0, 0.0]);
+        curve.get_derivative(2.0, &mut d).unwrap();
+        assert_close(&d, &[3.0, -1.0]);
+
+        let short = Hermite::new(vec![0.0, 2.0], vec![0.0, 0.0, 4.0, 2.0], vec![1.0, 0.0]);
+        assert_eq!(short.err(), Some(CubeSplineError::GenDimensionMismatch { knots: 2, values: 2 }));
+    }
+
+    #[test]
+    fn domain_needs_knots() {
+        let curve = Hermite::bezier(vec![0.0, 1.0, 3.0], vec![0.0, 1.0, 3.0, 4.0, 5.0, 2.0, 1.0]).unwrap();
+        assert_eq!(curve.domain().unwrap(), (0.0, 3.0));
+        assert!(Interpolator::<f64>::domain(&CubeSpline::new()).is_err());
+    }
+
+    #[test]
+    fn monotone_never_overshoots_a_step() {
+        let x_list = vec![0.0, 1.0, 2.0, 2.1, 3.0, 4.0];
+        let y_list = vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+        let natural = from_points(CurveKind::Natural, x_list.clone(), y_list.clone()).unwrap();
+        let monotone = from_points(CurveKind::Monotone, x_list, y_list).unwrap();
+        let mut natural_out = false;
+        let mut last = 0.0;
+        let mut i = 0;
+        while i <= 400 {
+            let x = i as f64 * 0.01;
+            let y = at(monotone.as_ref(), x)[0];
+            assert!((0.0..=1.0).contains(&y), "{} at {}", y, x);
+            assert!(y >= last - EPS, "decreasing at {}", x);
+            last = y;
+            let n = at(natural.as_ref(), x)[0];
+            natural_out |= !(0.0..=1.0).contains(&n);
+            i += 1;
+        }
+        // the same data rings with a natural spline
+        assert!(natural_out);
+    }
+}
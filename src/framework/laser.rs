@@ -0,0 +1,409 @@
+use std::collections::VecDeque;
+use glium::{Surface, Program, DrawParameters};
+use glium::backend::Facade;
+use glium::index::PrimitiveType;
+use glium::texture::Texture2d;
+use glium::uniforms::MagnifySamplerFilter;
+
+use super::mesh::Mesh;
+use super::util::Resource;
+use super::spline::{CubeSpline, CubeSplineError};
+use super::collision::{Shape, CollisionWorld};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const NANOS_PER_SEC: f32 = 1_000_000_000.0;
+
+#[derive(Copy, Clone)]
+pub struct LaserVertex {
+    l_position: [f32; 2],
+    l_uv: [f32; 2],
+    l_alpha: f32,
+}
+
+implement_vertex!(LaserVertex, l_position, l_uv, l_alpha);
+
+/// look of a laser; widths in world units, `taper` is the fraction of the length narrowing at each end
+#[derive(Debug, Clone, Copy)]
+pub struct LaserStyle {
+
+    pub width: f32,
+
+    /// collision width, usually narrower than the drawn one
+    pub hit_width: f32,
+
+    pub taper: f32,
+
+    /// [u0, v0, u1, v1]; u runs along the laser, v across it
+    pub uv: [f32; 4],
+
+}
+
+impl Default for LaserStyle {
+
+    fn default() -> Self {
+        LaserStyle {
+            width: 1.0,
+            hit_width: 0.5,
+            taper: 0.1,
+            uv: [0.0, 0.0, 1.0, 1.0],
+        }
+    }
+}
+
+impl LaserStyle {
+
+    /// width multiplier at `u` in [0, 1] along the laser
+    pub fn falloff(&self, u: f32) -> f32 {
+        if self.taper <= 0.0 {
+            return 1.0;
+        }
+        let edge = u.min(1.0 - u).max(0.0);
+        (edge / self.taper).min(1.0)
+    }
+}
+
+// position of every point along the polyline as a fraction of its length, so bunched nodes do not stretch the texture
+fn along(points: &[[f32; 2]]) -> Vec<f32> {
+    let mut out = Vec::with_capacity(points.len());
+    let mut total = 0.0;
+    out.push(0.0);
+    let mut i = 1;
+    while i < points.len() {
+        let (a, b) = (points[i - 1], points[i]);
+        total += (b[0] - a[0]).hypot(b[1] - a[1]);
+        out.push(total);
+        i += 1;
+    }
+    if total > 0.0 {
+        for u in out.iter_mut() {
+            *u /= total;
+        }
+    } else {
+        // all nodes on one spot, fall back to the node index
+        let last = std::cmp::max(points.len(), 2) - 1;
+        for (i, u) in out.iter_mut().enumerate() {
+            *u = i as f32 / last as f32;
+        }
+    }
+    out
+}
+
+/// triangle strip along `points`, `scale` multiplying the style width and `alpha` the texture
+fn ribbon<'a, I: ExactSizeIterator<Item = &'a [f32; 2]>>(points: I, style: &LaserStyle, scale: f32, alpha: f32) -> Mesh<LaserVertex> {
+    let n = points.len();
+    let mut vertices = Vec::with_capacity(n * 2);
+    if n < 2 {
+        return Mesh::wrap_noind(vertices, PrimitiveType::TriangleStrip);
+    }
+    let pts: Vec<[f32; 2]> = points.cloned().collect();
+    let us = along(&pts);
+    let mut i = 0;
+    while i < n {
+        // tangent from the neighbours so the ribbon bends smoothly at each joint
+        let prev = pts[if i > 0 { i - 1 } else { 0 }];
+        let next = pts[if i + 1 < n { i + 1 } else { n - 1 }];
+        let t = [next[0] - prev[0], next[1] - prev[1]];
+        let len = (t[0] * t[0] + t[1] * t[1]).sqrt();
+        let normal = if len > 0.0 { [-t[1] / len, t[0] / len] } else { [0.0, 0.0] };
+        let u = us[i];
+        let half = style.width * scale * style.falloff(u) * 0.5;
+        let tu = style.uv[0] + (style.uv[2] - style.uv[0]) * u;
+        let p = pts[i];
+        vertices.push(LaserVertex { l_position: [p[0] + normal[0] * half, p[1] + normal[1] * half], l_uv: [tu, style.uv[3]], l_alpha: alpha });
+        vertices.push(LaserVertex { l_position: [p[0] - normal[0] * half, p[1] - normal[1] * half], l_uv: [tu, style.uv[1]], l_alpha: alpha });
+        i += 1;
+    }
+    Mesh::wrap_noind(vertices, PrimitiveType::TriangleStrip)
+}
+
+/// one capsule per segment, radius following the style falloff
+fn capsules<'a, K: Copy, I: ExactSizeIterator<Item = &'a [f32; 2]>>(points: I, style: &LaserStyle, world: &mut CollisionWorld<K>, layer: u32, mask: u32, key: K) {
+    if points.len() < 2 {
+        return;
+    }
+    let pts: Vec<[f32; 2]> = points.cloned().collect();
+    let us = along(&pts);
+    let mut prev: Option<[f32; 2]> = None;
+    for (i, p) in pts.iter().enumerate() {
+        if let Some(a) = prev {
+            let u = (us[i - 1] + us[i]) * 0.5;
+            let radius = style.hit_width * style.falloff(u) * 0.5;
+            if radius > 0.0 {
+                world.insert(Shape::Capsule { a, b: *p, radius }, layer, mask, key);
+            }
+        }
+        prev = Some(*p);
+    }
+}
+
+/// snake laser: a body of points that either trails its head or is sampled from a spline
+pub struct CurvedLaser {
+
+    points: VecDeque<[f32; 2]>,
+
+    nodes: usize,
+
+    style: LaserStyle,
+
+}
+
+impl CurvedLaser {
+
+    /// body of at most `nodes` points, grown by `push`
+    pub fn new(nodes: usize, style: LaserStyle) -> Self {
+        CurvedLaser {
+            points: VecDeque::with_capacity(nodes),
+            nodes: std::cmp::max(nodes, 2),
+            style,
+        }
+    }
+
+    /// `nodes` points evenly spaced in distance along the first two dimensions of `spline`
+    pub fn from_spline(spline: &CubeSpline<f32>, nodes: usize, style: LaserStyle) -> std::result::Result<Self, CubeSplineError> {
+        let mut laser = CurvedLaser::new(nodes, style);
+        let dim = spline.dim();
+        let arc = spline.arc_length(8)?;
+        let values = spline.resample_by_length(&arc, laser.nodes)?;
+        for v in values.chunks(dim) {
+            laser.points.push_back([v[0], if dim > 1 { v[1] } else { 0.0 }]);
+        }
+        Ok(laser)
+    }
+
+    /// new head position; the tail node is dropped once the body is full
+    pub fn push(&mut self, head: [f32; 2]) {
+        if self.points.len() == self.nodes {
+            self.points.pop_back();
+        }
+        self.points.push_front(head);
+    }
+
+    pub fn head(&self) -> Option<[f32; 2]> {
+        self.points.front().cloned()
+    }
+
+    pub fn points(&self) -> &VecDeque<[f32; 2]> {
+        &self.points
+    }
+
+    pub fn style(&self) -> &LaserStyle {
+        &self.style
+    }
+
+    pub fn set_style(&mut self, style: LaserStyle) -> &mut Self {
+        self.style = style;
+        self
+    }
+
+    pub fn ribbon(&self) -> Mesh<LaserVertex> {
+        ribbon(self.points.iter(), &self.style, 1.0, 1.0)
+    }
+
+    pub fn insert_into<K: Copy>(&self, world: &mut CollisionWorld<K>, layer: u32, mask: u32, key: K) {
+        capsules(self.points.iter(), &self.style, world, layer, mask, key);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BeamPhase {
+    /// thin telegraph line, harmless
+    Warmup,
+    Active,
+    /// narrowing to nothing, harmless
+    Fade,
+    Done,
+}
+
+/// straight laser fired from `origin` towards `angle` (radians); durations in seconds
+pub struct BeamLaser {
+
+    pub origin: [f32; 2],
+
+    pub angle: f32,
+
+    pub length: f32,
+
+    style: LaserStyle,
+
+    warmup: f32,
+
+    active: f32,
+
+    fade: f32,
+
+    /// width multiplier while warming up
+    warmup_width: f32,
+
+    age: f32,
+
+}
+
+impl BeamLaser {
+
+    pub fn new(origin: [f32; 2], angle: f32, length: f32, style: LaserStyle) -> Self {
+        BeamLaser {
+            origin,
+            angle,
+            length,
+            style,
+            warmup: 1.0,
+            active: 2.0,
+            fade: 0.25,
+            warmup_width: 0.1,
+            age: 0.0,
+        }
+    }
+
+    pub fn set_timing(&mut self, warmup: f32, active: f32, fade: f32) -> &mut Self {
+        self.warmup = warmup.max(0.0);
+        self.active = active.max(0.0);
+        self.fade = fade.max(0.0);
+        self
+    }
+
+    pub fn set_warmup_width(&mut self, warmup_width: f32) -> &mut Self {
+        self.warmup_width = warmup_width;
+        self
+    }
+
+    /// advance by `dt` nanoseconds
+    pub fn update(&mut self, dt: u64) {
+        self.age += dt as f32 / NANOS_PER_SEC;
+    }
+
+    pub fn phase(&self) -> BeamPhase {
+        if self.age < self.warmup {
+            BeamPhase::Warmup
+        } else if self.age < self.warmup + self.active {
+            BeamPhase::Active
+        } else if self.age < self.warmup + self.active + self.fade {
+            BeamPhase::Fade
+        } else {
+            BeamPhase::Done
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.phase() == BeamPhase::Done
+    }
+
+    pub fn end(&self) -> [f32; 2] {
+        [self.origin[0] + self.angle.cos() * self.length, self.origin[1] + self.angle.sin() * self.length]
+    }
+
+    /// hit shape, only while active
+    pub fn shape(&self) -> Option<Shape> {
+        if self.phase() != BeamPhase::Active {
+            return None;
+        }
+        Some(Shape::Capsule { a: self.origin, b: self.end(), radius: self.style.hit_width * 0.5 })
+    }
+
+    pub fn insert_into<K: Copy>(&self, world: &mut CollisionWorld<K>, layer: u32, mask: u32, key: K) {
+        if let Some(shape) = self.shape() {
+            world.insert(shape, layer, mask, key);
+        }
+    }
+
+    pub fn ribbon(&self) -> Mesh<LaserVertex> {
+        let (scale, alpha) = match self.phase() {
+            BeamPhase::Warmup => (self.warmup_width, 0.5),
+            BeamPhase::Active => (1.0, 1.0),
+            BeamPhase::Fade => {
+                let t = (self.age - self.warmup - self.active) / self.fade;
+                (1.0 - t, 1.0 - t)
+            }
+            BeamPhase::Done => (0.0, 0.0),
+        };
+        // no taper on a beam, it starts at the emitter and leaves the screen
+        let style = LaserStyle { taper: 0.0, .. self.style };
+        let points = [self.origin, self.end()];
+        ribbon(points.iter(), &style, scale, alpha)
+    }
+}
+
+/// program built from `glsl/laser.vert` and `glsl/laser.frag`
+pub fn load_program(facade: &dyn Facade, resource: &Resource) -> Result<Program> {
+    let vert = resource.load_as_string("glsl/laser.vert").map_err(Box::new)?;
+    let frag = resource.load_as_string("glsl/laser.frag").map_err(Box::new)?;
+    let prog = Program::from_source(facade, &vert, &frag, None).map_err(Box::new)?;
+    Ok(prog)
+}
+
+/// draw a ribbon with `texture`; `projection` maps world units to clip space
+pub fn draw<S: Surface>(mesh: &Mesh<LaserVertex>, facade: &dyn Facade, target: &mut S, program: &Program, texture: &Texture2d, projection: [[f32; 4]; 4], draw_parameters: &DrawParameters) -> Result<()> {
+    let texture = texture.sampled().magnify_filter(MagnifySamplerFilter::Linear);
+    let uniforms = uniform!{ texture_sampler: texture, projection: projection };
+    mesh.draw(facade, target, program, &uniforms, draw_parameters)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use super::super::spline::Boundary;
+
+    #[test]
+    fn snake_keeps_its_length() {
+        let mut laser = CurvedLaser::new(4, LaserStyle::default());
+        let mut i = 0;
+        while i < 10 {
+            laser.push([i as f32, 0.0]);
+            i += 1;
+        }
+        assert_eq!(laser.points().len(), 4);
+        assert_eq!(laser.head(), Some([9.0, 0.0]));
+
+        let mut world = CollisionWorld::new([0.0, -5.0], [10.0, 5.0], 1.0);
+        laser.insert_into(&mut world, 1, 1, 0u32);
+        assert_eq!(world.len(), 3);
+    }
+
+    #[test]
+    fn beam_only_hits_while_active() {
+        let mut beam = BeamLaser::new([0.0, 0.0], 0.0, 10.0, LaserStyle::default());
+        beam.set_timing(1.0, 1.0, 1.0);
+        assert_eq!(beam.phase(), BeamPhase::Warmup);
+        assert!(beam.shape().is_none());
+        beam.update(1_500_000_000);
+        assert_eq!(beam.phase(), BeamPhase::Active);
+        let target = Shape::Circle { center: [5.0, 0.3], radius: 0.1 };
+        assert!(beam.shape().unwrap().intersects(&target));
+        beam.update(1_000_000_000);
+        assert_eq!(beam.phase(), BeamPhase::Fade);
+        assert!(beam.shape().is_none());
+        beam.update(1_000_000_000);
+        assert!(beam.is_finished());
+    }
+
+    #[test]
+    fn spline_nodes_are_evenly_spaced() {
+        // (t^3 + t, 2t): equal parameter steps get ten times longer towards the end
+        let knots = vec![0.0, 1.0, 2.0, 3.0];
+        let values = knots.iter().flat_map(|t: &f32| vec![t * t * t + t, 2.0 * t]).collect();
+        let spline = CubeSpline::new().compile_with(knots, values, Boundary::NotAKnot).unwrap();
+        let laser = CurvedLaser::from_spline(&spline, 31, LaserStyle::default()).unwrap();
+        let points: Vec<[f32; 2]> = laser.points().iter().cloned().collect();
+        assert_eq!(points.len(), 31);
+        let gaps: Vec<f32> = points.windows(2).map(|p| (p[1][0] - p[0][0]).hypot(p[1][1] - p[0][1])).collect();
+        for gap in gaps.iter() {
+            assert!((gap - gaps[0]).abs() < 1e-2 * gaps[0], "{:?}", gaps);
+        }
+    }
+
+    #[test]
+    fn texture_follows_distance_not_nodes() {
+        let style = LaserStyle { taper: 0.0, ..Default::default() };
+        let mut laser = CurvedLaser::new(4, style);
+        for p in [[10.0, 0.0], [0.2, 0.0], [0.1, 0.0], [0.0, 0.0]].iter() {
+            laser.push(*p);
+        }
+        let mesh = laser.ribbon();
+        let u: Vec<f32> = mesh.vertices().iter().step_by(2).map(|v| v.l_uv[0]).collect();
+        assert_eq!(u[0], 0.0);
+        assert!((u[1] - 0.01).abs() < 1e-6 && (u[2] - 0.02).abs() < 1e-6);
+        assert_eq!(u[3], 1.0);
+    }
+}
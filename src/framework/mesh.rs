@@ -1,7 +1,9 @@
-
-use glium::{Vertex, Frame, Program, DrawParameters};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::ops::Range;
+use glium::{Vertex, Program, DrawParameters, VertexBuffer};
 use glium::vertex::{PerInstance};
-use glium::index::{PrimitiveType};
+use glium::index::{PrimitiveType, IndexBuffer, NoIndices, Index};
 use glium::uniforms::{Uniforms};
 use glium::backend::{Facade};
 use glium::Surface;
@@ -9,87 +11,280 @@ use glium::texture::Texture2d;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-pub struct Mesh<V: Vertex> {
+/// index types a `Mesh` can use: u8, u16 or u32
+pub trait MeshIndex: Index + Copy {
+
+    /// largest vertex index the type can hold
+    const MAX: usize;
+
+    /// `i` must not exceed `MAX`
+    fn from_usize(i: usize) -> Self;
+
+    fn to_usize(self) -> usize;
+}
+
+macro_rules! impl_mesh_index {
+    ($($t:ty),*) => {
+        $(
+            impl MeshIndex for $t {
+
+                const MAX: usize = <$t>::MAX as usize;
+
+                fn from_usize(i: usize) -> Self {
+                    i as $t
+                }
+
+                fn to_usize(self) -> usize {
+                    self as usize
+                }
+            }
+        )*
+    };
+}
+
+impl_mesh_index!(u8, u16, u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MeshError {
+    /// the mesh was built without indices
+    NoIndices,
+    /// fewer indices than vertices were pushed
+    CountMismatch { vertices: usize, indices: usize },
+    /// a pushed index points past the pushed vertices
+    IndexOutOfRange { index: usize, vertices: usize },
+    /// the mesh would hold more vertices than its index type can address; convert it to a wider index
+    IndexOverflow { vertices: usize, max: usize },
+}
+
+impl fmt::Display for MeshError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::NoIndices => write!(f, "mesh has no indices"),
+            MeshError::CountMismatch { vertices, indices } => write!(f, "mesh push: {} indices for {} vertices", indices, vertices),
+            MeshError::IndexOutOfRange { index, vertices } => write!(f, "mesh push: index {} out of {} vertices", index, vertices),
+            MeshError::IndexOverflow { vertices, max } => write!(f, "mesh of {} vertices overflows its index type (max {})", vertices, max),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+// buffers kept on the gpu between draws, sized to a power of two so pushes rarely reallocate
+struct GpuBuffers<V: Vertex, I: MeshIndex> {
+
+    vertices: VertexBuffer<V>,
+
+    indices: Option<IndexBuffer<I>>,
+
+}
+
+pub struct Mesh<V: Vertex, I: MeshIndex = u16> {
 
     vertices: Vec<V>,
 
-    indices: Option<Vec<u16>>,
+    indices: Option<Vec<I>>,
 
     primitive_type: PrimitiveType,
 
+    gpu: RefCell<Option<GpuBuffers<V, I>>>,
+
+    /// vertices changed since the last upload
+    dirty_vertices: Cell<Option<(usize, usize)>>,
+
+    /// indices changed since the last upload
+    dirty_indices: Cell<Option<(usize, usize)>>,
+
+}
+
+// grow `dirty` to cover `start..end`
+fn mark(dirty: &Cell<Option<(usize, usize)>>, start: usize, end: usize) {
+    if start < end {
+        dirty.set(match dirty.get() {
+            Some((a, b)) => Some((std::cmp::min(a, start), std::cmp::max(b, end))),
+            None => Some((start, end)),
+        });
+    }
+}
+
+impl<V: Vertex, I: MeshIndex> Default for Mesh<V, I> {
+
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<V: Vertex> Mesh<V> {
+impl<V: Vertex, I: MeshIndex> Mesh<V, I> {
 
     pub fn new() -> Self {
-        Mesh {
-            vertices: Vec::new(),
-            indices: Some(Vec::new()),
-            primitive_type: PrimitiveType::TrianglesList,
-        }
+        Self::from_parts(Vec::new(), Some(Vec::new()), PrimitiveType::TrianglesList)
     }
 
-    pub fn wrap(vertices: Vec<V>, indices: Vec<u16>, primitive_type: PrimitiveType) -> Self {
-        Mesh {
-            vertices,
-            indices: Some(indices),
-            primitive_type,
-        }
+    pub fn wrap(vertices: Vec<V>, indices: Vec<I>, primitive_type: PrimitiveType) -> Self {
+        Self::from_parts(vertices, Some(indices), primitive_type)
     }
 
     pub fn wrap_noind(vertices: Vec<V>, primitive_type: PrimitiveType) -> Self {
+        Self::from_parts(vertices, None, primitive_type)
+    }
+
+    fn from_parts(vertices: Vec<V>, indices: Option<Vec<I>>, primitive_type: PrimitiveType) -> Self {
         Mesh {
             vertices,
-            indices: None,
+            indices,
             primitive_type,
+            gpu: RefCell::new(None),
+            dirty_vertices: Cell::new(None),
+            dirty_indices: Cell::new(None),
         }
     }
 
-    pub fn push(&mut self, vertices: &[V], indices: &[u16]) -> bool {
-        if let Some(ind) = &mut self.indices {
-            if indices.len() >= vertices.len() {
-                let vert = &mut self.vertices;
-                let offset = vert.len() as u16;
-                let start = ind.len() as usize;
-                ind.extend_from_slice(indices);
-                vert.extend_from_slice(vertices);               
-                for i in &mut ind[start..] {
-                    *i += offset;
-                }              
-                return true;              
-            }
+    /// append vertices with indices relative to the first of them; any index type is accepted
+    pub fn push<J: MeshIndex>(&mut self, vertices: &[V], indices: &[J]) -> std::result::Result<&mut Self, MeshError> {
+        let ind = self.indices.as_mut().ok_or(MeshError::NoIndices)?;
+        if indices.len() < vertices.len() {
+            return Err(MeshError::CountMismatch { vertices: vertices.len(), indices: indices.len() });
+        }
+        if let Some(bad) = indices.iter().map(|i| i.to_usize()).find(|&i| i >= vertices.len()) {
+            return Err(MeshError::IndexOutOfRange { index: bad, vertices: vertices.len() });
         }
-        return false;
+        let offset = self.vertices.len();
+        let total = offset + vertices.len();
+        if total > 0 && total - 1 > I::MAX {
+            return Err(MeshError::IndexOverflow { vertices: total, max: I::MAX });
+        }
+        let start = ind.len();
+        ind.extend(indices.iter().map(|i| I::from_usize(i.to_usize() + offset)));
+        self.vertices.extend_from_slice(vertices);
+        mark(&self.dirty_vertices, offset, total);
+        mark(&self.dirty_indices, start, ind.len());
+        Ok(self)
     }
 
+    /// same mesh with another index type, e.g. u16 to u32 once it outgrows 65536 vertices
+    pub fn convert<J: MeshIndex>(self) -> std::result::Result<Mesh<V, J>, MeshError> {
+        if !self.vertices.is_empty() && self.vertices.len() - 1 > J::MAX {
+            return Err(MeshError::IndexOverflow { vertices: self.vertices.len(), max: J::MAX });
+        }
+        let indices = self.indices.map(|ind| ind.into_iter().map(|i| J::from_usize(i.to_usize())).collect());
+        Ok(Mesh::from_parts(self.vertices, indices, self.primitive_type))
+    }
+
+    /// the whole vertex range is uploaded again on the next draw
     pub fn modify(&mut self) -> &mut [V] {
+        mark(&self.dirty_vertices, 0, self.vertices.len());
         self.vertices.as_mut_slice()
     }
 
-    pub fn draw<U: Uniforms>(&self, facade: &dyn Facade, target: &mut Frame, program: &Program, uniforms: &U, draw_parameters: &DrawParameters) -> Result<&Self> {
+    /// only `range` is uploaded again on the next draw
+    pub fn modify_range(&mut self, range: Range<usize>) -> &mut [V] {
+        mark(&self.dirty_vertices, range.start, range.end);
+        &mut self.vertices[range]
+    }
+
+    pub fn vertices(&self) -> &[V] {
+        &self.vertices
+    }
+
+    /// true when the next draw has something to upload
+    pub fn is_dirty(&self) -> bool {
+        self.gpu.borrow().is_none() || self.dirty_vertices.get().is_some() || self.dirty_indices.get().is_some()
+    }
+
+    /// drop the gpu copy, e.g. when the context is lost
+    pub fn release(&self) {
+        *self.gpu.borrow_mut() = None;
+    }
+
+    /// bring the gpu buffers up to date; called by the draw functions
+    pub fn upload(&self, facade: &dyn Facade) -> Result<()> {
+        let mut gpu = self.gpu.borrow_mut();
+        let fits = match &*gpu {
+            Some(g) => g.vertices.len() >= self.vertices.len() && match (&g.indices, &self.indices) {
+                (Some(gi), Some(i)) => gi.len() >= i.len(),
+                (None, None) => true,
+                _ => false,
+            },
+            None => false,
+        };
+        if !fits {
+            let vertices = VertexBuffer::empty_dynamic(facade, self.vertices.len().next_power_of_two()).map_err(Box::new)?;
+            if let Some(slice) = vertices.slice(0..self.vertices.len()) {
+                slice.write(&self.vertices);
+            }
+            let indices = match &self.indices {
+                Some(ind) => {
+                    let buffer = IndexBuffer::empty_dynamic(facade, self.primitive_type, ind.len().next_power_of_two()).map_err(Box::new)?;
+                    if let Some(slice) = buffer.slice(0..ind.len()) {
+                        slice.write(ind);
+                    }
+                    Some(buffer)
+                }
+                None => None,
+            };
+            *gpu = Some(GpuBuffers { vertices, indices });
+            self.dirty_vertices.set(None);
+            self.dirty_indices.set(None);
+            return Ok(());
+        }
+
+        if let Some(g) = gpu.as_mut() {
+            if let Some((a, b)) = self.dirty_vertices.take() {
+                // a full rewrite orphans the old storage instead of waiting for draws still using it
+                if a == 0 && b >= g.vertices.len() {
+                    g.vertices.invalidate();
+                }
+                if let Some(slice) = g.vertices.slice(a..b) {
+                    slice.write(&self.vertices[a..b]);
+                }
+            }
+            if let (Some(gi), Some(ind), Some((a, b))) = (&mut g.indices, &self.indices, self.dirty_indices.take()) {
+                if let Some(slice) = gi.slice(a..b) {
+                    slice.write(&ind[a..b]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn draw<S: Surface, U: Uniforms>(&self, facade: &dyn Facade, target: &mut S, program: &Program, uniforms: &U, draw_parameters: &DrawParameters) -> Result<&Self> {
         if !self.vertices.is_empty() {
-            let vbo = glium::VertexBuffer::new(facade, self.vertices.as_slice()).map_err(Box::new)?;
-            if let Some(indices) = &self.indices {
-                let ind = glium::index::IndexBuffer::new(facade, self.primitive_type, indices.as_slice()).map_err(Box::new)?;
-                target.draw(&vbo, &ind, program, uniforms, draw_parameters).map_err(Box::new)?;
-            } else {
-                let ind = glium::index::NoIndices(self.primitive_type);
-                target.draw(&vbo, &ind, program, uniforms, draw_parameters).map_err(Box::new)?;
-            }           
+            self.upload(facade)?;
+            let gpu = self.gpu.borrow();
+            if let Some(g) = &*gpu {
+                let vbo = g.vertices.slice(0..self.vertices.len()).ok_or("mesh vertex buffer too small")?;
+                match (&g.indices, &self.indices) {
+                    (Some(gi), Some(ind)) => {
+                        let ind = gi.slice(0..ind.len()).ok_or("mesh index buffer too small")?;
+                        target.draw(vbo, ind, program, uniforms, draw_parameters).map_err(Box::new)?;
+                    }
+                    _ => {
+                        let ind = NoIndices(self.primitive_type);
+                        target.draw(vbo, ind, program, uniforms, draw_parameters).map_err(Box::new)?;
+                    }
+                }
+            }
         }
         Ok(self)
     }
 
-    pub fn draw_instances<U: Uniforms>(&self, facade: &dyn Facade, target: &mut Frame, per_instance: PerInstance, program: &Program, uniforms: &U, draw_parameters: &DrawParameters) -> Result<&Self> {
+    pub fn draw_instances<S: Surface, U: Uniforms>(&self, facade: &dyn Facade, target: &mut S, per_instance: PerInstance, program: &Program, uniforms: &U, draw_parameters: &DrawParameters) -> Result<&Self> {
         if !self.vertices.is_empty() {
-            let vbo = glium::VertexBuffer::new(facade, self.vertices.as_slice()).map_err(Box::new)?;
-            if let Some(indices) = &self.indices {
-                let ind = glium::index::IndexBuffer::new(facade, self.primitive_type, indices.as_slice()).map_err(Box::new)?;
-                target.draw((&vbo, per_instance), &ind, program, uniforms, draw_parameters).map_err(Box::new)?;
-            } else {
-                let ind = glium::index::NoIndices(self.primitive_type);
-                target.draw((&vbo, per_instance), &ind, program, uniforms, draw_parameters).map_err(Box::new)?;
-            }           
+            self.upload(facade)?;
+            let gpu = self.gpu.borrow();
+            if let Some(g) = &*gpu {
+                let vbo = g.vertices.slice(0..self.vertices.len()).ok_or("mesh vertex buffer too small")?;
+                match (&g.indices, &self.indices) {
+                    (Some(gi), Some(ind)) => {
+                        let ind = gi.slice(0..ind.len()).ok_or("mesh index buffer too small")?;
+                        target.draw((vbo, per_instance), ind, program, uniforms, draw_parameters).map_err(Box::new)?;
+                    }
+                    _ => {
+                        let ind = NoIndices(self.primitive_type);
+                        target.draw((vbo, per_instance), ind, program, uniforms, draw_parameters).map_err(Box::new)?;
+                    }
+                }
+            }
         }
         Ok(self)
     }
@@ -115,4 +310,60 @@ pub fn load_texture2d<R: Read>(ifile: R) -> io::Result<RawImage2d<'static, u8>>
         _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", info.color_type)))   
     };
     Ok(tx)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[derive(Copy, Clone)]
+    struct P {
+        p: [f32; 2],
+    }
+
+    implement_vertex!(P, p);
+
+    fn quad() -> [P; 4] {
+        [P { p: [0.0, 0.0] }, P { p: [1.0, 0.0] }, P { p: [0.0, 1.0] }, P { p: [1.0, 1.0] }]
+    }
+
+    #[test]
+    fn push_and_modify_grow_the_dirty_range() {
+        let mut mesh: Mesh<P> = Mesh::new();
+        mesh.push(&quad(), &INDICES4_RECT).unwrap().push(&quad(), &INDICES4_RECT).unwrap();
+        assert_eq!(mesh.dirty_vertices.get(), Some((0, 8)));
+        assert_eq!(mesh.dirty_indices.get(), Some((0, 12)));
+        assert_eq!(mesh.indices.as_ref().unwrap()[6], 4);
+
+        mesh.dirty_vertices.set(None);
+        mesh.modify_range(5..6)[0].p = [2.0, 2.0];
+        mesh.modify_range(2..3);
+        assert_eq!(mesh.dirty_vertices.get(), Some((2, 6)));
+        assert_eq!(mesh.vertices()[5].p, [2.0, 2.0]);
+    }
+
+    #[test]
+    fn never_uploaded_mesh_is_dirty() {
+        let mesh: Mesh<P> = Mesh::wrap_noind(quad().to_vec(), PrimitiveType::TriangleStrip);
+        assert!(mesh.is_dirty());
+        assert_eq!(Mesh::<P>::new().push(&quad(), &INDICES3_TRIANGLE).err(), Some(MeshError::CountMismatch { vertices: 4, indices: 3 }));
+        assert_eq!(mesh.convert::<u32>().unwrap().push(&quad(), &INDICES4_RECT).err(), Some(MeshError::NoIndices));
+    }
+
+    #[test]
+    fn narrow_indices_overflow_until_converted() {
+        let mut mesh: Mesh<P, u8> = Mesh::new();
+        let mut i = 0;
+        while i < 64 {
+            mesh.push(&quad(), &INDICES4_RECT).unwrap();
+            i += 1;
+        }
+        assert_eq!(mesh.push(&quad(), &INDICES4_RECT).err(), Some(MeshError::IndexOverflow { vertices: 260, max: 255 }));
+        assert!(mesh.push(&quad(), &[0u8, 1, 9, 9, 2, 0]).is_err());
+
+        let mut wide: Mesh<P, u32> = mesh.convert().unwrap();
+        wide.push(&quad(), &INDICES4_RECT).unwrap();
+        assert_eq!(wide.indices.as_ref().unwrap()[64 * 6], 256);
+        assert!(wide.convert::<u8>().is_err());
+    }
+}
@@ -2,4 +2,21 @@ pub mod util;
 pub mod game;
 pub mod mesh;
 pub mod spline;
+pub mod bullet;
+pub mod danmaku;
+pub mod path;
+pub mod interpolate;
+pub mod collision;
+pub mod laser;
+pub mod ecs;
+pub mod input;
+pub mod replay;
+pub mod rng;
+pub mod stage;
 
+pub mod boss;
+pub mod sprite;
+pub mod atlas;
+pub mod target;
+pub mod primitive;
+pub mod block;
@@ -0,0 +1,333 @@
+use std::sync::Arc;
+use num_traits::float::Float;
+use serde::Deserialize;
+
+use super::spline::{CubeSpline, CubeSplineError, ArcLength, SplineCursor};
+use super::interpolate::{Interpolator, CurveKind, from_points};
+
+const NANOS_PER_SEC: f64 = 1_000_000_000.0;
+
+pub enum FollowSpeed<T> {
+    /// world units per second
+    Constant(T),
+    /// 1-dimensional spline mapping seconds since start to world units per second
+    Curve(Arc<CubeSpline<T>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FollowEnd {
+    Stop,
+    Loop,
+}
+
+/// curve with its arc length table, built once and shared by every follower
+pub struct Path<T> {
+
+    curve: Box<dyn Interpolator<T> + Send + Sync>,
+
+    arc: ArcLength<T>,
+
+}
+
+impl<T: Float> Path<T> {
+
+    /// `subdiv` is the number of arc length samples per curve segment
+    pub fn new(curve: Box<dyn Interpolator<T> + Send + Sync>, subdiv: usize) -> Result<Self, CubeSplineError> {
+        let arc = ArcLength::new(curve.as_ref(), subdiv)?;
+        Ok(Path { curve, arc })
+    }
+
+    pub fn curve(&self) -> &dyn Interpolator<T> {
+        self.curve.as_ref()
+    }
+
+    pub fn arc(&self) -> &ArcLength<T> {
+        &self.arc
+    }
+
+    pub fn length(&self) -> T {
+        self.arc.total()
+    }
+}
+
+/// path as written in stage and boss files: `{ "points": [[0, 200], [80, 120]], "knots": [0, 1], "kind": "centripetal" }`
+#[derive(Debug, Clone, Deserialize)]
+pub struct PathDesc {
+
+    pub points: Vec<[f32; 2]>,
+
+    /// curve family through the points, a natural spline unless given
+    #[serde(default)]
+    pub kind: CurveKind,
+
+    /// parameter of each point, defaults to 0, 1, 2, ...
+    pub knots: Option<Vec<f32>>,
+
+    #[serde(default = "default_subdiv")]
+    pub subdiv: usize,
+
+}
+
+fn default_subdiv() -> usize {
+    8
+}
+
+impl PathDesc {
+
+    /// curve of `kind` through the points, shifted by `shift`
+    pub fn compile(&self, shift: [f32; 2]) -> Result<Path<f32>, CubeSplineError> {
+        let knots = match &self.knots {
+            Some(knots) => knots.clone(),
+            None => (0..self.points.len()).map(|i| i as f32).collect(),
+        };
+        let mut values = Vec::with_capacity(self.points.len() * 2);
+        for p in self.points.iter() {
+            values.push(p[0] + shift[0]);
+            values.push(p[1] + shift[1]);
+        }
+        let curve = from_points(self.kind, knots, values)?;
+        Path::new(curve, self.subdiv)
+    }
+}
+
+/// moves along a path at a speed measured in arc length rather than in the spline parameter
+pub struct PathFollower<T> {
+
+    path: Arc<Path<T>>,
+
+    speed: FollowSpeed<T>,
+
+    speed_cursor: SplineCursor,
+
+    end: FollowEnd,
+
+    orient: bool,
+
+    elapsed: T,
+
+    distance: T,
+
+    position: Vec<T>,
+
+    tangent: Vec<T>,
+
+}
+
+impl<T: Float> PathFollower<T> {
+
+    pub fn new(path: Arc<Path<T>>, speed: FollowSpeed<T>) -> Result<Self, CubeSplineError> {
+        let dim = path.curve.dim();
+        let mut follower = PathFollower {
+            path,
+            speed,
+            speed_cursor: SplineCursor::new(),
+            end: FollowEnd::Stop,
+            orient: false,
+            elapsed: T::zero(),
+            distance: T::zero(),
+            position: vec![T::zero(); dim],
+            tangent: vec![T::zero(); dim],
+        };
+        follower.sample()?;
+        Ok(follower)
+    }
+
+    pub fn set_end(&mut self, end: FollowEnd) -> &mut Self {
+        self.end = end;
+        self
+    }
+
+    /// keep the tangent up to date so `angle` can be used to rotate the follower
+    pub fn set_orient(&mut self, orient: bool) -> Result<&mut Self, CubeSplineError> {
+        self.orient = orient;
+        self.sample()?;
+        Ok(self)
+    }
+
+    pub fn set_speed(&mut self, speed: FollowSpeed<T>) -> &mut Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn seek(&mut self, distance: T) -> Result<&mut Self, CubeSplineError> {
+        if !distance.is_finite() {
+            return Err(CubeSplineError::EvalNotFinite);
+        }
+        self.distance = self.wrap(distance);
+        self.sample()?;
+        Ok(self)
+    }
+
+    pub fn length(&self) -> T {
+        self.path.length()
+    }
+
+    pub fn distance(&self) -> T {
+        self.distance
+    }
+
+    pub fn position(&self) -> &[T] {
+        self.position.as_slice()
+    }
+
+    /// derivative of the curve at the current point; only maintained when orienting
+    pub fn tangent(&self) -> &[T] {
+        self.tangent.as_slice()
+    }
+
+    /// heading in the plane of the first two dimensions, in radians
+    pub fn angle(&self) -> T {
+        if self.tangent.len() < 2 {
+            return T::zero();
+        }
+        self.tangent[1].atan2(self.tangent[0])
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.end == FollowEnd::Stop && self.distance >= self.path.length()
+    }
+
+    pub fn get_path(&self) -> &Arc<Path<T>> {
+        &self.path
+    }
+
+    /// advance by `dt` nanoseconds
+    pub fn update(&mut self, dt: u64) -> Result<(), CubeSplineError> {
+        let t = T::from(dt as f64 / NANOS_PER_SEC).unwrap();
+        let speed = match &self.speed {
+            FollowSpeed::Constant(v) => *v,
+            FollowSpeed::Curve(curve) => {
+                let mut v = [T::zero()];
+                self.speed_cursor.get(curve, self.elapsed, &mut v)?;
+                v[0]
+            }
+        };
+        let distance = self.distance + speed * t;
+        if !distance.is_finite() {
+            return Err(CubeSplineError::EvalNotFinite);
+        }
+        self.elapsed = self.elapsed + t;
+        self.distance = self.wrap(distance);
+        self.sample()
+    }
+
+    fn wrap(&self, distance: T) -> T {
+        let total = self.path.length();
+        match self.end {
+            FollowEnd::Stop => distance.max(T::zero()).min(total),
+            FollowEnd::Loop => {
+                if total <= T::zero() {
+                    return T::zero();
+                }
+                let d = distance % total;
+                if d < T::zero() { d + total } else { d }
+            }
+        }
+    }
+
+    fn sample(&mut self) -> Result<(), CubeSplineError> {
+        let curve = self.path.curve();
+        let x = self.path.arc.param_at(curve, self.distance)?;
+        curve.get(x, &mut self.position)?;
+        if self.orient {
+            curve.get_derivative(x, &mut self.tangent)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use super::super::spline::Boundary;
+
+    const TICK: u64 = 50_000_000;
+
+    // (t^3 + t, 2t): the parameter speed grows more than tenfold from the start to the end
+    fn uneven() -> Arc<Path<f64>> {
+        let knots = vec![0.0, 1.0, 2.0, 3.0];
+        let points = knots.iter().flat_map(|t: &f64| vec![t * t * t + t, 2.0 * t]).collect();
+        let spline = CubeSpline::new().compile_with(knots, points, Boundary::NotAKnot).unwrap();
+        Arc::new(Path::new(Box::new(spline), 32).unwrap())
+    }
+
+    fn distance(a: &[f64], b: &[f64]) -> f64 {
+        (a[0] - b[0]).hypot(a[1] - b[1])
+    }
+
+    #[test]
+    fn constant_speed_moves_equal_arc_per_tick() {
+        let path = uneven();
+        let mut d = [0.0; 2];
+        path.curve().get_derivative(0.0, &mut d).unwrap();
+        let slow = d[0].hypot(d[1]);
+        path.curve().get_derivative(3.0, &mut d).unwrap();
+        assert!(d[0].hypot(d[1]) > slow * 10.0);
+
+        // 20 units per second for 50 ms ticks, one unit of arc each
+        let mut follower = PathFollower::new(path, FollowSpeed::Constant(20.0)).unwrap();
+        let mut last = follower.position().to_vec();
+        let mut ticks = 0;
+        while !follower.is_finished() {
+            follower.update(TICK).unwrap();
+            let step = distance(follower.position(), &last);
+            if !follower.is_finished() {
+                assert!((step - 1.0).abs() < 1e-2, "tick {} moved {}", ticks, step);
+            }
+            last = follower.position().to_vec();
+            ticks += 1;
+        }
+        assert_eq!(ticks, follower.length().ceil() as usize);
+        assert!(distance(follower.position(), &[30.0, 6.0]) < 1e-9);
+    }
+
+    #[test]
+    fn curve_speed_follows_elapsed_time() {
+        let line = CubeSpline::new().compile(vec![0.0, 1.0, 2.0], vec![0.0, 0.0, 50.0, 0.0, 100.0, 0.0]).unwrap();
+        let path = Arc::new(Path::new(Box::new(line), 8).unwrap());
+        // speed ramps from 0 to 10 units per second over 2 seconds
+        let ramp = Arc::new(CubeSpline::new().compile(vec![0.0, 1.0, 2.0], vec![0.0, 5.0, 10.0]).unwrap());
+        let mut follower = PathFollower::new(path, FollowSpeed::Curve(ramp)).unwrap();
+        let mut i = 0;
+        while i < 20 {
+            follower.update(TICK).unwrap();
+            i += 1;
+        }
+        // each tick uses the speed at its start: 5 t over t = 0, 0.05, ..., 0.95
+        let expected = (0..20).map(|k| 5.0 * k as f64 * 0.05 * 0.05).sum::<f64>();
+        assert!((follower.distance() - expected).abs() < 1e-9);
+        assert!((follower.position()[0] - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn loop_wraps_around() {
+        let mut follower = PathFollower::new(uneven(), FollowSpeed::Constant(20.0)).unwrap();
+        follower.set_end(FollowEnd::Loop);
+        let length = follower.length();
+        follower.seek(length - 0.5).unwrap();
+        follower.update(TICK).unwrap();
+        assert!((follower.distance() - 0.5).abs() < 1e-9);
+        assert!(!follower.is_finished());
+    }
+
+    #[test]
+    fn desc_picks_the_curve_kind() {
+        let desc: PathDesc = serde_json::from_str(r#"{ "points": [[0, 0], [1, 0], [2, 0], [2.1, 1], [3, 1]], "kind": "monotone" }"#).unwrap();
+        assert_eq!(desc.kind, CurveKind::Monotone);
+        let path = desc.compile([10.0, 0.0]).unwrap();
+        let mut y = [0.0; 2];
+        let mut x = 0.0;
+        while x <= 4.0 {
+            path.curve().get(x, &mut y).unwrap();
+            assert!(y[1] >= 0.0 && y[1] <= 1.0);
+            x += 0.05;
+        }
+        path.curve().get(3.0, &mut y).unwrap();
+        assert_eq!(y, [12.1, 1.0]);
+
+        let desc: PathDesc = serde_json::from_str(r#"{ "points": [[0, 0], [1, 1], [2, 0]] }"#).unwrap();
+        assert_eq!(desc.kind, CurveKind::Natural);
+        assert!(serde_json::from_str::<PathDesc>(r#"{ "points": [[0, 0], [1, 1]], "kind": "bspline" }"#).is_err());
+    }
+}
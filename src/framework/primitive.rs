@@ -0,0 +1,350 @@
+use std::f32::consts::PI;
+use glium::Vertex;
+
+use super::mesh::{Mesh, MeshIndex, MeshError, INDICES4_RECT};
+
+///
+/// vertex the builders below can fill in
+///
+/// every setter defaults to doing nothing, so a vertex only implements the attributes it has;
+/// 2d vertices just drop the z of `set_position`
+pub trait BuildVertex: Vertex + Default {
+
+    fn set_position(&mut self, _position: [f32; 3]) {
+    }
+
+    fn set_uv(&mut self, _uv: [f32; 2]) {
+    }
+
+    fn set_normal(&mut self, _normal: [f32; 3]) {
+    }
+
+    fn set_color(&mut self, _color: [f32; 4]) {
+    }
+}
+
+pub const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+fn vertex<V: BuildVertex>(position: [f32; 3], uv: [f32; 2], normal: [f32; 3]) -> V {
+    let mut v = V::default();
+    v.set_position(position);
+    v.set_uv(uv);
+    v.set_normal(normal);
+    v.set_color(WHITE);
+    v
+}
+
+/// side of a box, in the order used by block models
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Face {
+    Down,
+    Up,
+    North,
+    South,
+    West,
+    East,
+}
+
+impl Face {
+
+    pub const ALL: [Face; 6] = [Face::Down, Face::Up, Face::North, Face::South, Face::West, Face::East];
+
+    /// north is -z, west is -x, up is +y
+    pub fn normal(self) -> [f32; 3] {
+        match self {
+            Face::Down => [0.0, -1.0, 0.0],
+            Face::Up => [0.0, 1.0, 0.0],
+            Face::North => [0.0, 0.0, -1.0],
+            Face::South => [0.0, 0.0, 1.0],
+            Face::West => [-1.0, 0.0, 0.0],
+            Face::East => [1.0, 0.0, 0.0],
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Face::Down => "down",
+            Face::Up => "up",
+            Face::North => "north",
+            Face::South => "south",
+            Face::West => "west",
+            Face::East => "east",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Face> {
+        Face::ALL.iter().cloned().find(|f| f.name() == name)
+    }
+}
+
+/// corners of one side of the box `from`..`to`: bottom left, bottom right, top left, top right, as `INDICES4_RECT` expects
+pub fn face_corners(from: [f32; 3], to: [f32; 3], face: Face) -> [[f32; 3]; 4] {
+    // per corner, whether x, y and z come from `to`
+    let pick: [[bool; 3]; 4] = match face {
+        Face::Down => [[false, false, true], [true, false, true], [false, false, false], [true, false, false]],
+        Face::Up => [[false, true, false], [true, true, false], [false, true, true], [true, true, true]],
+        Face::North => [[false, false, false], [true, false, false], [false, true, false], [true, true, false]],
+        Face::South => [[true, false, true], [false, false, true], [true, true, true], [false, true, true]],
+        Face::West => [[false, false, true], [false, false, false], [false, true, true], [false, true, false]],
+        Face::East => [[true, false, false], [true, false, true], [true, true, false], [true, true, true]],
+    };
+    let mut out = [[0.0; 3]; 4];
+    for (corner, p) in out.iter_mut().zip(pick.iter()) {
+        let mut k = 0;
+        while k < 3 {
+            corner[k] = if p[k] { to[k] } else { from[k] };
+            k += 1;
+        }
+    }
+    out
+}
+
+/// uv of the corners of `rect` = [u0, v0, u1, v1], in `face_corners` order; v0 is the top edge
+pub fn rect_uv(rect: [f32; 4]) -> [[f32; 2]; 4] {
+    [[rect[0], rect[3]], [rect[2], rect[3]], [rect[0], rect[1]], [rect[2], rect[1]]]
+}
+
+/// append one side of the box `from`..`to`
+pub fn push_face<V: BuildVertex, I: MeshIndex>(mesh: &mut Mesh<V, I>, from: [f32; 3], to: [f32; 3], face: Face, uv: [f32; 4]) -> Result<(), MeshError> {
+    let corners = face_corners(from, to, face);
+    let uvs = rect_uv(uv);
+    let normal = face.normal();
+    let quad: Vec<V> = corners.iter().zip(uvs.iter()).map(|(p, t)| vertex(*p, *t, normal)).collect();
+    mesh.push(&quad, &INDICES4_RECT)?;
+    Ok(())
+}
+
+/// rectangle in the xy plane facing +z
+pub fn quad<V: BuildVertex, I: MeshIndex>(min: [f32; 2], max: [f32; 2], uv: [f32; 4]) -> Result<Mesh<V, I>, MeshError> {
+    let mut mesh: Mesh<V, I> = Mesh::new();
+    push_face(&mut mesh, [min[0], min[1], 0.0], [max[0], max[1], 0.0], Face::North, uv)?;
+    for v in mesh.modify() {
+        v.set_normal([0.0, 0.0, 1.0]);
+    }
+    Ok(mesh)
+}
+
+/// box from `from` to `to` with one uv rect per side, indexed like `Face::ALL`; `None` leaves the side out
+pub fn cuboid<V: BuildVertex, I: MeshIndex>(from: [f32; 3], to: [f32; 3], faces: &[Option<[f32; 4]>; 6]) -> Result<Mesh<V, I>, MeshError> {
+    let mut mesh = Mesh::new();
+    for (face, uv) in Face::ALL.iter().zip(faces.iter()) {
+        if let Some(uv) = uv {
+            push_face(&mut mesh, from, to, *face, *uv)?;
+        }
+    }
+    Ok(mesh)
+}
+
+/// cube of edge `size` around `center`
+pub fn cube<V: BuildVertex, I: MeshIndex>(center: [f32; 3], size: f32, faces: &[[f32; 4]; 6]) -> Result<Mesh<V, I>, MeshError> {
+    let h = size * 0.5;
+    let from = [center[0] - h, center[1] - h, center[2] - h];
+    let to = [center[0] + h, center[1] + h, center[2] + h];
+    let mut all = [None; 6];
+    for (a, f) in all.iter_mut().zip(faces.iter()) {
+        *a = Some(*f);
+    }
+    cuboid(from, to, &all)
+}
+
+/// filled disc in the xy plane, uv mapped from its bounding square
+pub fn circle<V: BuildVertex, I: MeshIndex>(center: [f32; 2], radius: f32, segments: u32) -> Result<Mesh<V, I>, MeshError> {
+    let segments = std::cmp::max(segments, 3);
+    let mut vertices = Vec::with_capacity(segments as usize + 1);
+    let mut indices: Vec<u32> = Vec::with_capacity(segments as usize * 3);
+    vertices.push(vertex([center[0], center[1], 0.0], [0.5, 0.5], [0.0, 0.0, 1.0]));
+    let mut i = 0;
+    while i < segments {
+        let a = 2.0 * PI * i as f32 / segments as f32;
+        let (s, c) = a.sin_cos();
+        vertices.push(vertex([center[0] + c * radius, center[1] + s * radius, 0.0], [0.5 + c * 0.5, 0.5 - s * 0.5], [0.0, 0.0, 1.0]));
+        indices.extend_from_slice(&[0, i + 1, (i + 1) % segments + 1]);
+        i += 1;
+    }
+    let mut mesh = Mesh::new();
+    mesh.push(&vertices, &indices)?;
+    Ok(mesh)
+}
+
+/// band between `inner` and `outer` from angle `start` to `end` (radians, counter clockwise);
+/// u runs along the arc, v from the inner (0) to the outer edge (1)
+pub fn arc<V: BuildVertex, I: MeshIndex>(center: [f32; 2], inner: f32, outer: f32, start: f32, end: f32, segments: u32) -> Result<Mesh<V, I>, MeshError> {
+    let segments = std::cmp::max(segments, 1);
+    let mut vertices = Vec::with_capacity((segments as usize + 1) * 2);
+    let mut indices: Vec<u32> = Vec::with_capacity(segments as usize * 6);
+    let mut i = 0;
+    while i <= segments {
+        let u = i as f32 / segments as f32;
+        let (s, c) = (start + (end - start) * u).sin_cos();
+        vertices.push(vertex([center[0] + c * inner, center[1] + s * inner, 0.0], [u, 0.0], [0.0, 0.0, 1.0]));
+        vertices.push(vertex([center[0] + c * outer, center[1] + s * outer, 0.0], [u, 1.0], [0.0, 0.0, 1.0]));
+        if i < segments {
+            let k = i * 2;
+            indices.extend_from_slice(&[k, k + 2, k + 3, k + 3, k + 1, k]);
+        }
+        i += 1;
+    }
+    let mut mesh = Mesh::new();
+    mesh.push(&vertices, &indices)?;
+    Ok(mesh)
+}
+
+/// full ring, e.g. a hit box outline or an aura
+pub fn ring<V: BuildVertex, I: MeshIndex>(center: [f32; 2], inner: f32, outer: f32, segments: u32) -> Result<Mesh<V, I>, MeshError> {
+    arc(center, inner, outer, 0.0, 2.0 * PI, segments)
+}
+
+/// rectangle in the xy plane split into `cells` quads, uv spanning the whole grid
+pub fn grid<V: BuildVertex, I: MeshIndex>(min: [f32; 2], max: [f32; 2], cells: [u32; 2]) -> Result<Mesh<V, I>, MeshError> {
+    let nx = std::cmp::max(cells[0], 1);
+    let ny = std::cmp::max(cells[1], 1);
+    let mut vertices = Vec::with_capacity(((nx + 1) * (ny + 1)) as usize);
+    let mut indices: Vec<u32> = Vec::with_capacity((nx * ny * 6) as usize);
+    let mut j = 0;
+    while j <= ny {
+        let v = j as f32 / ny as f32;
+        let mut i = 0;
+        while i <= nx {
+            let u = i as f32 / nx as f32;
+            vertices.push(vertex([min[0] + (max[0] - min[0]) * u, min[1] + (max[1] - min[1]) * v, 0.0], [u, 1.0 - v], [0.0, 0.0, 1.0]));
+            if i < nx && j < ny {
+                let k = j * (nx + 1) + i;
+                let up = k + nx + 1;
+                indices.extend_from_slice(&[k, k + 1, up + 1, up + 1, up, k]);
+            }
+            i += 1;
+        }
+        j += 1;
+    }
+    let mut mesh = Mesh::new();
+    mesh.push(&vertices, &indices)?;
+    Ok(mesh)
+}
+
+/// uv sphere; `segments` around the y axis, `rings` from pole to pole
+pub fn sphere<V: BuildVertex, I: MeshIndex>(center: [f32; 3], radius: f32, segments: u32, rings: u32) -> Result<Mesh<V, I>, MeshError> {
+    let segments = std::cmp::max(segments, 3);
+    let rings = std::cmp::max(rings, 2);
+    let mut vertices = Vec::with_capacity(((segments + 1) * (rings + 1)) as usize);
+    let mut indices: Vec<u32> = Vec::with_capacity((segments * rings * 6) as usize);
+    let mut j = 0;
+    while j <= rings {
+        let v = j as f32 / rings as f32;
+        let (sp, cp) = (PI * v).sin_cos();
+        let mut i = 0;
+        while i <= segments {
+            let u = i as f32 / segments as f32;
+            let (st, ct) = (2.0 * PI * u).sin_cos();
+            let n = [sp * ct, cp, sp * st];
+            vertices.push(vertex([center[0] + n[0] * radius, center[1] + n[1] * radius, center[2] + n[2] * radius], [u, v], n));
+            if i < segments && j < rings {
+                let k = j * (segments + 1) + i;
+                let next = k + segments + 1;
+                indices.extend_from_slice(&[k, next, k + 1, k + 1, next, next + 1]);
+            }
+            i += 1;
+        }
+        j += 1;
+    }
+    let mut mesh = Mesh::new();
+    mesh.push(&vertices, &indices)?;
+    Ok(mesh)
+}
+
+/// strip of constant `width` along `points` in the xy plane; u runs along it, v across
+pub fn ribbon<V: BuildVertex, I: MeshIndex>(points: &[[f32; 2]], width: f32) -> Result<Mesh<V, I>, MeshError> {
+    let mut mesh = Mesh::new();
+    let n = points.len();
+    if n < 2 {
+        return Ok(mesh);
+    }
+    let half = width * 0.5;
+    let mut vertices = Vec::with_capacity(n * 2);
+    let mut indices: Vec<u32> = Vec::with_capacity((n - 1) * 6);
+    let mut i = 0;
+    while i < n {
+        // tangent from the neighbours so joints bend smoothly
+        let prev = points[if i > 0 { i - 1 } else { 0 }];
+        let next = points[if i + 1 < n { i + 1 } else { n - 1 }];
+        let t = [next[0] - prev[0], next[1] - prev[1]];
+        let len = (t[0] * t[0] + t[1] * t[1]).sqrt();
+        let normal = if len > 0.0 { [-t[1] / len * half, t[0] / len * half] } else { [0.0, 0.0] };
+        let u = i as f32 / (n - 1) as f32;
+        let p = points[i];
+        vertices.push(vertex([p[0] - normal[0], p[1] - normal[1], 0.0], [u, 1.0], [0.0, 0.0, 1.0]));
+        vertices.push(vertex([p[0] + normal[0], p[1] + normal[1], 0.0], [u, 0.0], [0.0, 0.0, 1.0]));
+        if i + 1 < n {
+            let k = i as u32 * 2;
+            indices.extend_from_slice(&[k, k + 2, k + 3, k + 3, k + 1, k]);
+        }
+        i += 1;
+    }
+    mesh.push(&vertices, &indices)?;
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[derive(Copy, Clone, Default)]
+    struct V {
+        position: [f32; 3],
+        uv: [f32; 2],
+        normal: [f32; 3],
+    }
+
+    implement_vertex!(V, position, uv, normal);
+
+    impl BuildVertex for V {
+
+        fn set_position(&mut self, position: [f32; 3]) {
+            self.position = position;
+        }
+
+        fn set_uv(&mut self, uv: [f32; 2]) {
+            self.uv = uv;
+        }
+
+        fn set_normal(&mut self, normal: [f32; 3]) {
+            self.normal = normal;
+        }
+    }
+
+    #[test]
+    fn cube_faces_point_outwards() {
+        let mesh: Mesh<V> = cube([0.0, 0.0, 0.0], 1.0, &[[0.0, 0.0, 1.0, 1.0]; 6]).unwrap();
+        assert_eq!(mesh.vertices().len(), 24);
+        for v in mesh.vertices() {
+            let d: f32 = v.position.iter().zip(v.normal.iter()).map(|(p, n)| p * n).sum();
+            assert!((d - 0.5).abs() < 1e-6);
+        }
+        let north = face_corners([0.0; 3], [1.0; 3], Face::North);
+        assert_eq!(north, [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [1.0, 1.0, 0.0]]);
+        assert_eq!(Face::from_name("west"), Some(Face::West));
+    }
+
+    #[test]
+    fn round_shapes_lie_on_their_radius() {
+        let ring: Mesh<V> = ring([1.0, 1.0], 2.0, 3.0, 16).unwrap();
+        assert_eq!(ring.vertices().len(), 34);
+        for (i, v) in ring.vertices().iter().enumerate() {
+            let r = ((v.position[0] - 1.0).powi(2) + (v.position[1] - 1.0).powi(2)).sqrt();
+            assert!((r - if i % 2 == 0 { 2.0 } else { 3.0 }).abs() < 1e-5);
+        }
+        let sphere: Mesh<V, u32> = sphere([0.0; 3], 2.0, 8, 4).unwrap();
+        assert!(sphere.vertices().iter().all(|v| (v.position.iter().map(|p| p * p).sum::<f32>().sqrt() - 2.0).abs() < 1e-5));
+        let disc: Mesh<V> = circle([0.0, 0.0], 1.0, 6).unwrap();
+        assert_eq!(disc.vertices().len(), 7);
+    }
+
+    #[test]
+    fn large_grids_need_wide_indices() {
+        assert!(grid::<V, u16>([0.0, 0.0], [1.0, 1.0], [300, 300]).is_err());
+        let big: Mesh<V, u32> = grid([0.0, 0.0], [1.0, 1.0], [300, 300]).unwrap();
+        assert_eq!(big.vertices().len(), 301 * 301);
+        let strip: Mesh<V> = ribbon(&[[0.0, 0.0], [1.0, 0.0], [2.0, 0.0]], 2.0).unwrap();
+        assert_eq!(strip.vertices()[1].position, [0.0, 1.0, 0.0]);
+    }
+}
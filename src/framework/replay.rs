@@ -0,0 +1,455 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::fs::OpenOptions;
+
+use super::util::Resource;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub const REPLAY_MAGIC: [u8; 4] = *b"STGR";
+
+/// bumped whenever the layout below changes
+pub const REPLAY_VERSION: u16 = 1;
+
+/// longest replay accepted by `Replay::read`, a bit over 77 hours at 60 ticks per second;
+/// the checksum only catches accidents, so the header cannot be trusted with an allocation
+pub const MAX_REPLAY_TICKS: u64 = 1 << 24;
+
+/// replays only play back on the engine build that recorded them
+pub const ENGINE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    EngineMismatch { recorded: String, running: String },
+    /// recorded with an input map of a different size
+    ActionMismatch { recorded: u8, running: u8 },
+    Checksum,
+    Corrupt,
+    /// more ticks than `MAX_REPLAY_TICKS`
+    TooLong(u64),
+}
+
+impl fmt::Display for ReplayError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "replay io: {}", e),
+            ReplayError::BadMagic => write!(f, "not a replay file"),
+            ReplayError::UnsupportedVersion(v) => write!(f, "replay format {} is not supported (expected {})", v, REPLAY_VERSION),
+            ReplayError::EngineMismatch { recorded, running } => write!(f, "replay recorded on engine {}, running {}", recorded, running),
+            ReplayError::ActionMismatch { recorded, running } => write!(f, "replay has {} input actions, input map has {}", recorded, running),
+            ReplayError::Checksum => write!(f, "replay checksum mismatch"),
+            ReplayError::Corrupt => write!(f, "replay data is corrupt"),
+            ReplayError::TooLong(ticks) => write!(f, "replay has {} ticks, at most {} are supported", ticks, MAX_REPLAY_TICKS),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<io::Error> for ReplayError {
+
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            ReplayError::Corrupt
+        } else {
+            ReplayError::Io(e)
+        }
+    }
+}
+
+///
+/// per-tick action bits plus what is needed to rebuild the run: rng seed and stage id
+///
+/// layout, little endian: magic, version u16, engine (u8 len + utf8), seed u64, stage (u16 len + utf8),
+/// actions u8, ticks u64, payload (u32 len + run-length pairs of varint bits / varint count), fnv-1a u32 of all of it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+
+    seed: u64,
+
+    stage: String,
+
+    actions: u8,
+
+    engine: String,
+
+    frames: Vec<u64>,
+
+}
+
+impl Replay {
+
+    /// `actions` is the size of the input map the bits refer to
+    pub fn new(seed: u64, stage: &str, actions: usize) -> Self {
+        Replay {
+            seed,
+            stage: stage.to_string(),
+            actions: actions as u8,
+            engine: ENGINE_VERSION.to_string(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn stage(&self) -> &str {
+        &self.stage
+    }
+
+    pub fn actions(&self) -> u8 {
+        self.actions
+    }
+
+    pub fn engine(&self) -> &str {
+        &self.engine
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn push(&mut self, bits: u64) {
+        self.frames.push(bits);
+    }
+
+    pub fn frame(&self, tick: usize) -> Option<u64> {
+        self.frames.get(tick).cloned()
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(64 + self.frames.len() / 4);
+        buf.extend_from_slice(&REPLAY_MAGIC);
+        buf.extend_from_slice(&REPLAY_VERSION.to_le_bytes());
+        let engine = truncate(&self.engine, u8::MAX as usize).as_bytes();
+        buf.push(engine.len() as u8);
+        buf.extend_from_slice(engine);
+        buf.extend_from_slice(&self.seed.to_le_bytes());
+        let stage = truncate(&self.stage, u16::MAX as usize).as_bytes();
+        buf.extend_from_slice(&(stage.len() as u16).to_le_bytes());
+        buf.extend_from_slice(stage);
+        buf.push(self.actions);
+        buf.extend_from_slice(&(self.frames.len() as u64).to_le_bytes());
+
+        let mut payload = Vec::new();
+        let mut i = 0;
+        while i < self.frames.len() {
+            let bits = self.frames[i];
+            let mut run = 1;
+            while i + run < self.frames.len() && self.frames[i + run] == bits {
+                run += 1;
+            }
+            write_varint(&mut payload, bits);
+            write_varint(&mut payload, run as u64);
+            i += run;
+        }
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        let sum = fnv1a(&buf);
+        buf.extend_from_slice(&sum.to_le_bytes());
+        out.write_all(&buf)
+    }
+
+    /// rejects other formats, other engine builds, damaged files and more than `MAX_REPLAY_TICKS` ticks
+    pub fn read<R: Read>(mut input: R) -> std::result::Result<Self, ReplayError> {
+        let mut buf = Vec::new();
+        input.read_to_end(&mut buf)?;
+        if buf.len() < REPLAY_MAGIC.len() + 2 || buf[..4] != REPLAY_MAGIC {
+            return Err(ReplayError::BadMagic);
+        }
+        let version = u16::from_le_bytes([buf[4], buf[5]]);
+        if version != REPLAY_VERSION {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+        if buf.len() < 10 {
+            return Err(ReplayError::Corrupt);
+        }
+        let (body, sum) = buf.split_at(buf.len() - 4);
+        if fnv1a(body) != u32::from_le_bytes([sum[0], sum[1], sum[2], sum[3]]) {
+            return Err(ReplayError::Checksum);
+        }
+
+        let mut r = &body[6..];
+        let engine = read_string(&mut r, 1)?;
+        if engine != ENGINE_VERSION {
+            return Err(ReplayError::EngineMismatch { recorded: engine, running: ENGINE_VERSION.to_string() });
+        }
+        let seed = u64::from_le_bytes(read_array(&mut r)?);
+        let stage = read_string(&mut r, 2)?;
+        let actions = read_array::<1>(&mut r)?[0];
+        let ticks = u64::from_le_bytes(read_array(&mut r)?);
+        if ticks > MAX_REPLAY_TICKS {
+            return Err(ReplayError::TooLong(ticks));
+        }
+        let len = u32::from_le_bytes(read_array(&mut r)?) as usize;
+        if r.len() != len {
+            return Err(ReplayError::Corrupt);
+        }
+
+        // grown by the runs actually decoded, not reserved from the header
+        let mut frames = Vec::new();
+        while !r.is_empty() {
+            let bits = read_varint(&mut r)?;
+            let run = read_varint(&mut r)?;
+            // runs never add up past the tick count, which is already bounded
+            if run == 0 || run > ticks - frames.len() as u64 {
+                return Err(ReplayError::Corrupt);
+            }
+            frames.resize(frames.len() + run as usize, bits);
+        }
+        if frames.len() as u64 != ticks {
+            return Err(ReplayError::Corrupt);
+        }
+        Ok(Replay { seed, stage, actions, engine, frames })
+    }
+
+    pub fn save(&self, resource: &Resource, file: &str) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        let ofile = resource.open(file, options).map_err(Box::new)?;
+        self.write(io::BufWriter::new(ofile)).map_err(Box::new)?;
+        Ok(())
+    }
+
+    pub fn load(resource: &Resource, file: &str) -> Result<Self> {
+        let ifile = resource.open_read_only(file).map_err(Box::new)?;
+        let replay = Self::read(io::BufReader::new(ifile)).map_err(Box::new)?;
+        Ok(replay)
+    }
+}
+
+/// hands recorded action bits back one update tick at a time
+#[derive(Debug, Clone)]
+pub struct ReplayPlayer {
+
+    replay: Replay,
+
+    tick: usize,
+
+}
+
+impl ReplayPlayer {
+
+    /// `actions` is the size of the running input map
+    pub fn new(replay: Replay, actions: usize) -> std::result::Result<Self, ReplayError> {
+        if replay.actions as usize != actions {
+            return Err(ReplayError::ActionMismatch { recorded: replay.actions, running: actions as u8 });
+        }
+        Ok(ReplayPlayer { replay, tick: 0 })
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    pub fn tick(&self) -> usize {
+        self.tick
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.tick >= self.replay.len()
+    }
+
+    /// bits for the next tick, `None` once the recording runs out
+    pub fn next_bits(&mut self) -> Option<u64> {
+        let bits = self.replay.frame(self.tick)?;
+        self.tick += 1;
+        Some(bits)
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for b in data {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(r: &mut &[u8]) -> std::result::Result<u64, ReplayError> {
+    let mut v: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let b = read_array::<1>(r)?[0];
+        // the 10th byte only has room for the top bit
+        if shift == 63 && b > 1 {
+            return Err(ReplayError::Corrupt);
+        }
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+        shift += 7;
+    }
+}
+
+fn read_array<const N: usize>(r: &mut &[u8]) -> std::result::Result<[u8; N], ReplayError> {
+    if r.len() < N {
+        return Err(ReplayError::Corrupt);
+    }
+    let mut a = [0; N];
+    a.copy_from_slice(&r[..N]);
+    *r = &r[N..];
+    Ok(a)
+}
+
+// longest prefix of at most `max` bytes that still ends on a char boundary
+fn truncate(s: &str, max: usize) -> &str {
+    match s.char_indices().map(|(i, c)| i + c.len_utf8()).take_while(|end| *end <= max).last() {
+        Some(end) => &s[..end],
+        None => "",
+    }
+}
+
+// string prefixed by a `width` byte little endian length
+fn read_string(r: &mut &[u8], width: usize) -> std::result::Result<String, ReplayError> {
+    let len = if width == 1 {
+        read_array::<1>(r)?[0] as usize
+    } else {
+        u16::from_le_bytes(read_array(r)?) as usize
+    };
+    if r.len() < len {
+        return Err(ReplayError::Corrupt);
+    }
+    let s = String::from_utf8(r[..len].to_vec()).map_err(|_| ReplayError::Corrupt)?;
+    *r = &r[len..];
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn sample() -> Replay {
+        let mut replay = Replay::new(0xdead_beef, "stage1", 8);
+        for i in 0..500u64 {
+            replay.push(if i % 40 < 25 { 0b1_0001 } else { i & 0x7 });
+        }
+        replay
+    }
+
+    #[test]
+    fn round_trip() {
+        let replay = sample();
+        let mut buf = Vec::new();
+        replay.write(&mut buf).unwrap();
+        assert!(buf.len() < 500);
+        assert_eq!(Replay::read(buf.as_slice()).unwrap(), replay);
+    }
+
+    #[test]
+    fn long_stage_names_are_cut_between_chars() {
+        // 3 byte chars, so u16::MAX falls on a char boundary only after 21845 of them
+        let name = "弾".repeat(30000);
+        let mut buf = Vec::new();
+        Replay::new(1, &name, 8).write(&mut buf).unwrap();
+        let stage = Replay::read(buf.as_slice()).unwrap().stage().to_string();
+        assert_eq!(stage.len(), 21845 * 3);
+        assert!(name.starts_with(&stage));
+        assert_eq!(truncate("aé", 2), "a");
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let mut buf = Vec::new();
+        sample().write(&mut buf).unwrap();
+
+        let mut flipped = buf.clone();
+        flipped[30] ^= 1;
+        assert!(matches!(Replay::read(flipped.as_slice()), Err(ReplayError::Checksum)));
+
+        let mut version = buf.clone();
+        version[4] = 99;
+        assert!(matches!(Replay::read(version.as_slice()), Err(ReplayError::UnsupportedVersion(99))));
+
+        assert!(matches!(Replay::read(&buf[..3]), Err(ReplayError::BadMagic)));
+
+        let mut other = sample();
+        other.engine = "0.0.0-other".to_string();
+        let mut buf = Vec::new();
+        other.write(&mut buf).unwrap();
+        assert!(matches!(Replay::read(buf.as_slice()), Err(ReplayError::EngineMismatch { .. })));
+    }
+
+    #[test]
+    fn player_checks_action_count() {
+        assert!(ReplayPlayer::new(sample(), 7).is_err());
+        let mut player = ReplayPlayer::new(sample(), 8).unwrap();
+        assert_eq!(player.next_bits(), Some(0b1_0001));
+        while player.next_bits().is_some() {}
+        assert!(player.is_finished());
+    }
+
+    // file with a valid checksum around a hand made tick count and payload
+    fn craft(ticks: u64, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Replay::new(1, "x", 8).write(&mut buf).unwrap();
+        // header up to the tick count: magic, version, engine, seed, stage, actions
+        let head = 4 + 2 + 1 + ENGINE_VERSION.len() + 8 + 2 + 1 + 1;
+        buf.truncate(head);
+        buf.extend_from_slice(&ticks.to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(payload);
+        let sum = fnv1a(&buf);
+        buf.extend_from_slice(&sum.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn oversized_headers_are_rejected() {
+        let mut run = Vec::new();
+        write_varint(&mut run, 3);
+        write_varint(&mut run, 1 << 40);
+        assert!(matches!(Replay::read(craft(1 << 40, &run).as_slice()), Err(ReplayError::TooLong(_))));
+        // a run longer than the declared ticks, and one that would wrap around
+        assert!(matches!(Replay::read(craft(10, &run).as_slice()), Err(ReplayError::Corrupt)));
+        let mut wrap = Vec::new();
+        write_varint(&mut wrap, 3);
+        write_varint(&mut wrap, u64::MAX);
+        assert!(matches!(Replay::read(craft(10, &wrap).as_slice()), Err(ReplayError::Corrupt)));
+
+        let mut ok = Vec::new();
+        write_varint(&mut ok, 3);
+        write_varint(&mut ok, 10);
+        assert_eq!(Replay::read(craft(10, &ok).as_slice()).unwrap().frames(), &[3; 10]);
+    }
+
+    #[test]
+    fn varints_past_64_bits_are_rejected() {
+        let mut max = Vec::new();
+        write_varint(&mut max, u64::MAX);
+        assert_eq!(max.len(), 10);
+        assert_eq!(read_varint(&mut max.as_slice()).unwrap(), u64::MAX);
+        let mut over = max.clone();
+        over[9] = 0x02;
+        assert!(matches!(read_varint(&mut over.as_slice()), Err(ReplayError::Corrupt)));
+        over[9] = 0x81;
+        over.push(0x00);
+        assert!(matches!(read_varint(&mut over.as_slice()), Err(ReplayError::Corrupt)));
+    }
+}
@@ -0,0 +1,238 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+
+/// stream id of `Rng::gameplay`
+pub const STREAM_GAMEPLAY: u64 = 1;
+
+/// stream id of `Rng::cosmetic`
+pub const STREAM_COSMETIC: u64 = 2;
+
+/// pcg32 generator; the same seed and stream always give the same numbers on every platform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pcg32 {
+
+    state: u64,
+
+    inc: u64,
+
+}
+
+impl Pcg32 {
+
+    /// streams with the same seed but different ids are independent
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Pcg32 { state: 0, inc: (stream << 1) | 1 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(MULTIPLIER).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+    }
+
+    /// uniform in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+    }
+
+    /// uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+
+    /// uniform in [low, high)
+    pub fn range(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.next_f32()
+    }
+
+    /// uniform integer in [0, n), without modulo bias; 0 when `n` is 0
+    pub fn below(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        let threshold = n.wrapping_neg() % n;
+        loop {
+            let r = self.next_u32();
+            if r >= threshold {
+                return r % n;
+            }
+        }
+    }
+
+    /// true with probability `p`
+    pub fn chance(&mut self, p: f32) -> bool {
+        self.next_f32() < p
+    }
+}
+
+/// state of both streams, for rewinding or syncing a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RngSnapshot {
+
+    seed: u64,
+
+    gameplay: Pcg32,
+
+    cosmetic: Pcg32,
+
+}
+
+///
+/// seeded randomness split in two streams:
+///
+/// `gameplay` drives anything that affects the simulation and is only available during update ticks,
+/// `cosmetic` is for particles and other effects and may be drawn at any rate
+#[derive(Debug, Clone)]
+pub struct Rng {
+
+    seed: u64,
+
+    gameplay: Pcg32,
+
+    cosmetic: Pcg32,
+
+    locked: bool,
+
+    misused: bool,
+
+}
+
+impl Default for Rng {
+
+    fn default() -> Self {
+        Rng::new(0)
+    }
+}
+
+impl Rng {
+
+    pub fn new(seed: u64) -> Self {
+        Rng {
+            seed,
+            gameplay: Pcg32::new(seed, STREAM_GAMEPLAY),
+            cosmetic: Pcg32::new(seed, STREAM_COSMETIC),
+            locked: false,
+            misused: false,
+        }
+    }
+
+    /// seed taken from the clock, for runs that are not replays
+    pub fn from_time() -> Self {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        Rng::new(nanos)
+    }
+
+    /// the seed to store in a replay
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// restart both streams from `seed`
+    pub fn reseed(&mut self, seed: u64) {
+        let (locked, misused) = (self.locked, self.misused);
+        *self = Rng::new(seed);
+        self.locked = locked;
+        self.misused = misused;
+    }
+
+    /// only for update ticks, so gameplay never depends on how often frames are drawn;
+    /// outside of them debug builds panic and release builds hand out the cosmetic stream, see `misused`
+    pub fn gameplay(&mut self) -> &mut Pcg32 {
+        if self.locked {
+            debug_assert!(false, "gameplay rng used outside an update tick, use the cosmetic stream when rendering");
+            self.misused = true;
+            return &mut self.cosmetic;
+        }
+        &mut self.gameplay
+    }
+
+    /// whether `gameplay` was ever asked for outside an update tick
+    pub fn misused(&self) -> bool {
+        self.misused
+    }
+
+    pub fn cosmetic(&mut self) -> &mut Pcg32 {
+        &mut self.cosmetic
+    }
+
+    pub fn snapshot(&self) -> RngSnapshot {
+        RngSnapshot { seed: self.seed, gameplay: self.gameplay, cosmetic: self.cosmetic }
+    }
+
+    pub fn restore(&mut self, snapshot: &RngSnapshot) {
+        self.seed = snapshot.seed;
+        self.gameplay = snapshot.gameplay;
+        self.cosmetic = snapshot.cosmetic;
+    }
+
+    /// set by the schedulers around render calls and frame-rate event handling
+    pub fn set_gameplay_locked(&mut self, locked: bool) {
+        self.locked = locked;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let sa: Vec<u32> = (0..8).map(|_| a.gameplay().next_u32()).collect();
+        // drawing cosmetic numbers must not shift the gameplay stream
+        b.cosmetic().next_u32();
+        let sb: Vec<u32> = (0..8).map(|_| b.gameplay().next_u32()).collect();
+        assert_eq!(sa, sb);
+        assert_ne!(Rng::new(43).gameplay().next_u32(), sa[0]);
+    }
+
+    #[test]
+    fn snapshot_restores_streams() {
+        let mut rng = Rng::new(7);
+        rng.gameplay().next_u64();
+        let snap = rng.snapshot();
+        let x = rng.gameplay().range(-1.0, 1.0);
+        rng.restore(&snap);
+        assert_eq!(rng.gameplay().range(-1.0, 1.0), x);
+        assert!((-1.0..1.0).contains(&x));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn gameplay_is_locked_while_rendering() {
+        let mut rng = Rng::new(1);
+        rng.set_gameplay_locked(true);
+        rng.gameplay();
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn locked_gameplay_falls_back_to_cosmetic() {
+        let mut rng = Rng::new(1);
+        let expected = Rng::new(1).cosmetic().next_u32();
+        rng.set_gameplay_locked(true);
+        assert_eq!(rng.gameplay().next_u32(), expected);
+        assert!(rng.misused());
+    }
+
+    #[test]
+    fn below_stays_in_range() {
+        let mut rng = Pcg32::new(3, 9);
+        assert!((0..1000).all(|_| rng.below(7) < 7));
+        assert_eq!(rng.below(0), 0);
+    }
+}
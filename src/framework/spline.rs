@@ -1,6 +1,12 @@
 
 use std::vec::Vec;
+use std::fmt;
 use num_traits::float::Float;
+use glium::Vertex;
+use glium::index::PrimitiveType;
+
+use super::mesh::Mesh;
+use super::interpolate::Interpolator;
 
 
 pub struct CubeSpline<T> {
@@ -13,16 +19,302 @@ pub struct CubeSpline<T> {
 
     dim: usize,
 
-    c_ind: usize,
+    // (a, b, c, d) per segment and dimension: y = a + b dx + c dx^2 + d dx^3
+    coef: Vec<(T,T,T,T)>,
+
+    periodic: bool,
+
+}
+
+/// cumulative arc length sampled along the parameter: (x, s) pairs in increasing order
+#[derive(Debug, Clone)]
+pub struct ArcLength<T> {
+
+    table: Vec<(T, T)>,
+
+}
+
+impl<T: Float> ArcLength<T> {
+
+    /// tabulate the arc length of any curve, `subdiv` quadrature intervals per knot interval
+    pub fn new<I: Interpolator<T> + ?Sized>(curve: &I, subdiv: usize) -> Result<Self, CubeSplineError> {
+        let knots = curve.knots();
+        if knots.len() < 2 {
+            return Err(CubeSplineError::EvalUncompiled);
+        }
+        let subdiv = std::cmp::max(subdiv, 1);
+        let n = knots.len() - 1;
+        let mut table = Vec::with_capacity(n * subdiv + 1);
+        let mut s = T::zero();
+        let mut d = vec![T::zero(); curve.dim()];
+        table.push((knots[0], s));
+        let mut i = 0;
+        while i < n {
+            let x0 = knots[i];
+            let h = (knots[i + 1] - x0) / T::from(subdiv).unwrap();
+            let mut k = 0;
+            while k < subdiv {
+                let a = x0 + h * T::from(k).unwrap();
+                let b = if k + 1 == subdiv { knots[i + 1] } else { a + h };
+                s = s + speed_integral(curve, a, b, &mut d)?;
+                table.push((b, s));
+                k += 1;
+            }
+            i += 1;
+        }
+        Ok(ArcLength { table })
+    }
+
+    /// parameter of `curve` at arc length `s` from the start, refined by newton steps on the curve itself
+    pub fn param_at<I: Interpolator<T> + ?Sized>(&self, curve: &I, s: T) -> Result<T, CubeSplineError> {
+        if !s.is_finite() {
+            return Err(CubeSplineError::EvalNotFinite);
+        }
+        let t = &self.table;
+        let last = t.len() - 1;
+        if s <= t[0].1 {
+            return Ok(t[0].0);
+        }
+        if s >= t[last].1 {
+            return Ok(t[last].0);
+        }
+        let mut low = 0;
+        let mut high = last;
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if t[mid].1 > s {
+                high = mid;
+            } else {
+                low = mid;
+            }
+        }
+        let (xa, sa) = t[low];
+        let (xb, sb) = t[high];
+        if sb <= sa {
+            return Ok(xa);
+        }
+        let mut x = xa + (s - sa) / (sb - sa) * (xb - xa);
+        let mut d = vec![T::zero(); curve.dim()];
+        // two steps are enough on smooth stretches, slow turns near a cusp need a few more
+        let tol = T::epsilon() * (T::one() + s);
+        let mut iter = 0;
+        while iter < 8 {
+            let g = sa + speed_integral(curve, xa, x, &mut d)? - s;
+            if g.abs() <= tol {
+                break;
+            }
+            curve.get_derivative(x, &mut d)?;
+            let v = norm(&d);
+            if v <= T::epsilon() {
+                break;
+            }
+            x = (x - g / v).max(xa).min(xb);
+            iter += 1;
+        }
+        Ok(x)
+    }
+
+    /// arc length of `curve` from the start to parameter `x`
+    pub fn length_at<I: Interpolator<T> + ?Sized>(&self, curve: &I, x: T) -> Result<T, CubeSplineError> {
+        if !x.is_finite() {
+            return Err(CubeSplineError::EvalNotFinite);
+        }
+        let t = &self.table;
+        let last = t.len() - 1;
+        if x <= t[0].0 {
+            return Ok(t[0].1);
+        }
+        if x >= t[last].0 {
+            return Ok(t[last].1);
+        }
+        let mut low = 0;
+        let mut high = last;
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if t[mid].0 > x {
+                high = mid;
+            } else {
+                low = mid;
+            }
+        }
+        let (xa, sa) = t[low];
+        let mut d = vec![T::zero(); curve.dim()];
+        Ok(sa + speed_integral(curve, xa, x, &mut d)?)
+    }
+
+    pub fn total(&self) -> T {
+        self.table.last().map_or(T::zero(), |(_, s)| *s)
+    }
+
+    pub fn table(&self) -> &[(T, T)] {
+        self.table.as_slice()
+    }
+}
+
+fn norm<T: Float>(v: &[T]) -> T {
+    v.iter().fold(T::zero(), |acc, x| acc + *x * *x).sqrt()
+}
+
+// 5 point gauss-legendre integral of |dy/dx| over [a, b]
+fn speed_integral<T: Float, I: Interpolator<T> + ?Sized>(curve: &I, a: T, b: T, d: &mut [T]) -> Result<T, CubeSplineError> {
+    const NODES: [(f64, f64); 5] = [
+        (0.0, 0.568_888_888_888_888_9),
+        (-0.538_469_310_105_683, 0.478_628_670_499_366_5),
+        (0.538_469_310_105_683, 0.478_628_670_499_366_5),
+        (-0.906_179_845_938_664, 0.236_926_885_056_189_1),
+        (0.906_179_845_938_664, 0.236_926_885_056_189_1),
+    ];
+    let _2 = T::one() + T::one();
+    let half = (b - a) / _2;
+    let mid = (a + b) / _2;
+    let mut sum = T::zero();
+    for (node, weight) in NODES.iter() {
+        let x = mid + half * T::from(*node).unwrap();
+        curve.get_derivative(x, d)?;
+        sum = sum + T::from(*weight).unwrap() * norm(d);
+    }
+    Ok(sum * half)
+}
+
+/// end conditions used by `CubeSpline::compile_with`
+#[derive(Debug, Clone)]
+pub enum Boundary<T> {
+    /// zero second derivative at both ends
+    Natural,
+    /// given first derivatives at the start and the end, one value per dimension
+    Clamped(Vec<T>, Vec<T>),
+    /// value, first and second derivative match across the seam; x outside the knots wraps around
+    Periodic,
+    /// third derivative is continuous at the second and the second to last knot
+    NotAKnot,
+}
 
-    c_mat: Vec<(T,T,T,T)>,
+// thomas algorithm; sub[i] / sup[i] multiply x[i-1] / x[i+1] in row i, solution is left in rhs
+fn solve_tridiag<T: Float>(sub: &[T], diag: &mut [T], sup: &[T], rhs: &mut [T]) {
+    let n = rhs.len();
+    let mut i = 1;
+    while i < n {
+        let r = sub[i] / diag[i - 1];
+        diag[i] = diag[i] - r * sup[i - 1];
+        rhs[i] = rhs[i] - r * rhs[i - 1];
+        i += 1;
+    }
+    rhs[n - 1] = rhs[n - 1] / diag[n - 1];
+    i = n - 1;
+    while i > 0 {
+        i -= 1;
+        rhs[i] = (rhs[i] - sup[i] * rhs[i + 1]) / diag[i];
+    }
+}
 
+// sherman-morrison on top of `solve_tridiag`; sub[0] multiplies x[n-1] and sup[n-1] multiplies x[0]
+fn solve_cyclic_tridiag<T: Float>(sub: &[T], diag: &mut [T], sup: &[T], rhs: &mut [T]) {
+    let n = rhs.len();
+    let alpha = sub[0];
+    let beta = sup[n - 1];
+    let gamma = -diag[0];
+    diag[0] = diag[0] - gamma;
+    diag[n - 1] = diag[n - 1] - alpha * beta / gamma;
+    let mut u = vec![T::zero(); n];
+    u[0] = gamma;
+    u[n - 1] = beta;
+    let mut diag_u = diag.to_vec();
+    solve_tridiag(sub, diag, sup, rhs);
+    solve_tridiag(sub, &mut diag_u, sup, &mut u);
+    let factor = (rhs[0] + alpha * rhs[n - 1] / gamma) / (T::one() + u[0] + alpha * u[n - 1] / gamma);
+    let mut i = 0;
+    while i < n {
+        rhs[i] = rhs[i] - factor * u[i];
+        i += 1;
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum CubeSplineError {
-    GenInvalidInput,
-    GenUnordered,
+    /// fewer knots than the boundary condition needs
+    GenTooFewPoints { required: usize, found: usize },
+    /// value count is not a non-zero multiple of the knot count
+    GenDimensionMismatch { knots: usize, values: usize },
+    /// knot `index` is smaller than the one before it
+    GenUnordered { index: usize },
+    /// knot `index` repeats the one before it
+    GenDuplicate { index: usize },
+    /// knot `index` is nan or infinite
+    GenKnotNotFinite { index: usize },
+    /// value `index` (flat index into the value list) is nan or infinite
+    GenValueNotFinite { index: usize },
+    /// clamped end derivatives do not have one value per dimension, or are not finite
+    GenBoundaryMismatch { dim: usize, start: usize, end: usize },
+    /// periodic spline whose last point does not repeat the first
+    GenNotClosed,
+    /// evaluation before a successful `compile`
+    EvalUncompiled,
+    /// evaluation at a nan or infinite parameter
+    EvalNotFinite,
+    /// batch output buffer shorter than `dim` values per parameter
+    EvalBufferTooSmall { required: usize, found: usize },
+}
+
+impl fmt::Display for CubeSplineError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CubeSplineError::GenTooFewPoints { required, found } => write!(f, "spline needs at least {} knots, got {}", required, found),
+            CubeSplineError::GenDimensionMismatch { knots, values } => write!(f, "{} values cannot be split evenly over {} knots", values, knots),
+            CubeSplineError::GenUnordered { index } => write!(f, "knot {} is smaller than knot {}", index, index - 1),
+            CubeSplineError::GenDuplicate { index } => write!(f, "knot {} duplicates knot {}", index, index - 1),
+            CubeSplineError::GenKnotNotFinite { index } => write!(f, "knot {} is not a finite number", index),
+            CubeSplineError::GenValueNotFinite { index } => write!(f, "value {} is not a finite number", index),
+            CubeSplineError::GenBoundaryMismatch { dim, start, end } => write!(f, "clamped boundary needs {} finite derivatives at each end, got {} and {}", dim, start, end),
+            CubeSplineError::GenNotClosed => write!(f, "periodic spline must end where it starts"),
+            CubeSplineError::EvalUncompiled => write!(f, "spline evaluated before compile"),
+            CubeSplineError::EvalNotFinite => write!(f, "spline evaluated at a non-finite parameter"),
+            CubeSplineError::EvalBufferTooSmall { required, found } => write!(f, "batch output needs {} values, buffer holds {}", required, found),
+        }
+    }
+}
+
+impl std::error::Error for CubeSplineError {}
+
+// `count` evenly spaced values from `a` to exactly `b`
+fn spread<T: Float>(a: T, b: T, count: usize) -> Vec<T> {
+    let mut v = Vec::with_capacity(count);
+    let last = if count > 1 { count - 1 } else { 1 };
+    let step = (b - a) / T::from(last).unwrap();
+    let mut i = 0;
+    while i < count {
+        v.push(if i == last { b } else { a + step * T::from(i).unwrap() });
+        i += 1;
+    }
+    v
+}
+
+/// knots must be finite and strictly increasing, values finite and `dim` per knot; returns `dim`
+pub(super) fn validate<T: Float>(x_list: &[T], y_list: &[T], min_knots: usize) -> Result<usize, CubeSplineError> {
+    if x_list.len() < min_knots {
+        return Err(CubeSplineError::GenTooFewPoints { required: min_knots, found: x_list.len() });
+    }
+    let dim = y_list.len() / x_list.len();
+    if dim == 0 || dim * x_list.len() != y_list.len() {
+        return Err(CubeSplineError::GenDimensionMismatch { knots: x_list.len(), values: y_list.len() });
+    }
+    let mut i = 0;
+    while i < x_list.len() {
+        if !x_list[i].is_finite() {
+            return Err(CubeSplineError::GenKnotNotFinite { index: i });
+        }
+        if i > 0 && x_list[i] == x_list[i - 1] {
+            return Err(CubeSplineError::GenDuplicate { index: i });
+        }
+        if i > 0 && x_list[i] < x_list[i - 1] {
+            return Err(CubeSplineError::GenUnordered { index: i });
+        }
+        i += 1;
+    }
+    if let Some(index) = y_list.iter().position(|y| !y.is_finite()) {
+        return Err(CubeSplineError::GenValueNotFinite { index });
+    }
+    Ok(dim)
 }
 
 
@@ -35,8 +327,8 @@ impl<T: Float> CubeSpline<T> {
             y_list: Vec::new(),
             m_list: Vec::new(),
             dim: 0,
-            c_ind: 0,
-            c_mat: Vec::new(),
+            coef: Vec::new(),
+            periodic: false,
         }
     }
 
@@ -44,50 +336,112 @@ impl<T: Float> CubeSpline<T> {
     /// x_list: vec n x 1   [x1, x2, ...]
     /// 
     /// y_list: vec n * d   [[y1,y2,...,yd], [y1,y2,...,yd], ...]
-    pub fn compile(mut self, x_list: Vec<T>, y_list: Vec<T>) -> Result<Self, CubeSplineError> {
+    pub fn compile(self, x_list: Vec<T>, y_list: Vec<T>) -> Result<Self, CubeSplineError> {
+        self.compile_with(x_list, y_list, Boundary::Natural)
+    }
+
+    ///
+    /// same as `compile` with a choice of end conditions;
+    /// 
+    /// `Periodic` requires the last point to repeat the first, `Periodic` and `NotAKnot` need at least 4 points
+    pub fn compile_with(mut self, x_list: Vec<T>, y_list: Vec<T>, boundary: Boundary<T>) -> Result<Self, CubeSplineError> {
+        let min_knots = match &boundary {
+            Boundary::Periodic | Boundary::NotAKnot => 4,
+            _ => 3,
+        };
+        self.dim = validate(&x_list, &y_list, min_knots)?;
         let n = x_list.len() - 1;
-        self.dim = y_list.len() / x_list.len();
-        if x_list.len() < 3 || x_list.len() * self.dim < y_list.len() {
-            return Err(CubeSplineError::GenInvalidInput);
+        match &boundary {
+            Boundary::Clamped(start, end) if start.len() != self.dim || end.len() != self.dim || start.iter().chain(end.iter()).any(|v| !v.is_finite()) => {
+                return Err(CubeSplineError::GenBoundaryMismatch { dim: self.dim, start: start.len(), end: end.len() });
+            }
+            Boundary::Periodic if y_list[..self.dim] != y_list[n * self.dim..] => {
+                return Err(CubeSplineError::GenNotClosed);
+            }
+            _ => {}
         }
-        
+
         let mut i: usize;
         let mut j: usize;
 
         let _2 = T::one() + T::one();
         let _6 = _2 + _2 + _2;
 
+        let mut h = Vec::with_capacity(n);
+        i = 0;
+        while i < n {
+            h.push(x_list[i + 1] - x_list[i]);
+            i += 1;
+        }
+
+        self.m_list.clear();
         self.m_list.resize(self.dim * (n + 1), T::zero());
 
+        let mut d = vec![T::zero(); n];
+        let mut sub = vec![T::zero(); n + 1];
+        let mut diag = vec![T::zero(); n + 1];
+        let mut sup = vec![T::zero(); n + 1];
+        let mut rhs = vec![T::zero(); n + 1];
         j = 0;
         while j < self.dim {
-            let len_tmat = 3 * (n + 1) - 2;
-            let mut tmat = Vec::new();
-            tmat.resize(len_tmat, T::zero());
-            let len_dvec = n + 1;
-            let mut dvec = Vec::new();
-            dvec.resize(len_dvec, T::zero());
+            i = 0;
+            while i < n {
+                d[i] = (y_list[(i + 1) * self.dim + j] - y_list[i * self.dim + j]) / h[i];
+                i += 1;
+            }
+            // interior rows: h[i-1] * m[i-1] + 2 (h[i-1] + h[i]) * m[i] + h[i] * m[i+1] = 6 (d[i] - d[i-1])
             i = 1;
-            let mut h_ = x_list[1] - x_list[0];
-            let mut d_ = (y_list[self.dim + j] - y_list[j]) / h_;
-            tmat[0] = T::one();
-            tmat[len_tmat - 1] = T::one();
             while i < n {
-                let h = x_list[i + 1] - x_list[i + 0];
-                let d = (y_list[(i + 1) * self.dim + j] - y_list[i * self.dim + j]) / h;
-                let i3 = i * 3;
-                tmat[i3] = _2 * (h_ + h);
-                tmat[i3 - 1] = h_;
-                tmat[i3 + 1] = h;
-                dvec[i] = _6 * (d - d_);
+                sub[i] = h[i - 1];
+                diag[i] = _2 * (h[i - 1] + h[i]);
+                sup[i] = h[i];
+                rhs[i] = _6 * (d[i] - d[i - 1]);
                 i += 1;
-                h_ = h;
-                d_ = d;
             }
-            self.solve_tridiag_mat(&mut tmat[3..len_tmat-3], &mut dvec[1..len_dvec-1]);
+            match &boundary {
+                Boundary::Natural => {
+                    diag[0] = T::one();
+                    sup[0] = T::zero();
+                    rhs[0] = T::zero();
+                    sub[n] = T::zero();
+                    diag[n] = T::one();
+                    rhs[n] = T::zero();
+                    solve_tridiag(&sub, &mut diag, &sup, &mut rhs);
+                }
+                Boundary::Clamped(start, end) => {
+                    diag[0] = _2 * h[0];
+                    sup[0] = h[0];
+                    rhs[0] = _6 * (d[0] - start[j]);
+                    sub[n] = h[n - 1];
+                    diag[n] = _2 * h[n - 1];
+                    rhs[n] = _6 * (end[j] - d[n - 1]);
+                    solve_tridiag(&sub, &mut diag, &sup, &mut rhs);
+                }
+                Boundary::NotAKnot => {
+                    // eliminate m[0] and m[n] using third derivative continuity at x[1] and x[n-1]
+                    let (h0, h1) = (h[0], h[1]);
+                    diag[1] = (h0 + h1) * (h0 + _2 * h1) / h1;
+                    sup[1] = (h1 * h1 - h0 * h0) / h1;
+                    let (hp, hl) = (h[n - 2], h[n - 1]);
+                    diag[n - 1] = (hp + hl) * (_2 * hp + hl) / hp;
+                    sub[n - 1] = (hp * hp - hl * hl) / hp;
+                    solve_tridiag(&sub[1..n], &mut diag[1..n], &sup[1..n], &mut rhs[1..n]);
+                    rhs[0] = ((h0 + h1) * rhs[1] - h0 * rhs[2]) / h1;
+                    rhs[n] = ((hp + hl) * rhs[n - 1] - hl * rhs[n - 2]) / hp;
+                }
+                Boundary::Periodic => {
+                    // m[n] = m[0]; row 0 wraps around to m[n-1] and row n-1 to m[0]
+                    sub[0] = h[n - 1];
+                    diag[0] = _2 * (h[n - 1] + h[0]);
+                    sup[0] = h[0];
+                    rhs[0] = _6 * (d[0] - d[n - 1]);
+                    solve_cyclic_tridiag(&sub[..n], &mut diag[..n], &sup[..n], &mut rhs[..n]);
+                    rhs[n] = rhs[0];
+                }
+            }
             i = 0;
             while i <= n {
-                self.m_list[i * self.dim + j] = dvec[i];
+                self.m_list[i * self.dim + j] = rhs[i];
                 i += 1;
             }
             j += 1;
@@ -95,94 +449,252 @@ impl<T: Float> CubeSpline<T> {
 
         self.x_list = x_list;
         self.y_list = y_list;
+        self.periodic = matches!(boundary, Boundary::Periodic);
 
-        self.c_mat.resize(self.dim, (T::zero(), T::zero(), T::zero(), T::zero()));
-        self.c_ind = 0;
-        self.cal_cache();
+        self.cal_coef();
 
         Ok(self)
     }
 
-    pub fn get(&mut self, x: T, y: &mut [T]) -> usize {
+    pub fn is_compiled(&self) -> bool {
+        !self.x_list.is_empty()
+    }
+
+    /// value at x; returns the number of dimensions written to y
+    pub fn get(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        self.check_eval(x)?;
+        let x = self.wrap(x);
+        Ok(self.eval(self.segment(x), x, y))
+    }
+
+    pub fn get_derivative(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        self.check_eval(x)?;
+        let x = self.wrap(x);
+        Ok(self.eval_derivative(self.segment(x), x, y))
+    }
+
+    pub fn get_derivative2(&self, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        self.check_eval(x)?;
+        let x = self.wrap(x);
+        Ok(self.eval_derivative2(self.segment(x), x, y))
+    }
 
-        let mut x0 = self.x_list[self.c_ind];
-        if x < x0 || x >= self.x_list[self.c_ind + 1] {
-            x0 = self.binary_search(x);
-            self.cal_cache();
+    ///
+    /// values at every parameter of `x_list`, written `dim` per point into `out`;
+    /// 
+    /// ascending parameters are evaluated in one pass, each run of points sharing a segment at a time
+    pub fn get_many(&self, x_list: &[T], out: &mut [T]) -> Result<usize, CubeSplineError> {
+        self.eval_many(x_list, out, 0)
+    }
+
+    pub fn get_derivative_many(&self, x_list: &[T], out: &mut [T]) -> Result<usize, CubeSplineError> {
+        self.eval_many(x_list, out, 1)
+    }
+
+    pub fn get_derivative2_many(&self, x_list: &[T], out: &mut [T]) -> Result<usize, CubeSplineError> {
+        self.eval_many(x_list, out, 2)
+    }
+
+    /// `count` points evenly spaced in the parameter from the first to the last knot, `dim` values each
+    pub fn resample(&self, count: usize) -> Result<Vec<T>, CubeSplineError> {
+        if !self.is_compiled() {
+            return Err(CubeSplineError::EvalUncompiled);
         }
+        let x0 = self.x_list[0];
+        let x1 = self.x_list[self.x_list.len() - 1];
+        let x_list = spread(x0, x1, count);
+        let mut out = vec![T::zero(); count * self.dim];
+        self.get_many(&x_list, &mut out)?;
+        Ok(out)
+    }
 
-        let dx = x - x0;
+    /// `count` points evenly spaced in arc length along `table`, `dim` values each
+    pub fn resample_by_length(&self, table: &ArcLength<T>, count: usize) -> Result<Vec<T>, CubeSplineError> {
+        if !self.is_compiled() {
+            return Err(CubeSplineError::EvalUncompiled);
+        }
+        let mut x_list = Vec::with_capacity(count);
+        for s in spread(T::zero(), table.total(), count) {
+            x_list.push(self.param_at_length(table, s)?);
+        }
+        let mut out = vec![T::zero(); count * self.dim];
+        self.get_many(&x_list, &mut out)?;
+        Ok(out)
+    }
+
+    /// line strip through `count` evenly spaced points, `vertex` turning each point into a vertex
+    pub fn line_strip<V: Vertex, F: FnMut(&[T]) -> V>(&self, count: usize, vertex: F) -> Result<Mesh<V>, CubeSplineError> {
+        let points = self.resample(count)?;
+        let vertices = points.chunks(self.dim).map(vertex).collect();
+        Ok(Mesh::wrap_noind(vertices, PrimitiveType::LineStrip))
+    }
+
+    fn check_eval(&self, x: T) -> Result<(), CubeSplineError> {
+        if !self.is_compiled() {
+            return Err(CubeSplineError::EvalUncompiled);
+        }
+        if !x.is_finite() {
+            return Err(CubeSplineError::EvalNotFinite);
+        }
+        Ok(())
+    }
+
+    fn eval_many(&self, x_list: &[T], out: &mut [T], order: u8) -> Result<usize, CubeSplineError> {
+        if !self.is_compiled() {
+            return Err(CubeSplineError::EvalUncompiled);
+        }
+        let dim = self.dim;
+        let required = x_list.len() * dim;
+        if out.len() < required {
+            return Err(CubeSplineError::EvalBufferTooSmall { required, found: out.len() });
+        }
+        if x_list.iter().any(|x| !x.is_finite()) {
+            return Err(CubeSplineError::EvalNotFinite);
+        }
+
+        let _2 = T::one() + T::one();
+        let _3 = _2 + T::one();
+        let _6 = _3 * _2;
+
+        let mut seg = 0;
+        let mut i = 0;
+        while i < x_list.len() {
+            seg = self.segment_near(seg, self.wrap(x_list[i]));
+            let x0 = self.x_list[seg];
+            let coef = &self.coef[seg * dim..(seg + 1) * dim];
+            // coefficients stay put for the whole run, the inner loop is a straight pass over `dim` lanes
+            let start = i;
+            while i < x_list.len() {
+                let x = self.wrap(x_list[i]);
+                if i > start && !self.in_segment(seg, x) {
+                    break;
+                }
+                let dx = x - x0;
+                let dx2 = dx * dx;
+                let dx3 = dx2 * dx;
+                let row = &mut out[i * dim..(i + 1) * dim];
+                match order {
+                    0 => for (y, &(a, b, c, d)) in row.iter_mut().zip(coef) {
+                        *y = a + b * dx + c * dx2 + d * dx3;
+                    },
+                    1 => for (y, &(_a, b, c, d)) in row.iter_mut().zip(coef) {
+                        *y = b  + _2 * c * dx + _3 * d * dx2;
+                    },
+                    _ => for (y, &(_a, _b, c, d)) in row.iter_mut().zip(coef) {
+                        *y = _2 * c + _6 * d * dx;
+                    },
+                }
+                i += 1;
+            }
+        }
+        Ok(x_list.len())
+    }
+
+    // whether `segment(x)` would return `seg`
+    fn in_segment(&self, seg: usize, x: T) -> bool {
+        let last = self.x_list.len() - 2;
+        (seg == 0 || x >= self.x_list[seg]) && (seg == last || x < self.x_list[seg + 1])
+    }
+
+    fn eval(&self, seg: usize, x: T, y: &mut [T]) -> usize {
+        let dx = x - self.x_list[seg];
         let dx2 = dx * dx;
         let dx3 = dx2 * dx;
 
+        let coef = &self.coef[seg * self.dim..];
         let mut j = 0;
         let dim = std::cmp::min(self.dim, y.len());
         while j < dim {
-            let (a, b, c, d) = self.c_mat[j];
+            let (a, b, c, d) = coef[j];
             y[j] = a + b * dx + c * dx2 + d * dx3;
             j += 1;
         }
         dim
     }
 
-    pub fn get_derivative(&mut self, x: T, y: &mut [T]) -> usize {
-        let mut x0 = self.x_list[self.c_ind];
-        if x < x0 || x >= self.x_list[self.c_ind + 1] {
-            x0 = self.binary_search(x);
-            self.cal_cache();
-        }
-
-        let dx = x - x0;
+    fn eval_derivative(&self, seg: usize, x: T, y: &mut [T]) -> usize {
+        let dx = x - self.x_list[seg];
         let dx2 = dx * dx;
-        //let dx3 = dx2 * dx;
 
         let _2 = T::one() + T::one();
         let _3 = _2 + T::one();
 
+        let coef = &self.coef[seg * self.dim..];
         let mut j = 0;
         let dim = std::cmp::min(self.dim, y.len());
         while j < dim {
-            let (_a, b, c, d) = self.c_mat[j];
+            let (_a, b, c, d) = coef[j];
             y[j] = b  + _2 * c * dx + _3 * d * dx2;
             j += 1;
         }
         dim
     }
 
-    pub fn get_derivative2(&mut self, x: T, y: &mut [T]) -> usize {
-        let mut x0 = self.x_list[self.c_ind];
-        if x < x0 || x >= self.x_list[self.c_ind + 1] {
-            x0 = self.binary_search(x);
-            self.cal_cache();
-        }
-
-        let dx = x - x0;
-        //let dx2 = dx * dx;
-        //let dx3 = dx2 * dx;
+    fn eval_derivative2(&self, seg: usize, x: T, y: &mut [T]) -> usize {
+        let dx = x - self.x_list[seg];
 
         let _2 = T::one() + T::one();
         let _6 = (_2 + T::one()) * _2;
 
+        let coef = &self.coef[seg * self.dim..];
         let mut j = 0;
         let dim = std::cmp::min(self.dim, y.len());
         while j < dim {
-            let (_a, _b, c, d) = self.c_mat[j];
+            let (_a, _b, c, d) = coef[j];
             y[j] = _2 * c + _6 * d * dx;
             j += 1;
         }
         dim
     }
 
-    fn binary_search(&mut self, x: T) -> T {
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn knots(&self) -> &[T] {
+        self.x_list.as_slice()
+    }
+
+    /// tabulate arc length against the parameter, `subdiv` quadrature intervals per segment
+    pub fn arc_length(&self, subdiv: usize) -> Result<ArcLength<T>, CubeSplineError> {
+        if !self.is_compiled() {
+            return Err(CubeSplineError::EvalUncompiled);
+        }
+        ArcLength::new(self, subdiv)
+    }
+
+    /// parameter at arc length `s` from the start, see `ArcLength::param_at`
+    pub fn param_at_length(&self, table: &ArcLength<T>, s: T) -> Result<T, CubeSplineError> {
+        self.check_eval(s)?;
+        table.param_at(self, s)
+    }
+
+    /// arc length from the start to parameter `x`, the inverse of `param_at_length`
+    pub fn length_at(&self, table: &ArcLength<T>, x: T) -> Result<T, CubeSplineError> {
+        self.check_eval(x)?;
+        table.length_at(self, x)
+    }
+
+    // periodic splines repeat outside the knots
+    fn wrap(&self, x: T) -> T {
+        if !self.periodic {
+            return x;
+        }
+        let x0 = self.x_list[0];
+        let period = self.x_list[self.x_list.len() - 1] - x0;
+        let r = (x - x0) % period;
+        if r < T::zero() { x0 + r + period } else { x0 + r }
+    }
+
+    // segment containing x, clamped to the first / last one
+    fn segment(&self, x: T) -> usize {
         let mut low = 1;
         if x < self.x_list[low] {
-            self.c_ind = 0;
-            return self.x_list[self.c_ind];
+            return 0;
         }
         let mut high = self.x_list.len() - 2;
         if x > self.x_list[high] {
-            self.c_ind = high;
-            return self.x_list[self.c_ind];
+            return high;
         }
         while high - low > 1 {
             let mid = (low + high) / 2;
@@ -198,55 +710,48 @@ impl<T: Float> CubeSpline<T> {
             low = mid;
             break;
         }
-        self.c_ind = low;
-        return self.x_list[self.c_ind];
+        low
     }
 
-    fn solve_tridiag_mat(&self, tmat: &mut [T], dvec: &mut [T]) {
-        let mut i = 3;
-        let mut j = 1;
-        while i < tmat.len() {
-            let r = tmat[i - 1] / tmat[i - 3];
-            //tmat[i - 1] = T::zero();
-            tmat[i] = tmat[i] - r * tmat[i - 2];
-            //tmat[i + 1] = tmat[i + 1];
-            dvec[j] = dvec[j] - r * dvec[j - 1];
-            i += 3;
-            j += 1;
+    // like `segment`, trying `hint` and its successor first
+    fn segment_near(&self, hint: usize, x: T) -> usize {
+        let n = self.x_list.len() - 1;
+        if hint < n && x >= self.x_list[hint] && (x < self.x_list[hint + 1] || hint == n - 1) {
+            return hint;
         }
-        i -= 3;
-        j -= 1;
-        dvec[j] = dvec[j] / tmat[i];
-        loop {
-            i -= 3;
-            j -= 1;
-            dvec[j] = (dvec[j] - tmat[i + 1] * dvec[j + 1]) / tmat[i];
-
-            if i == 0 { break; }
-        } 
-
+        if hint + 1 < n && x >= self.x_list[hint + 1] && (x < self.x_list[hint + 2] || hint + 1 == n - 1) {
+            return hint + 1;
+        }
+        self.segment(x)
     }
 
-    fn cal_cache(&mut self) {
+    fn cal_coef(&mut self) {
 
         let _2 = T::one() + T::one();
         let _6 = _2 + _2 + _2;
-        let mut j = 0;
-        while j < self.dim {
-            let y0 = self.y_list[self.c_ind * self.dim + j];
-            let y1 = self.y_list[(self.c_ind + 1) * self.dim + j];
-            let m0 = self.m_list[self.c_ind * self.dim + j];
-            let m1 = self.m_list[(self.c_ind + 1) * self.dim + j];
-            let h0 = self.x_list[self.c_ind + 1] - self.x_list[self.c_ind];
-            self.c_mat[j] = (
-                y0,
-                (y1 - y0) / h0 - h0 / _2 * m0 - h0 / _6 * (m1 - m0),
-                m0 / _2,
-                (m1 - m0) / (_6 * h0)
-            );
-            j += 1;
+        let n = self.x_list.len() - 1;
+        self.coef.clear();
+        self.coef.reserve(n * self.dim);
+        let mut i = 0;
+        while i < n {
+            let h0 = self.x_list[i + 1] - self.x_list[i];
+            let mut j = 0;
+            while j < self.dim {
+                let y0 = self.y_list[i * self.dim + j];
+                let y1 = self.y_list[(i + 1) * self.dim + j];
+                let m0 = self.m_list[i * self.dim + j];
+                let m1 = self.m_list[(i + 1) * self.dim + j];
+                self.coef.push((
+                    y0,
+                    (y1 - y0) / h0 - h0 / _2 * m0 - h0 / _6 * (m1 - m0),
+                    m0 / _2,
+                    (m1 - m0) / (_6 * h0)
+                ));
+                j += 1;
+            }
+            i += 1;
         }
-        
+
     }
 
 
@@ -254,69 +759,233 @@ impl<T: Float> CubeSpline<T> {
 
 
 
+/// per-follower segment cache, so many users can walk one shared spline without a binary search every step
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SplineCursor {
+
+    seg: usize,
 
+}
 
+impl SplineCursor {
 
-// use float::Float;
-
-// fn binary_search<'a, T: Float>(knot: &T, ts: &'a [T]) -> &'a[T] {
-//     let mut i = 0;
-//     let mut j = ts.len() - 1;
-//     while j - i > 1 {
-//         let h = (i + j) / 2;
-//         let vh = &ts[h];
-//         if vh <= knot {
-//             i = h;
-//         } else {
-//             j = h;
-//         }
-//     }
-//     &ts[i-1..j+1]
-// }
-
-// fn catmull_rom_find<T: Float>(target: T, knots: &[T]) -> T {
-//     let _1_0 = T::one();
-//     let _2_0 = _1_0 + _1_0;
-//     let _3_0 = _2_0 + _1_0;
-//     let esp = (_1_0 / _2_0).powf(_3_0);
-
-//     let tau = _1_0 / _2_0;
-
-//     let mut t = _1_0 / _2_0;
-//     loop {
-//         let t_1 = t;
-//         let t_2 = t_1 * t_1;
-//         let t_3 = t_2 * t_1;
-//         let d = knots[1];
-//         let c = - tau * knots[0] + tau * knots[2];
-//         let b = _2_0 * tau * knots[0] + (tau - _3_0) * knots[1] + (_3_0 - _2_0 * tau) * knots[2] - tau * knots[3];
-//         let a = - tau * knots[0] + (_2_0 - tau) * knots[1] + (tau - _2_0) * knots[2] + tau * knots[3];
-//         let step = t_1 - (a * t_3 + b * t_2 + c * t_1 + d - target) / (_3_0 * a * t_2 + _2_0 * b * t_1 + c);
-//         t += step;
-//         if step < esp {
-//             break;
-//         }
-//     }
-//     t
-// }
-
-// fn catmull_rom_weight<T: Float>(t: T) -> (T, T, T, T) {
-    
-//     let _1_0 = T::one();
-//     let _2_0 = _1_0 + _1_0;
-//     let _3_0 = _2_0 + _1_0;
-
-//     let tau = _1_0 / _2_0;
-
-//     let t_1 = t;
-//     let t_2 = t_1 * t_1;
-//     let t_3 = t_2 * t_1;
-
-//     let w1 = - tau * t_3 + _2_0 * tau * t_2 - tau * t_1;
-//     let w2 = (_2_0 - tau) * t_3 + (tau - _3_0) * t_2 + _1_0;
-//     let w3 = (tau - _2_0) * t_3 + (_3_0 - _2_0 * tau) * t_2 + tau * t_1;
-//     let w4 = tau * t_3 - tau * t_2;
-
-//     (w1, w2, w3, w4)
-// }
+    pub fn new() -> Self {
+        SplineCursor { seg: 0 }
+    }
 
+    pub fn get<T: Float>(&mut self, spline: &CubeSpline<T>, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        let x = self.seek(spline, x)?;
+        Ok(spline.eval(self.seg, x, y))
+    }
+
+    pub fn get_derivative<T: Float>(&mut self, spline: &CubeSpline<T>, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        let x = self.seek(spline, x)?;
+        Ok(spline.eval_derivative(self.seg, x, y))
+    }
+
+    pub fn get_derivative2<T: Float>(&mut self, spline: &CubeSpline<T>, x: T, y: &mut [T]) -> Result<usize, CubeSplineError> {
+        let x = self.seek(spline, x)?;
+        Ok(spline.eval_derivative2(self.seg, x, y))
+    }
+
+    fn seek<T: Float>(&mut self, spline: &CubeSpline<T>, x: T) -> Result<T, CubeSplineError> {
+        spline.check_eval(x)?;
+        let x = spline.wrap(x);
+        self.seg = spline.segment_near(self.seg, x);
+        Ok(x)
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const EPS: f64 = 1e-6;
+
+    fn eval(s: &CubeSpline<f64>, x: f64) -> ([f64; 2], [f64; 2], [f64; 2]) {
+        let mut y = [0.0; 2];
+        let mut d = [0.0; 2];
+        let mut d2 = [0.0; 2];
+        s.get(x, &mut y).unwrap();
+        s.get_derivative(x, &mut d).unwrap();
+        s.get_derivative2(x, &mut d2).unwrap();
+        (y, d, d2)
+    }
+
+    fn assert_close(a: &[f64], b: &[f64], tol: f64) {
+        for (u, v) in a.iter().zip(b.iter()) {
+            assert!((u - v).abs() < tol, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn periodic_seam_is_continuous() {
+        let mut x_list = vec![0.0, 0.7, 1.5, 2.0, 3.1];
+        let mut y_list = Vec::new();
+        for x in x_list.iter() {
+            let a = x / 4.0 * std::f64::consts::PI * 2.0;
+            y_list.push(a.cos() + 0.3 * (2.0 * a).sin());
+            y_list.push(a.sin());
+        }
+        x_list.push(4.0);
+        y_list.push(y_list[0]);
+        y_list.push(y_list[1]);
+        let s = CubeSpline::new().compile_with(x_list, y_list, Boundary::Periodic).unwrap();
+        let (y0, d0, dd0) = eval(&s, 0.0);
+        let (y1, d1, dd1) = eval(&s, 4.0 - EPS);
+        assert_close(&y0, &y1, 1e-5);
+        assert_close(&d0, &d1, 1e-5);
+        assert_close(&dd0, &dd1, 1e-4);
+        let (y2, d2, _) = eval(&s, 4.0 + 0.7);
+        let (y3, d3, _) = eval(&s, 0.7);
+        assert_close(&y2, &y3, 1e-9);
+        assert_close(&d2, &d3, 1e-9);
+    }
+
+    #[test]
+    fn periodic_requires_closed_curve() {
+        let r = CubeSpline::new().compile_with(vec![0.0, 1.0, 2.0, 3.0], vec![0.0, 1.0, 0.0, 0.5], Boundary::Periodic);
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn clamped_matches_end_derivatives() {
+        let x_list = vec![0.0, 1.0, 2.5, 3.0];
+        let y_list = vec![0.0, 0.0, 1.0, 2.0, 0.5, 1.0, 2.0, 0.0];
+        let b = Boundary::Clamped(vec![1.0, -2.0], vec![0.5, 3.0]);
+        let s = CubeSpline::new().compile_with(x_list, y_list, b).unwrap();
+        let (_, d0, _) = eval(&s, 0.0);
+        let (_, d1, _) = eval(&s, 3.0);
+        assert_close(&d0, &[1.0, -2.0], 1e-9);
+        assert_close(&d1, &[0.5, 3.0], 1e-9);
+    }
+
+    #[test]
+    fn not_a_knot_reproduces_cubic() {
+        let f = |x: f64| 2.0 * x * x * x - x * x + 3.0 * x - 1.0;
+        let x_list = vec![-1.0, 0.0, 0.5, 2.0, 3.0];
+        let y_list: Vec<f64> = x_list.iter().map(|x| f(*x)).collect();
+        let s = CubeSpline::new().compile_with(x_list, y_list, Boundary::NotAKnot).unwrap();
+        let mut y = [0.0];
+        for i in 0..=40 {
+            let x = -1.0 + i as f64 * 0.1;
+            s.get(x, &mut y).unwrap();
+            assert!((y[0] - f(x)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn natural_has_flat_curvature_at_ends() {
+        let s = CubeSpline::new().compile(vec![1.0, 2.0, 4.0], vec![1.0, 3.0, 2.0]).unwrap();
+        let mut d2 = [0.0];
+        s.get_derivative2(1.0, &mut d2).unwrap();
+        assert!(d2[0].abs() < 1e-9);
+        s.get_derivative2(4.0, &mut d2).unwrap();
+        assert!(d2[0].abs() < 1e-9);
+    }
+
+    #[test]
+    fn compile_reports_bad_input() {
+        let e = |x: Vec<f64>, y: Vec<f64>| CubeSpline::new().compile(x, y).err();
+        assert_eq!(e(vec![], vec![]), Some(CubeSplineError::GenTooFewPoints { required: 3, found: 0 }));
+        assert_eq!(e(vec![0.0, 1.0, 2.0], vec![0.0; 4]), Some(CubeSplineError::GenDimensionMismatch { knots: 3, values: 4 }));
+        assert_eq!(e(vec![0.0, 2.0, 1.0], vec![0.0; 3]), Some(CubeSplineError::GenUnordered { index: 2 }));
+        assert_eq!(e(vec![0.0, 1.0, 1.0], vec![0.0; 3]), Some(CubeSplineError::GenDuplicate { index: 2 }));
+        assert_eq!(e(vec![0.0, f64::NAN, 1.0], vec![0.0; 3]), Some(CubeSplineError::GenKnotNotFinite { index: 1 }));
+        assert_eq!(e(vec![0.0, 1.0, 2.0], vec![0.0, f64::INFINITY, 0.0]), Some(CubeSplineError::GenValueNotFinite { index: 1 }));
+    }
+
+    #[test]
+    fn uncompiled_get_is_an_error() {
+        let s = CubeSpline::<f64>::new();
+        let mut y = [0.0];
+        assert_eq!(s.get(0.0, &mut y), Err(CubeSplineError::EvalUncompiled));
+        let s = CubeSpline::new().compile(vec![0.0, 1.0, 2.0], vec![0.0, 1.0, 0.0]).unwrap();
+        assert_eq!(s.get_derivative(f64::NAN, &mut y), Err(CubeSplineError::EvalNotFinite));
+    }
+
+    #[test]
+    fn spline_is_shareable() {
+        fn assert_send_sync<S: Send + Sync>() {}
+        assert_send_sync::<CubeSpline<f64>>();
+        assert_send_sync::<std::sync::Arc<CubeSpline<f32>>>();
+    }
+
+    #[test]
+    fn cursor_matches_direct_evaluation() {
+        let s = CubeSpline::new().compile(vec![0.0, 1.0, 2.5, 3.0, 5.0], vec![0.0, 2.0, -1.0, 0.5, 1.0]).unwrap();
+        let mut cursor = SplineCursor::new();
+        let mut a = [0.0];
+        let mut b = [0.0];
+        for x in [4.9, 0.1, 2.6, 2.6, 1.0, 5.0, 0.0, 3.3] {
+            s.get(x, &mut a).unwrap();
+            cursor.get(&s, x, &mut b).unwrap();
+            assert_close(&a, &b, EPS);
+            s.get_derivative(x, &mut a).unwrap();
+            cursor.get_derivative(&s, x, &mut b).unwrap();
+            assert_close(&a, &b, EPS);
+        }
+    }
+
+    #[test]
+    fn batch_matches_single_evaluation() {
+        let x_list = vec![0.0, 1.0, 2.5, 3.0, 5.0];
+        let y_list = vec![0.0, 1.0, 2.0, 0.0, -1.0, 1.0, 0.5, 2.0, 1.0, 1.0];
+        let s = CubeSpline::new().compile(x_list, y_list).unwrap();
+        // sorted, repeated, out of range and one step backwards
+        let xs = [-0.5, 0.0, 0.4, 1.0, 1.0, 2.9, 3.0, 4.2, 5.0, 6.0, 2.0];
+        let mut out = vec![0.0; xs.len() * 2];
+        let mut y = [0.0; 2];
+        assert_eq!(s.get_many(&xs, &mut out), Ok(xs.len()));
+        for (i, x) in xs.iter().enumerate() {
+            s.get(*x, &mut y).unwrap();
+            assert_close(&y, &out[i * 2..i * 2 + 2], EPS);
+        }
+        s.get_derivative_many(&xs, &mut out).unwrap();
+        for (i, x) in xs.iter().enumerate() {
+            s.get_derivative(*x, &mut y).unwrap();
+            assert_close(&y, &out[i * 2..i * 2 + 2], EPS);
+        }
+        s.get_derivative2_many(&xs, &mut out).unwrap();
+        for (i, x) in xs.iter().enumerate() {
+            s.get_derivative2(*x, &mut y).unwrap();
+            assert_close(&y, &out[i * 2..i * 2 + 2], EPS);
+        }
+        assert_eq!(s.get_many(&xs, &mut out[..3]), Err(CubeSplineError::EvalBufferTooSmall { required: 22, found: 3 }));
+    }
+
+    #[test]
+    fn resample_spans_the_knots() {
+        let s = CubeSpline::new().compile(vec![1.0, 2.0, 4.0], vec![1.0, 3.0, 2.0]).unwrap();
+        let points = s.resample(7).unwrap();
+        assert_eq!(points.len(), 7);
+        assert_close(&points[..1], &[1.0], EPS);
+        assert_close(&points[6..], &[2.0], EPS);
+        let mut y = [0.0];
+        s.get(2.5, &mut y).unwrap();
+        assert_close(&points[3..4], &y, EPS);
+
+        let arc = s.arc_length(16).unwrap();
+        let points = s.resample_by_length(&arc, 5).unwrap();
+        assert_close(&points[..1], &[1.0], EPS);
+        assert_close(&points[4..], &[2.0], EPS);
+    }
+
+    #[test]
+    fn length_and_param_are_inverse() {
+        let s = CubeSpline::new().compile(vec![0.0, 1.0, 2.0, 3.0], vec![0.0, 0.0, 1.0, 0.0, 30.0, 5.0, 31.0, 25.0]).unwrap();
+        let table = s.arc_length(16).unwrap();
+        assert_eq!(s.length_at(&table, 0.0).unwrap(), 0.0);
+        assert!((s.length_at(&table, 3.0).unwrap() - table.total()).abs() < EPS);
+        let mut x = 0.0;
+        while x <= 3.0 {
+            let l = s.length_at(&table, x).unwrap();
+            assert!((s.param_at_length(&table, l).unwrap() - x).abs() < 1e-6, "x = {}", x);
+            x += 0.07;
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use std::fmt;
+use glium::{Program, DrawParameters, VertexBuffer, IndexBuffer, Blend, Surface};
+use glium::backend::Facade;
+use glium::index::PrimitiveType;
+use glium::texture::Texture2d;
+use glium::uniforms::MagnifySamplerFilter;
+
+use super::util::Resource;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Copy, Clone)]
+pub struct SpriteVertex {
+    s_position: [f32; 2],
+    s_uv: [f32; 2],
+    s_color: [f32; 4],
+}
+
+implement_vertex!(SpriteVertex, s_position, s_uv, s_color);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BlendMode {
+    Opaque,
+    Alpha,
+    Additive,
+    Multiply,
+}
+
+impl BlendMode {
+
+    pub fn blend(self) -> Blend {
+        use glium::{BlendingFunction, LinearBlendingFactor};
+        match self {
+            BlendMode::Opaque => Blend::default(),
+            BlendMode::Alpha => Blend::alpha_blending(),
+            BlendMode::Additive => Blend {
+                color: BlendingFunction::Addition { source: LinearBlendingFactor::SourceAlpha, destination: LinearBlendingFactor::One },
+                alpha: BlendingFunction::Addition { source: LinearBlendingFactor::One, destination: LinearBlendingFactor::One },
+                constant_value: (0.0, 0.0, 0.0, 0.0),
+            },
+            BlendMode::Multiply => Blend {
+                color: BlendingFunction::Addition { source: LinearBlendingFactor::DestinationColor, destination: LinearBlendingFactor::OneMinusSourceAlpha },
+                alpha: BlendingFunction::Addition { source: LinearBlendingFactor::Zero, destination: LinearBlendingFactor::One },
+                constant_value: (0.0, 0.0, 0.0, 0.0),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpriteError {
+    /// a queued sprite uses a texture that was never added to the batch
+    UnknownTexture(TextureId),
+    /// `TextureId` cannot address another texture
+    TooManyTextures,
+}
+
+impl fmt::Display for SpriteError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpriteError::UnknownTexture(id) => write!(f, "sprite batch: unknown texture {}", id.0),
+            SpriteError::TooManyTextures => write!(f, "sprite batch: more than {} textures", u16::MAX as usize + 1),
+        }
+    }
+}
+
+impl std::error::Error for SpriteError {}
+
+/// index of a texture added with `SpriteBatch::add_texture`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TextureId(pub u16);
+
+/// one textured quad; `size` is in world units before `scale`, `rotation` in radians around `position`
+#[derive(Debug, Clone, Copy)]
+pub struct Sprite {
+
+    pub position: [f32; 2],
+
+    pub rotation: f32,
+
+    pub size: [f32; 2],
+
+    pub scale: [f32; 2],
+
+    pub color: [f32; 4],
+
+    /// [u0, v0, u1, v1] atlas region
+    pub uv: [f32; 4],
+
+    pub texture: TextureId,
+
+    pub blend: BlendMode,
+
+    /// higher layers are drawn on top; ui goes above bullets, bullets above the background
+    pub layer: i16,
+
+}
+
+impl Default for Sprite {
+
+    fn default() -> Self {
+        Sprite {
+            position: [0.0, 0.0],
+            rotation: 0.0,
+            size: [1.0, 1.0],
+            scale: [1.0, 1.0],
+            color: [1.0, 1.0, 1.0, 1.0],
+            uv: [0.0, 0.0, 1.0, 1.0],
+            texture: TextureId(0),
+            blend: BlendMode::Alpha,
+            layer: 0,
+        }
+    }
+}
+
+impl Sprite {
+
+    pub fn new(texture: TextureId, position: [f32; 2], size: [f32; 2], uv: [f32; 4]) -> Self {
+        Sprite { texture, position, size, uv, ..Default::default() }
+    }
+
+    // four corners in the order expected by `QUAD_INDICES`
+    fn corners(&self, out: &mut Vec<SpriteVertex>) {
+        let hw = self.size[0] * self.scale[0] * 0.5;
+        let hh = self.size[1] * self.scale[1] * 0.5;
+        let (s, c) = self.rotation.sin_cos();
+        let corners = [([-hw, -hh], [self.uv[0], self.uv[1]]), ([hw, -hh], [self.uv[2], self.uv[1]]), ([-hw, hh], [self.uv[0], self.uv[3]]), ([hw, hh], [self.uv[2], self.uv[3]])];
+        for (p, uv) in corners.iter() {
+            out.push(SpriteVertex {
+                s_position: [self.position[0] + c * p[0] - s * p[1], self.position[1] + s * p[0] + c * p[1]],
+                s_uv: *uv,
+                s_color: self.color,
+            });
+        }
+    }
+}
+
+const QUAD_INDICES: [u32; 6] = [0, 1, 3, 3, 2, 0];
+
+/// sprites sharing layer, blend mode and texture, drawn with one call
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Run {
+
+    layer: i16,
+
+    blend: BlendMode,
+
+    texture: TextureId,
+
+    start: usize,
+
+    count: usize,
+
+}
+
+// sort by layer, then blend mode and texture, keeping submission order inside a run
+fn sort_runs(sprites: &mut [Sprite], runs: &mut Vec<Run>) {
+    sprites.sort_by_key(|s| (s.layer, s.blend, s.texture));
+    runs.clear();
+    let mut i = 0;
+    while i < sprites.len() {
+        let s = &sprites[i];
+        match runs.last_mut() {
+            Some(run) if run.layer == s.layer && run.blend == s.blend && run.texture == s.texture => run.count += 1,
+            _ => runs.push(Run { layer: s.layer, blend: s.blend, texture: s.texture, start: i, count: 1 }),
+        }
+        i += 1;
+    }
+}
+
+// first texture used by a run but not added to the batch
+fn missing_texture(runs: &[Run], textures: usize) -> Option<TextureId> {
+    runs.iter().map(|run| run.texture).find(|t| t.0 as usize >= textures)
+}
+
+///
+/// collects sprites during a frame and draws them in as few calls as possible
+///
+/// quads are transformed on the cpu and streamed into one persistent vertex buffer,
+/// indices are built once for the whole capacity
+pub struct SpriteBatch {
+
+    vertices: VertexBuffer<SpriteVertex>,
+
+    indices: IndexBuffer<u32>,
+
+    textures: Vec<Texture2d>,
+
+    sprites: Vec<Sprite>,
+
+    runs: Vec<Run>,
+
+    staging: Vec<SpriteVertex>,
+
+    filter: MagnifySamplerFilter,
+
+    draw_calls: usize,
+
+}
+
+impl SpriteBatch {
+
+    /// `capacity` is the number of sprites per upload; larger frames are flushed in chunks
+    pub fn new(facade: &dyn Facade, capacity: usize) -> Result<Self> {
+        let capacity = std::cmp::max(capacity, 1);
+        let vertices = VertexBuffer::empty_dynamic(facade, capacity * 4).map_err(Box::new)?;
+        let mut ind = Vec::with_capacity(capacity * 6);
+        let mut i = 0;
+        while i < capacity {
+            ind.extend(QUAD_INDICES.iter().map(|k| k + i as u32 * 4));
+            i += 1;
+        }
+        let indices = IndexBuffer::new(facade, PrimitiveType::TrianglesList, &ind).map_err(Box::new)?;
+        Ok(SpriteBatch {
+            vertices,
+            indices,
+            textures: Vec::new(),
+            sprites: Vec::with_capacity(capacity),
+            runs: Vec::new(),
+            staging: Vec::with_capacity(capacity * 4),
+            filter: MagnifySamplerFilter::Nearest,
+            draw_calls: 0,
+        })
+    }
+
+    /// program built from `glsl/sprite.vert` and `glsl/sprite.frag`
+    pub fn load_program(facade: &dyn Facade, resource: &Resource) -> Result<Program> {
+        let vert = resource.load_as_string("glsl/sprite.vert").map_err(Box::new)?;
+        let frag = resource.load_as_string("glsl/sprite.frag").map_err(Box::new)?;
+        let prog = Program::from_source(facade, &vert, &frag, None).map_err(Box::new)?;
+        Ok(prog)
+    }
+
+    pub fn add_texture(&mut self, texture: Texture2d) -> std::result::Result<TextureId, SpriteError> {
+        if self.textures.len() > u16::MAX as usize {
+            return Err(SpriteError::TooManyTextures);
+        }
+        self.textures.push(texture);
+        Ok(TextureId((self.textures.len() - 1) as u16))
+    }
+
+    pub fn get_texture(&self, texture: TextureId) -> Option<&Texture2d> {
+        self.textures.get(texture.0 as usize)
+    }
+
+    pub fn set_filter(&mut self, filter: MagnifySamplerFilter) -> &mut Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.vertices.len() / 4
+    }
+
+    pub fn len(&self) -> usize {
+        self.sprites.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sprites.is_empty()
+    }
+
+    /// draw calls issued by the last `flush`
+    pub fn draw_calls(&self) -> usize {
+        self.draw_calls
+    }
+
+    pub fn push(&mut self, sprite: Sprite) {
+        self.sprites.push(sprite);
+    }
+
+    pub fn clear(&mut self) {
+        self.sprites.clear();
+    }
+
+    /// draw every queued sprite and empty the queue; `projection` maps world units to clip space,
+    /// the blend mode of `draw_parameters` is replaced per run
+    ///
+    /// nothing is drawn if a sprite uses a texture the batch does not know
+    pub fn flush<S: Surface>(&mut self, target: &mut S, program: &Program, projection: [[f32; 4]; 4], draw_parameters: &DrawParameters) -> Result<()> {
+        self.draw_calls = 0;
+        sort_runs(&mut self.sprites, &mut self.runs);
+        if let Some(texture) = missing_texture(&self.runs, self.textures.len()) {
+            self.sprites.clear();
+            return Err(Box::new(SpriteError::UnknownTexture(texture)));
+        }
+        let capacity = self.capacity();
+        let mut params = draw_parameters.clone();
+
+        // fill the buffer with whole runs (split when one is larger than the buffer), then draw them
+        let mut r = 0;
+        let mut done = 0;
+        while r < self.runs.len() {
+            self.staging.clear();
+            let mut batch: Vec<(Run, usize)> = Vec::new();
+            while r < self.runs.len() && self.staging.len() / 4 < capacity {
+                let run = self.runs[r];
+                let take = std::cmp::min(run.count - done, capacity - self.staging.len() / 4);
+                batch.push((run, self.staging.len() / 4));
+                for s in self.sprites[run.start + done..run.start + done + take].iter() {
+                    s.corners(&mut self.staging);
+                }
+                if let Some((last, _)) = batch.last_mut() {
+                    last.count = take;
+                }
+                done += take;
+                if done == run.count {
+                    done = 0;
+                    r += 1;
+                }
+            }
+            // orphan the old storage so the driver does not wait on the previous chunk
+            self.vertices.invalidate();
+            if let Some(slice) = self.vertices.slice_mut(0..self.staging.len()) {
+                slice.write(&self.staging);
+            }
+            for (run, first) in batch.iter() {
+                let texture = &self.textures[run.texture.0 as usize];
+                let indices = match self.indices.slice(first * 6..(first + run.count) * 6) {
+                    Some(indices) => indices,
+                    None => continue,
+                };
+                params.blend = run.blend.blend();
+                let uniforms = uniform!{ texture_sampler: texture.sampled().magnify_filter(self.filter), projection: projection };
+                target.draw(&self.vertices, indices, program, &uniforms, &params).map_err(Box::new)?;
+                self.draw_calls += 1;
+            }
+        }
+        self.sprites.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn runs_group_by_layer_blend_and_texture() {
+        let mut sprites = vec![
+            Sprite { texture: TextureId(1), ..Default::default() },
+            Sprite { texture: TextureId(0), layer: 2, ..Default::default() },
+            Sprite { texture: TextureId(0), ..Default::default() },
+            Sprite { texture: TextureId(1), ..Default::default() },
+            Sprite { texture: TextureId(0), blend: BlendMode::Additive, ..Default::default() },
+        ];
+        let mut runs = Vec::new();
+        sort_runs(&mut sprites, &mut runs);
+        let keys: Vec<(i16, BlendMode, u16, usize)> = runs.iter().map(|r| (r.layer, r.blend, r.texture.0, r.count)).collect();
+        assert_eq!(keys, vec![(0, BlendMode::Alpha, 0, 1), (0, BlendMode::Alpha, 1, 2), (0, BlendMode::Additive, 0, 1), (2, BlendMode::Alpha, 0, 1)]);
+        assert_eq!(missing_texture(&runs, 2), None);
+        assert_eq!(missing_texture(&runs, 1), Some(TextureId(1)));
+    }
+
+    #[test]
+    fn corners_follow_rotation_and_scale() {
+        let sprite = Sprite { position: [10.0, 0.0], size: [2.0, 2.0], scale: [2.0, 1.0], rotation: std::f32::consts::FRAC_PI_2, ..Default::default() };
+        let mut out = Vec::new();
+        sprite.corners(&mut out);
+        assert_eq!(out.len(), 4);
+        // the bottom right corner (2, -1) turns to (1, 2)
+        let p = out[1].s_position;
+        assert!((p[0] - 11.0).abs() < 1e-5 && (p[1] - 2.0).abs() < 1e-5);
+        assert_eq!(out[3].s_uv, [1.0, 1.0]);
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use serde::Deserialize;
+
+use super::danmaku::Pattern;
+use super::path::{Path, PathDesc};
+use super::spline::CubeSplineError;
+use super::util::Resource;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+///
+/// stage timeline, e.g.
+///
+/// { "name": "stage1", "events": [
+///   { "at": 60, "spawn": { "enemy": "fairy", "wave": 1, "position": [0, 200], "count": 4, "interval": 12,
+///     "path": { "points": [[0, 200], [80, 120], [0, 40]] }, "speed": 90, "pattern": "rsc/pattern/spiral.json" } },
+///   { "at": 300, "wait": { "until": { "wave_cleared": 1 }, "timeout": 600 } },
+///   { "at": 300, "music": "boss" }, { "at": 320, "boss": "rumia" } ] }
+///
+/// `at` is in update ticks on the stage clock, which stands still while a `wait` blocks
+#[derive(Deserialize)]
+struct RawStage {
+
+    name: String,
+
+    events: Vec<RawEvent>,
+
+}
+
+#[derive(Deserialize)]
+struct RawEvent {
+
+    at: u64,
+
+    #[serde(flatten)]
+    action: RawAction,
+
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RawAction {
+    Spawn(RawSpawn),
+    Music(String),
+    Dialogue(String),
+    Boss(String),
+    Checkpoint(String),
+    Wait { until: Condition, timeout: Option<u64> },
+}
+
+#[derive(Deserialize)]
+struct RawSpawn {
+
+    enemy: String,
+
+    #[serde(default)]
+    wave: u32,
+
+    #[serde(default)]
+    position: [f32; 2],
+
+    path: Option<PathDesc>,
+
+    #[serde(default)]
+    speed: f32,
+
+    pattern: Option<String>,
+
+    #[serde(default = "one")]
+    count: u32,
+
+    #[serde(default)]
+    interval: u64,
+
+    /// added to the position (and path) of each following copy
+    #[serde(default)]
+    offset: [f32; 2],
+
+}
+
+fn one() -> u32 {
+    1
+}
+
+/// what a `wait` blocks on; answered by the game through `StageHandler::is_met`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// every enemy spawned with this wave number is gone
+    WaveCleared(u32),
+    BossDefeated,
+    DialogueFinished,
+    /// game-defined flag
+    Flag(String),
+}
+
+/// one enemy to create; paths and patterns are shared by every copy
+#[derive(Clone)]
+pub struct Spawn {
+
+    pub enemy: String,
+
+    pub wave: u32,
+
+    pub position: [f32; 2],
+
+    pub path: Option<Arc<Path<f32>>>,
+
+    /// world units per second along `path`
+    pub speed: f32,
+
+    pub pattern: Option<Rc<Pattern>>,
+
+}
+
+#[derive(Clone)]
+pub enum Action {
+    Spawn(Spawn),
+    Music(String),
+    Dialogue(String),
+    Boss(String),
+    Checkpoint(String),
+    Wait { until: Condition, timeout: Option<u64> },
+}
+
+#[derive(Clone)]
+pub struct StageEvent {
+
+    pub at: u64,
+
+    pub action: Action,
+
+}
+
+#[derive(Debug)]
+pub enum StageError {
+    Json(serde_json::Error),
+    /// path of event `index` does not compile
+    Path { index: usize, error: CubeSplineError },
+    /// pattern file of event `index` failed to load
+    Pattern { index: usize, file: String, error: String },
+}
+
+impl fmt::Display for StageError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StageError::Json(e) => write!(f, "stage: {}", e),
+            StageError::Path { index, error } => write!(f, "stage event {}: bad path: {}", index, error),
+            StageError::Pattern { index, file, error } => write!(f, "stage event {}: pattern {}: {}", index, file, error),
+        }
+    }
+}
+
+impl std::error::Error for StageError {}
+
+/// game side of a stage: creates what the timeline asks for and answers wait conditions
+pub trait StageHandler {
+
+    fn spawn(&mut self, spawn: &Spawn) -> Result<()>;
+
+    fn music(&mut self, _track: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn dialogue(&mut self, _id: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn boss(&mut self, _id: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, _name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_met(&mut self, condition: &Condition) -> bool;
+}
+
+/// timeline of stage events run one update tick at a time
+pub struct Stage {
+
+    name: String,
+
+    events: Vec<StageEvent>,
+
+    cursor: usize,
+
+    time: u64,
+
+    // ticks spent blocked on the current wait
+    waited: u64,
+
+}
+
+impl Stage {
+
+    /// events are sorted by time; equal times keep their file order
+    pub fn new(name: &str, mut events: Vec<StageEvent>) -> Self {
+        events.sort_by_key(|e| e.at);
+        Stage {
+            name: name.to_string(),
+            events,
+            cursor: 0,
+            time: 0,
+            waited: 0,
+        }
+    }
+
+    /// pattern files are read through `resource`, each one once
+    pub fn parse(src: &str, resource: &Resource) -> std::result::Result<Self, StageError> {
+        let raw: RawStage = serde_json::from_str(src).map_err(StageError::Json)?;
+        let mut patterns: HashMap<String, Rc<Pattern>> = HashMap::new();
+        let mut events = Vec::with_capacity(raw.events.len());
+        for (index, e) in raw.events.into_iter().enumerate() {
+            let action = match e.action {
+                RawAction::Spawn(spawn) => {
+                    let pattern = match &spawn.pattern {
+                        Some(file) => {
+                            if !patterns.contains_key(file) {
+                                let p = Pattern::load(resource, file).map_err(|error| StageError::Pattern { index, file: file.clone(), error: error.to_string() })?;
+                                patterns.insert(file.clone(), Rc::new(p));
+                            }
+                            patterns.get(file).cloned()
+                        }
+                        None => None,
+                    };
+                    // copies without an offset share one path
+                    let shared = match &spawn.path {
+                        Some(path) if spawn.offset == [0.0, 0.0] => Some(Arc::new(path.compile([0.0, 0.0]).map_err(|error| StageError::Path { index, error })?)),
+                        _ => None,
+                    };
+                    let mut i = 0;
+                    while i < spawn.count {
+                        let shift = [spawn.offset[0] * i as f32, spawn.offset[1] * i as f32];
+                        let path = match (&shared, &spawn.path) {
+                            (Some(shared), _) => Some(shared.clone()),
+                            (None, Some(path)) => Some(Arc::new(path.compile(shift).map_err(|error| StageError::Path { index, error })?)),
+                            (None, None) => None,
+                        };
+                        events.push(StageEvent {
+                            at: e.at + spawn.interval * i as u64,
+                            action: Action::Spawn(Spawn {
+                                enemy: spawn.enemy.clone(),
+                                wave: spawn.wave,
+                                position: [spawn.position[0] + shift[0], spawn.position[1] + shift[1]],
+                                path,
+                                speed: spawn.speed,
+                                pattern: pattern.clone(),
+                            }),
+                        });
+                        i += 1;
+                    }
+                    continue;
+                }
+                RawAction::Music(track) => Action::Music(track),
+                RawAction::Dialogue(id) => Action::Dialogue(id),
+                RawAction::Boss(id) => Action::Boss(id),
+                RawAction::Checkpoint(name) => Action::Checkpoint(name),
+                RawAction::Wait { until, timeout } => Action::Wait { until, timeout },
+            };
+            events.push(StageEvent { at: e.at, action });
+        }
+        Ok(Self::new(&raw.name, events))
+    }
+
+    pub fn load(resource: &Resource, file: &str) -> Result<Self> {
+        let src = resource.load_as_string(file).map_err(Box::new)?;
+        let stage = Self::parse(&src, resource).map_err(Box::new)?;
+        Ok(stage)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn events(&self) -> &[StageEvent] {
+        &self.events
+    }
+
+    /// stage clock in update ticks
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.waited > 0
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.cursor >= self.events.len()
+    }
+
+    /// run the events due this tick, then advance the clock unless a wait is blocking
+    pub fn update(&mut self, handler: &mut dyn StageHandler) -> Result<()> {
+        while self.cursor < self.events.len() && self.events[self.cursor].at <= self.time {
+            let event = &self.events[self.cursor];
+            match &event.action {
+                Action::Spawn(spawn) => handler.spawn(spawn)?,
+                Action::Music(track) => handler.music(track)?,
+                Action::Dialogue(id) => handler.dialogue(id)?,
+                Action::Boss(id) => handler.boss(id)?,
+                Action::Checkpoint(name) => handler.checkpoint(name)?,
+                Action::Wait { until, timeout } => {
+                    let expired = timeout.is_some_and(|t| self.waited >= t);
+                    if !expired && !handler.is_met(until) {
+                        self.waited += 1;
+                        return Ok(());
+                    }
+                    self.waited = 0;
+                }
+            }
+            self.cursor += 1;
+        }
+        self.time += 1;
+        Ok(())
+    }
+
+    ///
+    /// jump to stage time `time` for testing:
+    ///
+    /// earlier spawns, dialogue, bosses and waits are skipped, the latest earlier music and checkpoint are replayed
+    pub fn seek(&mut self, time: u64, handler: &mut dyn StageHandler) -> Result<()> {
+        let index = self.events.iter().position(|e| e.at >= time).unwrap_or(self.events.len());
+        self.seek_to(index, time, handler)
+    }
+
+    /// `seek` to the named checkpoint, which runs on the next `update`;
+    /// events listed before it are skipped even when they share its tick. false if there is none
+    pub fn seek_checkpoint(&mut self, name: &str, handler: &mut dyn StageHandler) -> Result<bool> {
+        let index = self.events.iter().position(|e| matches!(&e.action, Action::Checkpoint(n) if n == name));
+        match index {
+            Some(index) => {
+                let at = self.events[index].at;
+                self.seek_to(index, at, handler)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // continue from event `index` at stage time `time`, replaying the latest music and checkpoint before it
+    fn seek_to(&mut self, index: usize, time: u64, handler: &mut dyn StageHandler) -> Result<()> {
+        self.cursor = index;
+        self.waited = 0;
+        self.time = time;
+        let earlier = &self.events[..index];
+        if let Some(Action::Checkpoint(name)) = earlier.iter().rev().map(|e| &e.action).find(|a| matches!(a, Action::Checkpoint(_))) {
+            handler.checkpoint(name)?;
+        }
+        if let Some(Action::Music(track)) = earlier.iter().rev().map(|e| &e.action).find(|a| matches!(a, Action::Music(_))) {
+            handler.music(track)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Log {
+        spawned: Vec<(u32, String)>,
+        music: Vec<String>,
+        cleared: bool,
+    }
+
+    impl StageHandler for Log {
+
+        fn spawn(&mut self, spawn: &Spawn) -> Result<()> {
+            self.spawned.push((spawn.wave, spawn.enemy.clone()));
+            Ok(())
+        }
+
+        fn music(&mut self, track: &str) -> Result<()> {
+            self.music.push(track.to_string());
+            Ok(())
+        }
+
+        fn is_met(&mut self, condition: &Condition) -> bool {
+            *condition == Condition::WaveCleared(1) && self.cleared
+        }
+    }
+
+    const SRC: &str = r#"{ "name": "test", "events": [
+        { "at": 0, "music": "road" },
+        { "at": 2, "spawn": { "enemy": "fairy", "wave": 1, "count": 3, "interval": 2,
+          "path": { "points": [[0, 0], [1, 1], [2, 0]] }, "speed": 1 } },
+        { "at": 10, "wait": { "until": { "wave_cleared": 1 } } },
+        { "at": 10, "checkpoint": "mid" },
+        { "at": 10, "music": "boss" },
+        { "at": 12, "spawn": { "enemy": "big", "wave": 2, "offset": [1, 0], "count": 2 } }
+    ] }"#;
+
+    fn run(stage: &mut Stage, log: &mut Log, ticks: u32) {
+        let mut i = 0;
+        while i < ticks {
+            stage.update(log).unwrap();
+            i += 1;
+        }
+    }
+
+    #[test]
+    fn wait_holds_the_clock() {
+        let mut stage = Stage::parse(SRC, &Resource::default()).unwrap();
+        let mut log = Log::default();
+        run(&mut stage, &mut log, 20);
+        assert_eq!(log.spawned.len(), 3);
+        assert!(stage.is_waiting());
+        assert_eq!(stage.time(), 10);
+        log.cleared = true;
+        run(&mut stage, &mut log, 3);
+        assert_eq!(log.music, vec!["road", "boss"]);
+        assert_eq!(log.spawned.len(), 5);
+        assert!(stage.is_finished());
+    }
+
+    #[test]
+    fn seek_skips_to_checkpoint() {
+        let mut stage = Stage::parse(SRC, &Resource::default()).unwrap();
+        let mut log = Log::default();
+        assert!(stage.seek_checkpoint("mid", &mut log).unwrap());
+        assert_eq!(log.music, vec!["road"]);
+        // the wave 1 wait listed before the checkpoint is skipped though it shares its tick
+        run(&mut stage, &mut log, 5);
+        assert!(!stage.is_waiting());
+        assert_eq!(log.music, vec!["road", "boss"]);
+        assert_eq!(log.spawned, vec![(2, "big".to_string()), (2, "big".to_string())]);
+        assert!(stage.is_finished());
+    }
+
+    #[test]
+    fn sample_stage_loads() {
+        let mut stage = Stage::load(&Resource::default(), "rsc/stage/stage1.json").unwrap();
+        assert_eq!(stage.name(), "stage1");
+        assert_eq!(stage.events().iter().filter(|e| matches!(e.action, Action::Spawn(_))).count(), 10);
+        let mut log = Log::default();
+        assert!(stage.seek_checkpoint("midboss", &mut log).unwrap());
+        run(&mut stage, &mut log, 1);
+        assert!(!stage.is_waiting());
+        assert_eq!(stage.time(), 301);
+    }
+}
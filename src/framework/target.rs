@@ -0,0 +1,189 @@
+use glium::Surface;
+use glium::backend::Facade;
+use glium::framebuffer::{SimpleFrameBuffer, DepthRenderBuffer};
+use glium::texture::{Texture2d, DepthFormat, RawImage2d};
+use glium::uniforms::MagnifySamplerFilter;
+use glium::{Rect, BlitTarget};
+
+use super::atlas::Image;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+///
+/// colour texture with an optional depth buffer to render into instead of the window
+///
+/// in `GameLogic::render`: draw meshes into `framebuffer(display)`, then `blit_to` the frame
+/// or sample `texture()` from a post-processing shader
+pub struct OffscreenTarget {
+
+    color: Texture2d,
+
+    depth: Option<DepthRenderBuffer>,
+
+}
+
+impl OffscreenTarget {
+
+    /// colour plus a 24 bit depth buffer, for 3d scenes
+    pub fn new(facade: &dyn Facade, width: u32, height: u32) -> Result<Self> {
+        let color = Texture2d::empty(facade, width, height).map_err(Box::new)?;
+        let depth = DepthRenderBuffer::new(facade, DepthFormat::I24, width, height).map_err(Box::new)?;
+        Ok(OffscreenTarget { color, depth: Some(depth) })
+    }
+
+    /// colour only, enough for sprites and ui layers
+    pub fn color_only(facade: &dyn Facade, width: u32, height: u32) -> Result<Self> {
+        let color = Texture2d::empty(facade, width, height).map_err(Box::new)?;
+        Ok(OffscreenTarget { color, depth: None })
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.color.dimensions()
+    }
+
+    pub fn has_depth(&self) -> bool {
+        self.depth.is_some()
+    }
+
+    /// recreate the buffers when the size changed, e.g. after a window resize; contents are lost
+    pub fn resize(&mut self, facade: &dyn Facade, width: u32, height: u32) -> Result<()> {
+        if self.size() == (width, height) {
+            return Ok(());
+        }
+        *self = if self.has_depth() {
+            Self::new(facade, width, height)?
+        } else {
+            Self::color_only(facade, width, height)?
+        };
+        Ok(())
+    }
+
+    /// surface drawing into the target, valid while it is borrowed
+    pub fn framebuffer<'a>(&'a self, facade: &dyn Facade) -> Result<SimpleFrameBuffer<'a>> {
+        let fb = match &self.depth {
+            Some(depth) => SimpleFrameBuffer::with_depth_buffer(facade, &self.color, depth).map_err(Box::new)?,
+            None => SimpleFrameBuffer::new(facade, &self.color).map_err(Box::new)?,
+        };
+        Ok(fb)
+    }
+
+    pub fn texture(&self) -> &Texture2d {
+        &self.color
+    }
+
+    /// stretch the whole target over `target`
+    pub fn blit_to<S: Surface>(&self, target: &S, filter: MagnifySamplerFilter) {
+        self.color.as_surface().fill(target, filter);
+    }
+
+    /// copy into the pixels of `target` from `origin` [left, bottom] spanning `size` [width, height],
+    /// e.g. a minimap corner; a negative width or height mirrors the copy
+    pub fn blit_to_rect<S: Surface>(&self, target: &S, origin: [u32; 2], size: [i32; 2], filter: MagnifySamplerFilter) {
+        let (width, height) = self.size();
+        let source = Rect { left: 0, bottom: 0, width, height };
+        let dest = BlitTarget { left: origin[0], bottom: origin[1], width: size[0], height: size[1] };
+        self.color.as_surface().blit_color(&source, target, &dest, filter);
+    }
+
+    /// read the colour back, rows from the top; slow, meant for tests and screenshots
+    pub fn read(&self) -> Image {
+        let raw: RawImage2d<u8> = self.color.read();
+        let (width, height) = (raw.width, raw.height);
+        let row = (width * 4) as usize;
+        let mut data = Vec::with_capacity(raw.data.len());
+        // gl rows start at the bottom
+        for line in raw.data.chunks(row).rev() {
+            data.extend_from_slice(line);
+        }
+        Image { width, height, data }
+    }
+}
+
+// OSMesa is the context that needs no display server
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+
+    use glium::{HeadlessRenderer, Program, DrawParameters};
+    use glium::glutin::ContextBuilder;
+    use glium::glutin::dpi::PhysicalSize;
+    use glium::index::PrimitiveType;
+    use super::*;
+    use super::super::mesh::{Mesh, INDICES4_RECT};
+
+    #[derive(Copy, Clone)]
+    struct P {
+        p: [f32; 2],
+    }
+
+    implement_vertex!(P, p);
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    // software gl through OSMesa; None (with the reason printed) where libOSMesa is missing
+    fn context() -> Option<HeadlessRenderer> {
+        use glium::glutin::os::unix::HeadlessContextExt;
+        match ContextBuilder::new().build_osmesa(PhysicalSize::new(16.0, 16.0)) {
+            Ok(context) => Some(HeadlessRenderer::new(context).unwrap()),
+            Err(e) => {
+                eprintln!("skipped: no OSMesa context ({})", e);
+                None
+            }
+        }
+    }
+
+    // target of `width` x `height`, its top half drawn red over a blue clear
+    fn paint(facade: &HeadlessRenderer, width: u32, height: u32) -> OffscreenTarget {
+        let program = Program::from_source(facade,
+            "#version 110\nattribute vec2 p;\nvoid main() { gl_Position = vec4(p, 0.0, 1.0); }",
+            "#version 110\nvoid main() { gl_FragColor = vec4(1.0, 0.0, 0.0, 1.0); }",
+            None).unwrap();
+        // bottom left, bottom right, top left, top right of the upper half in clip space
+        let quad = vec![P { p: [-1.0, 0.0] }, P { p: [1.0, 0.0] }, P { p: [-1.0, 1.0] }, P { p: [1.0, 1.0] }];
+        let mesh: Mesh<P> = Mesh::wrap(quad, INDICES4_RECT.to_vec(), PrimitiveType::TrianglesList);
+        let target = OffscreenTarget::new(facade, width, height).unwrap();
+        {
+            let mut fb = target.framebuffer(facade).unwrap();
+            fb.clear_color_and_depth((0.0, 0.0, 1.0, 1.0), 1.0);
+            mesh.draw(facade, &mut fb, &program, &glium::uniforms::EmptyUniforms, &DrawParameters::default()).unwrap();
+        }
+        target
+    }
+
+    #[test]
+    fn mesh_drawn_offscreen_reads_back_from_the_top() {
+        let facade = match context() {
+            Some(facade) => facade,
+            None => return,
+        };
+        let image = paint(&facade, 4, 2).read();
+        assert_eq!((image.width, image.height), (4, 2));
+        assert_eq!(image.pixel(0, 0), RED);
+        assert_eq!(image.pixel(3, 0), RED);
+        assert_eq!(image.pixel(0, 1), BLUE);
+    }
+
+    #[test]
+    fn resize_and_blit_to_rect() {
+        let facade = match context() {
+            Some(facade) => facade,
+            None => return,
+        };
+        let mut big = OffscreenTarget::color_only(&facade, 4, 2).unwrap();
+        big.resize(&facade, 8, 4).unwrap();
+        assert_eq!(big.size(), (8, 4));
+        assert!(!big.has_depth());
+        {
+            let mut fb = big.framebuffer(&facade).unwrap();
+            fb.clear_color(0.0, 0.0, 0.0, 1.0);
+            // top right quarter: pixels 4..8 from the left, 2..4 from the bottom
+            paint(&facade, 4, 2).blit_to_rect(&fb, [4, 2], [4, 2], MagnifySamplerFilter::Nearest);
+        }
+        let image = big.read();
+        assert_eq!(image.pixel(4, 0), RED);
+        assert_eq!(image.pixel(7, 1), BLUE);
+        assert_eq!(image.pixel(3, 0), [0, 0, 0, 255]);
+        assert_eq!(image.pixel(4, 2), [0, 0, 0, 255]);
+    }
+}
@@ -3,15 +3,15 @@ extern crate glium;
 //extern crate glium_text;
 extern crate cgmath;
 extern crate png;
-
-extern crate rand;
+extern crate serde;
+extern crate serde_json;
 
 mod framework;
 
 use glium::{glutin, Surface, Display};
 //use glium_text::{TextSystem, FontTexture};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default)]
 struct Vertex {
     position: [f32; 3],
     txcoord: [f32; 2],
@@ -19,6 +19,17 @@ struct Vertex {
 
 implement_vertex!(Vertex, position, txcoord);
 
+impl framework::primitive::BuildVertex for Vertex {
+
+    fn set_position(&mut self, position: [f32; 3]) {
+        self.position = position;
+    }
+
+    fn set_uv(&mut self, uv: [f32; 2]) {
+        self.txcoord = uv;
+    }
+}
+
 #[derive(Copy, Clone)]
 struct Attribute {
     norm: [f32; 3],
@@ -55,55 +66,8 @@ impl Test {
         // let is = framework::mesh::INDICES8_BLOCK.to_vec();
         // let mesh = framework::mesh::Mesh::wrap(vs, is, glium::index::PrimitiveType::TrianglesList);
         
-        let mut mesh = framework::mesh::Mesh::new();
-        
-        let f = [
-            Vertex { position: [-0.5, -0.5, -0.5], txcoord: [0.25, 0.5] },
-            Vertex { position: [ 0.5, -0.5, -0.5], txcoord: [0.5, 0.5] },
-            Vertex { position: [-0.5,  0.5, -0.5], txcoord: [0.25, 0.25] },
-            Vertex { position: [ 0.5,  0.5, -0.5], txcoord: [0.5, 0.25] },
-        ];
-        mesh.push(&f, &framework::mesh::INDICES4_RECT);
-
-        let f = [
-            Vertex { position: [-0.5, -0.5,  0.5], txcoord: [0.0, 0.5] },
-            Vertex { position: [-0.5, -0.5, -0.5], txcoord: [0.25, 0.5] },
-            Vertex { position: [-0.5,  0.5,  0.5], txcoord: [0.0, 0.25] },
-            Vertex { position: [-0.5,  0.5, -0.5], txcoord: [0.25, 0.25] },
-        ];
-        mesh.push(&f, &framework::mesh::INDICES4_RECT);
-
-        let f = [
-            Vertex { position: [ 0.5, -0.5, -0.5], txcoord: [0.5, 0.5] },
-            Vertex { position: [ 0.5, -0.5,  0.5], txcoord: [0.75, 0.5] },
-            Vertex { position: [ 0.5,  0.5, -0.5], txcoord: [0.5, 0.25] },
-            Vertex { position: [ 0.5,  0.5,  0.5], txcoord: [0.75, 0.25] },
-        ];
-        mesh.push(&f, &framework::mesh::INDICES4_RECT);
-
-        let f = [
-            Vertex { position: [ 0.5, -0.5,  0.5], txcoord: [0.75, 0.5] },
-            Vertex { position: [-0.5, -0.5,  0.5], txcoord: [1.0, 0.5] },
-            Vertex { position: [ 0.5,  0.5,  0.5], txcoord: [0.75, 0.25] },
-            Vertex { position: [-0.5,  0.5,  0.5], txcoord: [1.0, 0.25] },
-        ];
-        mesh.push(&f, &framework::mesh::INDICES4_RECT);
-
-        let f = [
-            Vertex { position: [-0.5,  0.5, -0.5], txcoord: [0.25, 0.25] },
-            Vertex { position: [ 0.5,  0.5, -0.5], txcoord: [0.5, 0.25] },
-            Vertex { position: [-0.5,  0.5,  0.5], txcoord: [0.25, 0.0] },
-            Vertex { position: [ 0.5,  0.5,  0.5], txcoord: [0.5, 0.0] },
-        ];
-        mesh.push(&f, &framework::mesh::INDICES4_RECT);
-
-        let f = [
-            Vertex { position: [-0.5, -0.5,  0.5], txcoord: [0.25, 0.75] },
-            Vertex { position: [ 0.5, -0.5,  0.5], txcoord: [0.5, 0.75] },
-            Vertex { position: [-0.5, -0.5, -0.5], txcoord: [0.25, 0.5] },
-            Vertex { position: [ 0.5, -0.5, -0.5], txcoord: [0.5, 0.5] },
-        ];
-        mesh.push(&f, &framework::mesh::INDICES4_RECT);
+        // filled in `init` from the block model
+        let mesh = framework::mesh::Mesh::new();
 
         Test {
             mesh,
@@ -140,8 +104,10 @@ impl GameLogic for Test {
             glium::Program::from_source(display, &vert, &frag, None).map_err(Box::new)?
         };
         self.prog = Some(prog);
-        let img = framework::mesh::load_texture2d(r.open_read_only("rsc/crafting_table.png").map_err(Box::new)?)?;
-        let texture = glium::texture::Texture2d::new(display, img).map_err(Box::new)?;
+        let block = framework::block::BlockModel::load(&r, "rsc", "crafting_table")?;
+        let built = block.build::<Vertex, u16>(&r, "rsc")?;
+        self.mesh = built.mesh;
+        let texture = glium::texture::Texture2d::new(display, built.texture.to_raw()).map_err(Box::new)?;
         self.texture = Some(texture);
         let buffer = {
             let m: i32 = 24;
@@ -174,10 +140,11 @@ impl GameLogic for Test {
             let mut mapping = buffer.map();
             if self.tick % 2 == 0 {
                 let cv: f32 = 0.02;
+                let rng = settings.get_rng().cosmetic();
                 for v in mapping.iter_mut() {
-                    v.norm[0] += cv * (rand::random::<f32>() - 0.5);
-                    v.norm[1] += cv * (rand::random::<f32>() - 0.5);
-                    v.norm[2] += cv * (rand::random::<f32>() - 0.5);
+                    v.norm[0] += cv * (rng.next_f32() - 0.5);
+                    v.norm[1] += cv * (rng.next_f32() - 0.5);
+                    v.norm[2] += cv * (rng.next_f32() - 0.5);
                 }
             } else {
                 let cv: f32 = 0.01;
@@ -230,9 +197,9 @@ fn main0() {
     let mut y = [0.0, 0.0, 0.0, 0.0];
     for i in (0..60) {
         y[0] = i as f64 / 100.0 * 10.0;
-        c.get(y[0] , &mut y[1..2]);
-        c.get_derivative(y[0], &mut y[2..3]);
-        c.get_derivative2(y[0], &mut y[3..4]);
+        c.get(y[0] , &mut y[1..2]).unwrap();
+        c.get_derivative(y[0], &mut y[2..3]).unwrap();
+        c.get_derivative2(y[0], &mut y[3..4]).unwrap();
         write!(f, "{},{},{},{}\n", y[0], y[1], y[2], y[3]);
     }
 